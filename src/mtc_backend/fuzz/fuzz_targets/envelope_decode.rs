@@ -0,0 +1,16 @@
+//! Feeds arbitrary bytes to the envelope parser, which must never panic
+//! and must only accept the single encoding of an envelope.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mtc_backend::fuzzing::Envelope;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(envelope) = Envelope::decode(data) {
+        assert_eq!(envelope.encode().as_deref(), Ok(data));
+    }
+    if let Ok(envelopes) = Envelope::decode_all(data) {
+        let encoded: Vec<u8> = envelopes.iter().flat_map(|envelope| envelope.encode().unwrap()).collect();
+        assert_eq!(encoded, data);
+    }
+});
@@ -0,0 +1,169 @@
+//! Resolves the account an endpoint acts for.
+//!
+//! Every spending endpoint acts for the caller's own account unless the
+//! owner of another account explicitly delegated to the caller. Grants,
+//! revocations and every use of a delegation are appended to an audit log
+//! that the owner can read back.
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::storage::{StableLists, DELEGATION_LOG_MEMORY};
+use crate::utils::MtcError;
+
+thread_local! {
+    static DELEGATION_STATE: RefCell<DelegationState> = RefCell::new(DelegationState::init());
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct Delegation {
+    pub delegate: Principal,
+    pub granted_at: u64,
+    /// The delegation stops being valid at this time, in nanoseconds since
+    /// the epoch. `None` means it is valid until revoked.
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum DelegationAction {
+    Granted,
+    Revoked,
+    /// The delegate called `method` on the owner's behalf.
+    Used { method: String },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct DelegationEvent {
+    pub owner: Principal,
+    pub delegate: Principal,
+    pub action: DelegationAction,
+    pub timestamp: u64,
+}
+
+pub struct DelegationState {
+    /// Delegations keyed by the principal that granted them.
+    pub delegations: HashMap<Principal, Vec<Delegation>>,
+    /// The events of each owner, in stable memory.
+    pub log: StableLists<DelegationEvent>,
+}
+
+impl DelegationState {
+    pub fn init() -> Self {
+        Self { delegations: HashMap::new(), log: StableLists::init(DELEGATION_LOG_MEMORY) }
+    }
+
+    pub fn grant(&mut self, owner: Principal, delegate: Principal, expires_at: Option<u64>, now: u64) {
+        let delegations = self.delegations.entry(owner).or_default();
+        delegations.retain(|d| d.delegate != delegate);
+        delegations.push(Delegation { delegate, granted_at: now, expires_at });
+        self.push_event(owner, delegate, DelegationAction::Granted, now);
+    }
+
+    pub fn revoke(&mut self, owner: Principal, delegate: Principal, now: u64) -> bool {
+        let Some(delegations) = self.delegations.get_mut(&owner) else {
+            return false;
+        };
+        let before = delegations.len();
+        delegations.retain(|d| d.delegate != delegate);
+        let revoked = delegations.len() != before;
+        if revoked {
+            self.push_event(owner, delegate, DelegationAction::Revoked, now);
+        }
+        revoked
+    }
+
+    pub fn is_delegate(&self, owner: &Principal, delegate: &Principal, now: u64) -> bool {
+        self.delegations.get(owner).map_or(false, |delegations| {
+            delegations.iter().any(|d| {
+                d.delegate == *delegate && d.expires_at.map_or(true, |expires_at| now < expires_at)
+            })
+        })
+    }
+
+    pub fn push_event(&mut self, owner: Principal, delegate: Principal, action: DelegationAction, now: u64) {
+        self.log.push(&Account::from(owner), DelegationEvent { owner, delegate, action, timestamp: now });
+    }
+}
+
+/// Moves the delegations out for an upgrade snapshot. The log stays in
+/// stable memory.
+pub fn take_delegations() -> HashMap<Principal, Vec<Delegation>> {
+    DELEGATION_STATE.with(|state| std::mem::take(&mut state.borrow_mut().delegations))
+}
+
+pub fn restore_delegations(delegations: HashMap<Principal, Vec<Delegation>>) {
+    DELEGATION_STATE.with(|state| state.borrow_mut().delegations = delegations);
+}
+
+pub fn mutate_delegation_state<R>(f: impl FnOnce(&mut DelegationState) -> R) -> R {
+    DELEGATION_STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Returns the caller's own account, rejecting the anonymous principal.
+pub fn caller_account(subaccount: Option<Subaccount>) -> Result<Account, MtcError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(MtcError::Unauthorized("anonymous callers are not allowed".to_string()));
+    }
+    Ok(Account { owner: caller, subaccount })
+}
+
+/// Returns the account `method` acts for: the caller's own account, or the
+/// account of `on_behalf_of` if it delegated to the caller. Acting for
+/// another principal is recorded in the owner's audit log.
+pub fn authorize(on_behalf_of: Option<Principal>, subaccount: Option<Subaccount>, method: &str) -> Result<Account, MtcError> {
+    let own = caller_account(subaccount)?;
+    let owner = match on_behalf_of {
+        Some(owner) if owner != own.owner => owner,
+        _ => return Ok(own),
+    };
+    let now = ic_cdk::api::time();
+    DELEGATION_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.is_delegate(&owner, &own.owner, now) {
+            return Err(MtcError::Unauthorized(format!("{} has no delegation from {}", own.owner, owner)));
+        }
+        state.push_event(owner, own.owner, DelegationAction::Used { method: method.to_string() }, now);
+        Ok(Account { owner, subaccount })
+    })
+}
+
+/// Lets `delegate` act for the caller until `expires_at` or until revoked.
+pub fn grant_delegation(delegate: Principal, expires_at: Option<u64>) -> Result<(), MtcError> {
+    let owner = caller_account(None)?.owner;
+    if delegate == Principal::anonymous() || delegate == owner {
+        return Err(MtcError::InvalidRequest(format!("cannot delegate to {}", delegate)));
+    }
+    let now = ic_cdk::api::time();
+    DELEGATION_STATE.with(|state| state.borrow_mut().grant(owner, delegate, expires_at, now));
+    Ok(())
+}
+
+pub fn revoke_delegation(delegate: Principal) -> Result<(), MtcError> {
+    let owner = caller_account(None)?.owner;
+    let now = ic_cdk::api::time();
+    if DELEGATION_STATE.with(|state| state.borrow_mut().revoke(owner, delegate, now)) {
+        Ok(())
+    } else {
+        Err(MtcError::InvalidRequest(format!("{} has no delegation from {}", delegate, owner)))
+    }
+}
+
+/// Returns the delegations granted by the caller.
+pub fn read_delegations() -> Vec<Delegation> {
+    let owner = ic_cdk::caller();
+    DELEGATION_STATE.with(|state| state.borrow().delegations.get(&owner).cloned().unwrap_or_default())
+}
+
+/// Returns the audit log of the delegations granted by the caller, oldest
+/// first.
+pub fn read_delegation_log() -> Vec<DelegationEvent> {
+    let owner = Account::from(ic_cdk::caller());
+    DELEGATION_STATE.with(|state| {
+        let mut log: Vec<DelegationEvent> = state.borrow().log.iter(&owner).map(|(_, event)| event).collect();
+        log.reverse();
+        log
+    })
+}
@@ -0,0 +1,240 @@
+//! The configuration shared by every endpoint.
+//!
+//! The configuration is set from the init argument, can be amended on
+//! upgrade or by an admin through `update_config`, and is read from here
+//! by every call site: address encoding and validation, fee lookups,
+//! balance and UTXO calls, and both signing APIs.
+use bitcoin::Network;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, MillisatoshiPerByte};
+use serde::Serialize;
+use std::cell::RefCell;
+
+use crate::utils::{is_public_key_requested, MtcError};
+
+/// The canister providing `schnorr_public_key` and `sign_with_schnorr`
+/// when none is configured.
+const DEFAULT_SCHNORR_CANISTER: &str = "6fwhw-fyaaa-aaaap-qb7ua-cai";
+const DEFAULT_FEE_PERCENTILE: u8 = 50;
+/// Used when there are no fee percentiles, which only happens on a regtest
+/// network without non-coinbase transactions: 2 satoshi/byte.
+const DEFAULT_FALLBACK_FEE_RATE: MillisatoshiPerByte = 2_000;
+
+thread_local! {
+    static CONFIG: RefCell<MtcConfig> = RefCell::new(MtcConfig::new(BitcoinNetwork::Testnet));
+}
+
+/// The argument of `init`, and optionally of `post_upgrade`.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct InitArg {
+    pub network: BitcoinNetwork,
+    pub ecdsa_key_name: Option<String>,
+    pub schnorr_canister: Option<Principal>,
+    pub schnorr_key_name: Option<String>,
+    pub admins: Vec<Principal>,
+    pub fee_percentile: Option<u8>,
+    pub fallback_fee_rate: Option<MillisatoshiPerByte>,
+    pub esplora_url: Option<String>,
+}
+
+/// The argument of `update_config`. Fields left empty keep their value.
+/// The network cannot be changed once the canister holds state for it,
+/// nor the ECDSA key once the master key was fetched.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct UpdateConfigArg {
+    pub ecdsa_key_name: Option<String>,
+    pub schnorr_canister: Option<Principal>,
+    pub schnorr_key_name: Option<String>,
+    pub admins: Option<Vec<Principal>>,
+    pub fee_percentile: Option<u8>,
+    pub fallback_fee_rate: Option<MillisatoshiPerByte>,
+    /// An empty URL switches back to relayed transactions.
+    pub esplora_url: Option<String>,
+}
+
+impl From<InitArg> for UpdateConfigArg {
+    fn from(arg: InitArg) -> Self {
+        Self {
+            ecdsa_key_name: arg.ecdsa_key_name,
+            schnorr_canister: arg.schnorr_canister,
+            schnorr_key_name: arg.schnorr_key_name,
+            admins: Some(arg.admins),
+            fee_percentile: arg.fee_percentile,
+            fallback_fee_rate: arg.fallback_fee_rate,
+            esplora_url: arg.esplora_url,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct MtcConfig {
+    pub network: BitcoinNetwork,
+    pub ecdsa_key_name: String,
+    pub schnorr_canister: Principal,
+    pub schnorr_key_name: String,
+    /// Principals allowed to call `update_config`, in addition to the
+    /// canister's controllers.
+    pub admins: Vec<Principal>,
+    /// The fee percentile used for sends that don't choose a fee rate.
+    pub fee_percentile: u8,
+    pub fallback_fee_rate: MillisatoshiPerByte,
+    /// The Esplora API the inbox indexer fetches transactions from. Without
+    /// one, it waits for them to be relayed with `submit_raw_transactions`.
+    pub esplora_url: Option<String>,
+}
+
+impl MtcConfig {
+    /// Returns the default configuration for `network`.
+    pub fn new(network: BitcoinNetwork) -> Self {
+        let key_name = match network {
+            // For local development, we use a special test key with dfx.
+            BitcoinNetwork::Regtest => "dfx_test_key",
+            // On the IC we're using a test ECDSA key.
+            BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => "test_key_1",
+        };
+        Self {
+            network,
+            ecdsa_key_name: key_name.to_string(),
+            schnorr_canister: Principal::from_text(DEFAULT_SCHNORR_CANISTER)
+                .expect("the default Schnorr canister id is valid"),
+            schnorr_key_name: key_name.to_string(),
+            admins: vec![],
+            fee_percentile: DEFAULT_FEE_PERCENTILE,
+            fallback_fee_rate: DEFAULT_FALLBACK_FEE_RATE,
+            esplora_url: None,
+        }
+    }
+
+    pub fn from_init_arg(arg: InitArg) -> Result<Self, MtcError> {
+        let mut config = Self::new(arg.network);
+        config.apply(arg.into())?;
+        Ok(config)
+    }
+
+    /// Overwrites the fields set in `arg`. Nothing changes if `arg` is
+    /// invalid.
+    pub fn apply(&mut self, arg: UpdateConfigArg) -> Result<(), MtcError> {
+        if let Some(fee_percentile) = arg.fee_percentile {
+            if fee_percentile > 99 {
+                return Err(MtcError::InvalidRequest(format!(
+                    "fee percentile must be below 100, got {}",
+                    fee_percentile
+                )));
+            }
+            self.fee_percentile = fee_percentile;
+        }
+        if let Some(esplora_url) = &arg.esplora_url {
+            if !esplora_url.is_empty() && !esplora_url.starts_with("https://") {
+                return Err(MtcError::InvalidRequest(format!(
+                    "HTTPS outcalls need an https:// URL, got {}",
+                    esplora_url
+                )));
+            }
+        }
+        if let Some(ecdsa_key_name) = arg.ecdsa_key_name {
+            self.ecdsa_key_name = ecdsa_key_name;
+        }
+        if let Some(schnorr_canister) = arg.schnorr_canister {
+            self.schnorr_canister = schnorr_canister;
+        }
+        if let Some(schnorr_key_name) = arg.schnorr_key_name {
+            self.schnorr_key_name = schnorr_key_name;
+        }
+        if let Some(admins) = arg.admins {
+            self.admins = admins;
+        }
+        if let Some(fallback_fee_rate) = arg.fallback_fee_rate {
+            self.fallback_fee_rate = fallback_fee_rate;
+        }
+        if let Some(esplora_url) = arg.esplora_url {
+            self.esplora_url = Some(esplora_url).filter(|url| !url.is_empty());
+        }
+        Ok(())
+    }
+}
+
+pub fn read_config<R>(f: impl FnOnce(&MtcConfig) -> R) -> R {
+    CONFIG.with(|config| f(&config.borrow()))
+}
+
+pub fn replace_config(config: MtcConfig) {
+    CONFIG.with(|c| *c.borrow_mut() = config);
+}
+
+/// Returns the network the canister operates on.
+pub fn network() -> BitcoinNetwork {
+    read_config(|config| config.network)
+}
+
+pub fn ecdsa_key_name() -> String {
+    read_config(|config| config.ecdsa_key_name.clone())
+}
+
+/// Rejects a new ECDSA key name once the master key was requested. Every
+/// address is derived from the master key, so switching keys would move
+/// every account to new addresses and leave the funds and messages of the
+/// old ones out of reach.
+fn check_key_name(key_name: Option<&str>) -> Result<(), MtcError> {
+    let current = ecdsa_key_name();
+    match key_name {
+        Some(key_name) if key_name != current && is_public_key_requested() => Err(MtcError::InvalidRequest(format!(
+            "the ECDSA key {} is in use and can't be replaced with {}",
+            current, key_name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Applies the argument of a `post_upgrade` call to the restored config.
+pub fn upgrade_config(arg: InitArg) -> Result<(), MtcError> {
+    let network = network();
+    if arg.network != network {
+        return Err(MtcError::InvalidRequest(format!(
+            "cannot switch the network from {:?} to {:?} on upgrade",
+            network, arg.network
+        )));
+    }
+    check_key_name(arg.ecdsa_key_name.as_deref())?;
+    CONFIG.with(|config| config.borrow_mut().apply(arg.into()))
+}
+
+/// Applies `arg` if the caller is an admin or a controller.
+pub fn update_config(arg: UpdateConfigArg) -> Result<MtcConfig, MtcError> {
+    let caller = ic_cdk::caller();
+    let is_admin = read_config(|config| config.admins.contains(&caller));
+    if !is_admin && !ic_cdk::api::is_controller(&caller) {
+        return Err(MtcError::Unauthorized(format!("{} is not an admin", caller)));
+    }
+    check_key_name(arg.ecdsa_key_name.as_deref())?;
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        config.apply(arg)?;
+        Ok(config.clone())
+    })
+}
+
+/// Maps the management canister's network onto the `bitcoin` crate's.
+pub fn to_bitcoin_network(network: BitcoinNetwork) -> Network {
+    match network {
+        BitcoinNetwork::Mainnet => Network::Bitcoin,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Regtest => Network::Regtest,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{restore_public_key, ECDSAPublicKey};
+
+    #[test]
+    fn key_name_is_fixed_once_the_key_is_fetched() {
+        replace_config(MtcConfig::new(BitcoinNetwork::Testnet));
+        assert_eq!(check_key_name(Some("key_1")), Ok(()));
+
+        restore_public_key(Some(ECDSAPublicKey { public_key: vec![2; 33], chain_code: vec![0; 32] }));
+        assert_eq!(check_key_name(Some("test_key_1")), Ok(()));
+        assert_eq!(check_key_name(None), Ok(()));
+        assert!(matches!(check_key_name(Some("key_1")), Err(MtcError::InvalidRequest(_))));
+    }
+}
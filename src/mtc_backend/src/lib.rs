@@ -1,157 +1,428 @@
+mod auth;
+mod config;
+mod message;
+mod storage;
 mod utils;
 mod wallet;
 pub use wallet::address;
-use utils::{init_ecdsa_public_key, read_public_key};
+
+/// The parsers exercised by the targets in `fuzz/`.
+#[cfg(fuzzing)]
+pub mod fuzzing {
+    pub use crate::message::envelope::Envelope;
+}
+
+use utils::{cached_public_key, read_public_key, schedule_public_key_fetch};
 use ic_cdk::api::management_canister::bitcoin::{
-    bitcoin_get_balance, bitcoin_get_current_fee_percentiles, BitcoinNetwork, GetBalanceRequest, GetCurrentFeePercentilesRequest, GetUtxosResponse, MillisatoshiPerByte
+    bitcoin_get_balance, bitcoin_get_current_fee_percentiles, GetBalanceRequest, GetCurrentFeePercentilesRequest, MillisatoshiPerByte
 };
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 // use ic_management_canister_types::DerivationPath;
-use utils::{ECDSAPublicKey, SendBtcRequest, UpdateUtxoRequest};
+use utils::{BumpFeeRequest, DelegateRequest, ECDSAPublicKey, MtcError, SendBtcRequest, SendBtcResponse, SendMessageRequest, SendMessageResponse, UtxoRequest};
 use wallet::{state, send_btc};
-use std::cell::{Cell, RefCell};
+use wallet::history::{self, SentTransaction};
+use config::{InitArg, MtcConfig, UpdateConfigArg};
+use message::group::{self, CreateGroupRequest, Group, GroupMembershipRequest, SendGroupMessageRequest};
+use message::inbox::{self, IndexReport};
+use message::mailbox::{self, ListMessagesRequest, MessagePage, MessageView};
+use message::receipt::{self, Receipt};
+use message::thread::{self, Thread};
+use message::registry::{self, KeyRegistration, RegisteredKeys};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
 use candid::candid_method;
-use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use candid::Principal;
 
 
 thread_local! {
-    static NETWORK: Cell<BitcoinNetwork> = Cell::new(BitcoinNetwork::Testnet);
-
     // The derivation path to use for ECDSA secp256k1.
     static DERIVATION_PATH: Vec<Vec<u8>> = vec![];
-    pub static SCHNORR_CANISTER: RefCell<String> = RefCell::new(String::from("6fwhw-fyaaa-aaaap-qb7ua-cai"));
-    // 6fwhw-fyaaa-aaaap-qb7ua-cai
-    // The ECDSA key name.
-    static KEY_NAME: RefCell<String> = RefCell::new(String::from("test_key_1"));
 }
 
 #[init]
 #[candid_method(init)]
-pub async fn init(network: BitcoinNetwork) {
-    NETWORK.with(|nw| nw.set(network));
-    KEY_NAME.with(|key_name| {
-        key_name.replace(String::from(match network {
-            // For local development, we use a special test key with dfx.
-            BitcoinNetwork::Regtest => "dfx_test_key",
-            // On the IC we're using a test ECDSA key.
-            BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => "test_key_1",
-        }))
-    });
+pub fn init(arg: InitArg) {
+    match MtcConfig::from_init_arg(arg) {
+        Ok(config) => config::replace_config(config),
+        Err(err) => ic_cdk::trap(&format!("invalid init argument: {:?}", err)),
+    }
+    schedule_public_key_fetch();
 }
+
+/// Returns the ECDSA master key, fetching it if needed. The key is fetched
+/// on first use anyway, so calling this is never required.
 #[update]
 #[candid_method(update)]
-pub async fn init_pub_key() -> ECDSAPublicKey {
-    init_ecdsa_public_key().await
+pub async fn init_pub_key() -> Result<ECDSAPublicKey, MtcError> {
+    read_public_key().await
+}
 
+/// Returns the ECDSA master key if it has been fetched already.
+#[query]
+#[candid_method(query)]
+pub fn read_pub_key() -> Result<ECDSAPublicKey, MtcError> {
+    cached_public_key().ok_or(MtcError::KeyNotInitialized)
 }
+
 /// Returns the balance of the given bitcoin address.
 #[update]
 #[candid_method(update)]
-pub async fn get_balance(address: String) -> u64 {
-    // let network = NETWORK.with(|n| n.get());
-    match bitcoin_get_balance(GetBalanceRequest {network: BitcoinNetwork::Testnet, address, min_confirmations: Some(0)}).await {
-        Ok(balance) => balance.0,
-        Err(_) => 0u64
-    }
+pub async fn get_balance(address: String) -> Result<u64, MtcError> {
+    let network = config::network();
+    send_btc::parse_address(&address, network)?;
+    bitcoin_get_balance(GetBalanceRequest {network, address, min_confirmations: Some(0)})
+        .await
+        .map(|balance| balance.0)
+        .map_err(|err| MtcError::rejected("bitcoin_get_balance", err))
 }
-/// Returns the UTXOs of the given bitcoin address.
+
+/// Returns the spendable UTXOs of the caller's account and address kind.
 #[update]
 #[candid_method(update)]
-pub async fn get_utxos() -> Vec<(String, u64)> {
-    // let network = NETWORK.with(|n| n.get());
-    // let mut utxo = Vec::new();
-    state::read_wallet_utxo()
-  
+pub async fn get_utxos(utxo_req: UtxoRequest) -> Result<Vec<(String, u64)>, MtcError> {
+    let account = auth::authorize(utxo_req.on_behalf_of, utxo_req.subaccount, "get_utxos")?;
+    state::read_wallet_utxo(&account, utxo_req.address_type)
 }
+
 /// Returns the 100 fee percentiles measured in millisatoshi/byte.
 /// Percentiles are computed from the last 10,000 transactions (if available).
 #[update]
 #[candid_method(update)]
-pub async fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
-    
-    // let network = NETWORK.with(|n| n.get());
-    match bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest{network: BitcoinNetwork::Testnet}).await {
-        Ok(vec_byte) => vec_byte.0,
-        Err(_) => vec![]
-    }
+pub async fn get_current_fee_percentiles() -> Result<Vec<MillisatoshiPerByte>, MtcError> {
+    let network = config::network();
+    bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest{network})
+        .await
+        .map(|percentiles| percentiles.0)
+        .map_err(|err| MtcError::rejected("bitcoin_get_current_fee_percentiles", err))
 }
 
-/// Returns the P2PKH address of this canister at a specific derivation path.
-// #[update]
-// #[candid_method(update)]
-// pub async fn get_p2pkh_address() -> String {
-//     let derivation_path = DERIVATION_PATH.with(|d| d.clone());
-//     // let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
-//     // let network = NETWORK.with(|n| n.get());
-//     bitcoin_wallet::get_p2pkh_address(BitcoinNetwork::Testnet, "test_key_1".to_string(), derivation_path).await
-// }
-
 #[update]
 #[candid_method(update)]
-pub async fn get_p2wpkh_address(pid: String) -> String {
-    let principal = Principal::from_text(pid).expect("get principal from string failed");
+pub async fn get_p2wpkh_address(pid: String) -> Result<String, MtcError> {
+    let principal = Principal::from_text(&pid)
+        .map_err(|err| MtcError::InvalidPrincipal(format!("{}: {}", pid, err)))?;
     let account = Account {
         owner: principal,
         subaccount: None,
     };
-    // let derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    // let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
-    // let network = NETWORK.with(|n| n.get());
-    let network = BitcoinNetwork::Testnet;
-    let pub_key = read_public_key().await;
+    let network = config::network();
+    let pub_key = read_public_key().await?;
     address::account_to_p2wpkh_address(network, &pub_key, &account).await
 }
 
-
 #[update]
 #[candid_method(update)]
-pub async fn get_p2pkh_address(pid: String) -> String {
-    let principal = Principal::from_text(pid).expect("get principal from string failed");
+pub async fn get_p2pkh_address(pid: String) -> Result<String, MtcError> {
+    let principal = Principal::from_text(&pid)
+        .map_err(|err| MtcError::InvalidPrincipal(format!("{}: {}", pid, err)))?;
     let account = Account {
         owner: principal,
         subaccount: None,
     };
-    // let derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    // let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
-    // let network = NETWORK.with(|n| n.get());
-    let network = BitcoinNetwork::Testnet;
-    let pub_key = read_public_key().await;
+    let network = config::network();
+    let pub_key = read_public_key().await?;
     address::account_to_p2pkh_address(network, &pub_key, &account).await
 }
+
 #[update]
 #[candid_method(update)]
-pub async fn send_btc(send_btc_request: SendBtcRequest) ->(Vec<u8>, String) {
+pub async fn send_btc(send_btc_request: SendBtcRequest) -> Result<SendBtcResponse, MtcError> {
     let dst_addr = send_btc_request.dst_address;
     let amount = send_btc_request.amount;
-    let pid = Principal::from_text(send_btc_request.pid).unwrap();
-    let account = Account { owner: pid, subaccount: None };
-    let network = BitcoinNetwork::Testnet;
-    // let key = read_public_key().await;
-    let key_name = "test_key_1".to_string();
-    let tx = send_btc::send(network, key_name, dst_addr, amount, &account).await;
-    tx
+    let strategy = send_btc_request.coin_selection.unwrap_or_default();
+    let fees = send_btc::FeePolicy {
+        sat_per_vbyte: send_btc_request.sat_per_vbyte,
+        percentile: send_btc_request.fee_percentile,
+        max_fee: send_btc_request.max_fee,
+    };
+    let account = auth::authorize(send_btc_request.on_behalf_of, send_btc_request.subaccount, "send_btc")?;
+    let network = config::network();
+    let key_name = config::ecdsa_key_name();
+    send_btc::send(network, key_name, dst_addr, amount, strategy, fees, &account).await
 }
 
+/// Replaces a pending send of the caller with one paying a higher fee rate.
+/// Messages carried by the transaction are moved to the replacement.
 #[update]
 #[candid_method(update)]
-pub async fn update_utxo(update_utxo_req: UpdateUtxoRequest) -> Vec<(String, u64)>{
-    let network = BitcoinNetwork::Testnet;
-    let address = update_utxo_req.address;
-    state::update_utxo(network, address).await
+pub async fn bump_fee(request: BumpFeeRequest) -> Result<SendBtcResponse, MtcError> {
+    let account = auth::authorize(request.on_behalf_of, request.subaccount, "bump_fee")?;
+    let network = config::network();
+    let key_name = config::ecdsa_key_name();
+    let fees = send_btc::FeePolicy { sat_per_vbyte: Some(request.sat_per_vbyte), ..Default::default() };
+    let fee_per_byte = send_btc::get_fee_per_byte(network, &fees).await?;
+    let response = send_btc::bump_fee(network, key_name, &request.txid, fee_per_byte, &account).await?;
+    message::outbox::replace_txid(&account, &request.txid.to_ascii_lowercase(), &response.txid);
+    Ok(response)
 }
-// #[pre_upgrade]
-// fn pre_upgrade() {
-//     let network = NETWORK.with(|n| n.get());
-//     ic_cdk::storage::stable_save((network,)).expect("Saving network to stable store must succeed.");
-// }
 
-// #[post_upgrade]
-// fn post_upgrade() {
-//     let network = ic_cdk::storage::stable_restore::<(BitcoinNetwork,)>()
-//         .expect("Failed to read network from stable memory.")
-//         .0;
+/// Returns the transactions the caller's account sent, newest first.
+#[query]
+#[candid_method(query)]
+pub fn get_sent_transactions(subaccount: Option<Subaccount>) -> Result<Vec<SentTransaction>, MtcError> {
+    Ok(history::read_sent_transactions(&Account { owner: ic_cdk::caller(), subaccount }))
+}
+
+/// Sends an encrypted message to the recipient's address. The payload is
+/// carried in OP_RETURN outputs or a taproot witness, next to a small
+/// payment to the recipient.
+#[update]
+#[candid_method(update)]
+pub async fn send_message(request: SendMessageRequest) -> Result<SendMessageResponse, MtcError> {
+    let account = auth::authorize(request.on_behalf_of, request.subaccount, "send_message")?;
+    let network = config::network();
+    let transport = request.transport.unwrap_or_default();
+    let strategy = request.coin_selection.unwrap_or_default();
+    message::send_message::send_message(network, request.recipient, request.payload, request.amount, transport, strategy, &account).await
+}
 
-//     init(network);
-// }
+/// Creates a group owned by the caller.
+#[update]
+#[candid_method(update)]
+pub async fn create_group(request: CreateGroupRequest) -> Result<Group, MtcError> {
+    group::create_group(request).await
+}
 
+/// Adds a member to a group the caller owns and starts a new key epoch.
+#[update]
+#[candid_method(update)]
+pub fn add_group_member(request: GroupMembershipRequest) -> Result<Group, MtcError> {
+    group::add_member(request)
+}
+
+/// Removes a member from a group the caller owns, or the caller from a
+/// group, and starts a new key epoch.
+#[update]
+#[candid_method(update)]
+pub fn remove_group_member(request: GroupMembershipRequest) -> Result<Group, MtcError> {
+    group::remove_member(request)
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_group(group_id: String) -> Result<Group, MtcError> {
+    group::read_group(&group_id, &ic_cdk::caller())
+}
+
+/// Returns the groups the caller is a member of.
+#[query]
+#[candid_method(query)]
+pub fn get_groups() -> Result<Vec<Group>, MtcError> {
+    Ok(group::read_groups())
+}
+
+/// Sends one message, readable by every current member, to a group.
+#[update]
+#[candid_method(update)]
+pub async fn send_group_message(request: SendGroupMessageRequest) -> Result<SendMessageResponse, MtcError> {
+    group::send_group_message(request).await
+}
+
+#[update]
+#[candid_method(update)]
+pub async fn index_group_inbox(group_id: String) -> Result<IndexReport, MtcError> {
+    group::index_group_inbox(group_id).await
+}
+
+#[query]
+#[candid_method(query)]
+pub fn list_group_messages(group_id: String, request: ListMessagesRequest) -> Result<MessagePage, MtcError> {
+    group::list_group_messages(group_id, request)
+}
+
+/// Sends the outputs paid to the address of a group the caller owns to the
+/// caller's wallet, once their messages are indexed.
+#[update]
+#[candid_method(update)]
+pub async fn sweep_group(group_id: String) -> Result<SendBtcResponse, MtcError> {
+    group::sweep_group(group_id).await
+}
+
+/// Parses the transactions received by the caller's address and adds the
+/// messages addressed to it to the caller's inbox.
+#[update]
+#[candid_method(update)]
+pub async fn index_inbox(utxo_req: UtxoRequest) -> Result<IndexReport, MtcError> {
+    let account = auth::authorize(utxo_req.on_behalf_of, utxo_req.subaccount, "index_inbox")?;
+    let network = config::network();
+    inbox::index_inbox(network, &account, utxo_req.address_type).await
+}
+
+/// Hands raw transactions to the inbox indexer when no Esplora API is
+/// configured. Only transactions the indexer waits for are kept; returns
+/// how many.
+#[update]
+#[candid_method(update)]
+pub fn submit_raw_transactions(transactions: Vec<Vec<u8>>) -> Result<u32, MtcError> {
+    Ok(message::source::relay_transactions(transactions, inbox::is_awaited))
+}
+
+/// Returns a page of the messages received by the caller, newest first.
+#[query]
+#[candid_method(query)]
+pub fn list_inbox(request: ListMessagesRequest) -> Result<MessagePage, MtcError> {
+    mailbox::list_inbox(&ic_cdk::caller(), &request)
+}
+
+/// Returns a page of the messages sent by the caller, newest first.
+#[query]
+#[candid_method(query)]
+pub fn list_outbox(request: ListMessagesRequest) -> Result<MessagePage, MtcError> {
+    Ok(mailbox::list_outbox(&ic_cdk::caller(), &request))
+}
+
+/// Returns a message received or sent by the caller.
+#[query]
+#[candid_method(query)]
+pub fn get_message(id: String) -> Result<Option<MessageView>, MtcError> {
+    Ok(mailbox::get_message(&ic_cdk::caller(), &id))
+}
+
+/// Returns the messages of a conversation the caller received or sent,
+/// with every reply under the message it answers.
+#[query]
+#[candid_method(query)]
+pub fn get_thread(conversation_id: String) -> Result<Thread, MtcError> {
+    Ok(thread::get_thread(&ic_cdk::caller(), &conversation_id))
+}
+
+/// Marks messages in the caller's inbox as read or unread. Returns how
+/// many changed.
+#[update]
+#[candid_method(update)]
+pub fn mark_read(ids: Vec<String>, read: bool) -> Result<u32, MtcError> {
+    Ok(inbox::mark_read(ids, read))
+}
+
+/// Records a receipt signed by the recipient of a message in the sender's
+/// outbox. Anyone may relay it.
+#[update]
+#[candid_method(update)]
+pub fn submit_receipt(receipt: Receipt) -> Result<(), MtcError> {
+    receipt::submit_receipt(receipt)
+}
+
+/// The transform of the HTTP outcalls of `message::source`. The system
+/// calls it with a fixed signature, so it can't return a `Result`.
+#[query]
+#[candid_method(query)]
+pub fn transform_raw_transaction(args: TransformArgs) -> HttpResponse {
+    message::source::transform_raw_transaction(args)
+}
+
+#[update]
+#[candid_method(update)]
+pub async fn update_utxo(utxo_req: UtxoRequest) -> Result<Vec<(String, u64)>, MtcError> {
+    let network = config::network();
+    let account = auth::authorize(utxo_req.on_behalf_of, utxo_req.subaccount, "update_utxo")?;
+    state::update_utxo(network, &account, utxo_req.address_type).await
+}
+
+/// Allows `delegate` to act for the caller's accounts until `expires_at`.
+#[update]
+#[candid_method(update)]
+pub fn add_delegate(delegate_req: DelegateRequest) -> Result<(), MtcError> {
+    auth::grant_delegation(delegate_req.delegate, delegate_req.expires_at)
+}
+
+#[update]
+#[candid_method(update)]
+pub fn remove_delegate(delegate: Principal) -> Result<(), MtcError> {
+    auth::revoke_delegation(delegate)
+}
+
+/// Returns the delegations granted by the caller.
+#[query]
+#[candid_method(query)]
+pub fn get_delegates() -> Result<Vec<auth::Delegation>, MtcError> {
+    Ok(auth::read_delegations())
+}
+
+/// Returns every grant, revocation and use of the caller's delegations.
+#[query]
+#[candid_method(query)]
+pub fn get_delegation_log() -> Result<Vec<auth::DelegationEvent>, MtcError> {
+    Ok(auth::read_delegation_log())
+}
+
+/// Registers the caller's messaging keys. Fails if the caller already has
+/// active keys; use `rotate_messaging_keys` to replace them.
+#[update]
+#[candid_method(update)]
+pub async fn register_messaging_keys(registration: KeyRegistration) -> Result<(), MtcError> {
+    registry::register_keys(registration, false).await
+}
+
+/// Replaces the caller's messaging keys. The old keys stay in the history.
+#[update]
+#[candid_method(update)]
+pub async fn rotate_messaging_keys(registration: KeyRegistration) -> Result<(), MtcError> {
+    registry::register_keys(registration, true).await
+}
+
+#[update]
+#[candid_method(update)]
+pub fn revoke_messaging_keys() -> Result<(), MtcError> {
+    registry::revoke_keys()
+}
+
+/// Returns the active messaging keys of `owner`.
+#[query]
+#[candid_method(query)]
+pub fn get_messaging_keys(owner: Principal) -> Result<Option<RegisteredKeys>, MtcError> {
+    Ok(registry::read_active_keys(&owner))
+}
+
+/// Returns the active messaging keys of the principal whose default
+/// account has the P2WPKH `address`.
+#[query]
+#[candid_method(query)]
+pub fn get_messaging_keys_by_address(address: String) -> Result<Option<RegisteredKeys>, MtcError> {
+    Ok(registry::read_address_owner(&address).and_then(|owner| registry::read_active_keys(&owner)))
+}
+
+/// Returns every messaging key registration of `owner`, oldest first.
+#[query]
+#[candid_method(query)]
+pub fn get_messaging_key_history(owner: Principal) -> Result<Vec<RegisteredKeys>, MtcError> {
+    Ok(registry::read_key_history(&owner))
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_config() -> Result<MtcConfig, MtcError> {
+    Ok(config::read_config(|config| config.clone()))
+}
+
+/// Changes the configuration. Only admins and controllers may call this.
+#[update]
+#[candid_method(update)]
+pub fn update_config(arg: UpdateConfigArg) -> Result<MtcConfig, MtcError> {
+    config::update_config(arg)
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    storage::save_to_stable_memory();
+}
+
+/// Builds that didn't write a snapshot leave stable memory empty. The
+/// canister then starts from the default state, configured by `arg` as
+/// on install.
+#[post_upgrade]
+fn post_upgrade(arg: Option<InitArg>) {
+    let restored = storage::restore_from_stable_memory();
+    if let Some(arg) = arg {
+        let result = if restored {
+            config::upgrade_config(arg)
+        } else {
+            MtcConfig::from_init_arg(arg).map(config::replace_config)
+        };
+        if let Err(err) = result {
+            ic_cdk::trap(&format!("invalid upgrade argument: {:?}", err));
+        }
+    }
+    if cached_public_key().is_none() {
+        schedule_public_key_fetch();
+    }
+}
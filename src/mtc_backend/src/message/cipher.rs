@@ -0,0 +1,355 @@
+//! Encrypts and decrypts envelope bodies.
+//!
+//! The suite byte of an envelope selects the cipher:
+//!
+//! * `SUITE_PLAINTEXT` (0): the body is not encrypted.
+//! * `SUITE_AES256_CBC_HMAC_SHA256` (1): AES-256-CBC with PKCS#7 padding
+//!   and a 16-byte IV as nonce, followed by an HMAC-SHA256 tag over the
+//!   associated data, the IV and the ciphertext (encrypt-then-MAC).
+//! * `SUITE_AES256_GCM` (2): AES-256-GCM with a 12-byte nonce; the body is
+//!   the ciphertext followed by the 16-byte tag.
+//!
+//! Both suites take a 32-byte message key. Suite 1 splits it into an
+//! encryption and a MAC key with HMAC-SHA256. The associated data is the
+//! envelope encoded with an empty body, so the header, nonce and
+//! extensions can't be changed without invalidating the body.
+//!
+//! Suite 3 encrypts to a secp256k1 public key instead of a shared key; it
+//! is implemented in `ecies`.
+//!
+//! The encrypted suites need the `encryption` feature. Without it only
+//! plaintext envelopes can be opened, and everything else fails with
+//! `CipherError::UnsupportedSuite`.
+use std::fmt;
+
+use crate::message::envelope::{Envelope, EnvelopeError, FLAG_ENCRYPTED, SUITE_PLAINTEXT};
+
+pub const SUITE_AES256_CBC_HMAC_SHA256: u8 = 1;
+pub const SUITE_AES256_GCM: u8 = 2;
+/// See `ecies`.
+pub const SUITE_SECP256K1_ECIES: u8 = 3;
+
+pub const KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CipherError {
+    UnsupportedSuite(u8),
+    InvalidNonce { suite: u8, len: usize },
+    /// A public or secret key is malformed.
+    InvalidKey,
+    Envelope(EnvelopeError),
+    /// The body was modified, or the key is wrong.
+    AuthenticationFailed,
+}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherError::UnsupportedSuite(suite) => write!(f, "unsupported cipher suite {}", suite),
+            CipherError::InvalidNonce { suite, len } => {
+                write!(f, "a {} bytes long nonce is not valid for suite {}", len, suite)
+            }
+            CipherError::InvalidKey => write!(f, "invalid key"),
+            CipherError::Envelope(err) => err.fmt(f),
+            CipherError::AuthenticationFailed => write!(f, "the message failed authentication"),
+        }
+    }
+}
+
+impl From<EnvelopeError> for CipherError {
+    fn from(err: EnvelopeError) -> Self {
+        CipherError::Envelope(err)
+    }
+}
+
+/// Encrypts `plaintext` into the body of `envelope`. The suite and nonce
+/// must be set; the ENCRYPTED flag is set here.
+pub fn seal(mut envelope: Envelope, key: &[u8; KEY_LEN], plaintext: &[u8]) -> Result<Envelope, CipherError> {
+    if envelope.suite == SUITE_PLAINTEXT {
+        envelope.flags &= !FLAG_ENCRYPTED;
+        envelope.body = plaintext.to_vec();
+        envelope.validate()?;
+        return Ok(envelope);
+    }
+    envelope.flags |= FLAG_ENCRYPTED;
+    envelope.body = vec![];
+    let aad = envelope.encode()?;
+    envelope.body = suites::encrypt(envelope.suite, key, &envelope.nonce, &aad, plaintext)?;
+    envelope.validate()?;
+    Ok(envelope)
+}
+
+/// Returns the plaintext of `envelope`. `key` is ignored for plaintext
+/// envelopes.
+pub fn open(envelope: &Envelope, key: &[u8; KEY_LEN]) -> Result<Vec<u8>, CipherError> {
+    envelope.validate()?;
+    if !envelope.is_encrypted() {
+        return Ok(envelope.body.clone());
+    }
+    let aad = Envelope { body: vec![], ..envelope.clone() }.encode()?;
+    suites::decrypt(envelope.suite, key, &envelope.nonce, &aad, &envelope.body)
+}
+
+#[cfg(feature = "encryption")]
+mod suites {
+    use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+    use aes_gcm::aead::{Aead, Payload};
+    use aes_gcm::{Aes256Gcm, KeyInit};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+
+    type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+    type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+    type HmacSha256 = Hmac<Sha256>;
+
+    const CBC_IV_LEN: usize = 16;
+    const GCM_NONCE_LEN: usize = 12;
+    const TAG_LEN: usize = 32;
+
+    pub fn encrypt(suite: u8, key: &[u8; KEY_LEN], nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        match suite {
+            SUITE_AES256_CBC_HMAC_SHA256 => {
+                let iv = check_nonce(suite, nonce, CBC_IV_LEN)?;
+                let (enc_key, mac_key) = split_key(key);
+                let mut body = Aes256CbcEnc::new(&enc_key.into(), iv.into())
+                    .encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+                let tag = cbc_tag(&mac_key, aad, iv, &body);
+                body.extend_from_slice(&tag);
+                Ok(body)
+            }
+            SUITE_AES256_GCM => {
+                let nonce = check_nonce(suite, nonce, GCM_NONCE_LEN)?;
+                Aes256Gcm::new(key.into())
+                    .encrypt(nonce.into(), Payload { msg: plaintext, aad })
+                    .map_err(|_| CipherError::AuthenticationFailed)
+            }
+            _ => Err(CipherError::UnsupportedSuite(suite)),
+        }
+    }
+
+    pub fn decrypt(suite: u8, key: &[u8; KEY_LEN], nonce: &[u8], aad: &[u8], body: &[u8]) -> Result<Vec<u8>, CipherError> {
+        match suite {
+            SUITE_AES256_CBC_HMAC_SHA256 => {
+                let iv = check_nonce(suite, nonce, CBC_IV_LEN)?;
+                if body.len() < TAG_LEN {
+                    return Err(CipherError::AuthenticationFailed);
+                }
+                let (ciphertext, tag) = body.split_at(body.len() - TAG_LEN);
+                let (enc_key, mac_key) = split_key(key);
+                // Check the tag before touching the ciphertext.
+                cbc_mac(&mac_key, aad, iv, ciphertext)
+                    .verify_slice(tag).map_err(|_| CipherError::AuthenticationFailed)?;
+                Aes256CbcDec::new(&enc_key.into(), iv.into())
+                    .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+                    .map_err(|_| CipherError::AuthenticationFailed)
+            }
+            SUITE_AES256_GCM => {
+                let nonce = check_nonce(suite, nonce, GCM_NONCE_LEN)?;
+                Aes256Gcm::new(key.into())
+                    .decrypt(nonce.into(), Payload { msg: body, aad })
+                    .map_err(|_| CipherError::AuthenticationFailed)
+            }
+            _ => Err(CipherError::UnsupportedSuite(suite)),
+        }
+    }
+
+    fn check_nonce(suite: u8, nonce: &[u8], len: usize) -> Result<&[u8], CipherError> {
+        if nonce.len() != len {
+            return Err(CipherError::InvalidNonce { suite, len: nonce.len() });
+        }
+        Ok(nonce)
+    }
+
+    /// Derives independent encryption and MAC keys from the message key.
+    fn split_key(key: &[u8; KEY_LEN]) -> ([u8; 32], [u8; 32]) {
+        let derive = |label: &[u8]| -> [u8; 32] {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+            mac.update(label);
+            mac.finalize().into_bytes().into()
+        };
+        (derive(b"mtc-aes-256-cbc"), derive(b"mtc-hmac-sha256"))
+    }
+
+    /// The MAC covers the lengths of the associated data so that bytes
+    /// can't be moved between it and the ciphertext.
+    fn cbc_mac(mac_key: &[u8; 32], aad: &[u8], iv: &[u8], ciphertext: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(mac_key).expect("HMAC takes keys of any size");
+        mac.update(&(aad.len() as u64).to_be_bytes());
+        mac.update(aad);
+        mac.update(iv);
+        mac.update(ciphertext);
+        mac
+    }
+
+    fn cbc_tag(mac_key: &[u8; 32], aad: &[u8], iv: &[u8], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+        cbc_mac(mac_key, aad, iv, ciphertext).finalize().into_bytes().into()
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+mod suites {
+    use super::*;
+
+    pub fn encrypt(suite: u8, _key: &[u8; KEY_LEN], _nonce: &[u8], _aad: &[u8], _plaintext: &[u8]) -> Result<Vec<u8>, CipherError> {
+        Err(CipherError::UnsupportedSuite(suite))
+    }
+
+    pub fn decrypt(suite: u8, _key: &[u8; KEY_LEN], _nonce: &[u8], _aad: &[u8], _body: &[u8]) -> Result<Vec<u8>, CipherError> {
+        Err(CipherError::UnsupportedSuite(suite))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::envelope::{Extension, EXT_THREAD};
+
+    fn envelope(suite: u8, nonce: Vec<u8>) -> Envelope {
+        Envelope {
+            flags: 0,
+            recipient_hint: [1, 2, 3, 4],
+            suite,
+            nonce,
+            extensions: vec![Extension { ext_type: EXT_THREAD, value: vec![9; 16] }],
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn plaintext_envelopes_open_without_a_key() {
+        let sealed = seal(envelope(SUITE_PLAINTEXT, vec![]), &[0; KEY_LEN], b"hello").unwrap();
+        assert!(!sealed.is_encrypted());
+        let decoded = Envelope::decode(&sealed.encode().unwrap()).unwrap();
+        assert_eq!(open(&decoded, &[7; KEY_LEN]).unwrap(), b"hello");
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn encrypted_suites_need_the_feature() {
+        assert_eq!(
+            seal(envelope(SUITE_AES256_GCM, vec![0; 12]), &[0; KEY_LEN], b"hello"),
+            Err(CipherError::UnsupportedSuite(SUITE_AES256_GCM))
+        );
+        let encrypted = Envelope { flags: FLAG_ENCRYPTED, body: vec![0; 32], ..envelope(SUITE_AES256_GCM, vec![0; 12]) };
+        assert_eq!(open(&encrypted, &[0; KEY_LEN]), Err(CipherError::UnsupportedSuite(SUITE_AES256_GCM)));
+    }
+
+    #[cfg(feature = "encryption")]
+    mod encryption {
+        use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        use super::*;
+
+        fn bytes(vector: &str) -> Vec<u8> {
+            hex::decode(vector).unwrap()
+        }
+
+        /// Test case 16 of the GCM specification submitted to NIST, the
+        /// AES-256 case with associated data.
+        #[test]
+        fn aes_256_gcm_matches_the_nist_vector() {
+            let key: [u8; KEY_LEN] = bytes("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308").try_into().unwrap();
+            let nonce = bytes("cafebabefacedbaddecaf888");
+            let aad = bytes("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+            let plaintext = bytes(
+                "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                 1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+            );
+            let expected = bytes(
+                "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+                 8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662\
+                 76fc6ece0f4e1768cddf8853bb2d551b",
+            );
+            let body = suites::encrypt(SUITE_AES256_GCM, &key, &nonce, &aad, &plaintext).unwrap();
+            assert_eq!(body, expected);
+            assert_eq!(suites::decrypt(SUITE_AES256_GCM, &key, &nonce, &aad, &body).unwrap(), plaintext);
+        }
+
+        /// SP 800-38A, F.2.5 CBC-AES256.Encrypt. PKCS#7 padding appends a
+        /// full block to the four blocks of the vector.
+        #[test]
+        fn aes_256_cbc_matches_sp_800_38a() {
+            let key = bytes("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+            let iv = bytes("000102030405060708090a0b0c0d0e0f");
+            let plaintext = bytes(
+                "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+                 30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+            );
+            let expected = bytes(
+                "f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d\
+                 39f23369a9d9bacfa530e26304231461b2eb05e2c39be9fcda6c19078c6a9d1b",
+            );
+            let ciphertext = cbc::Encryptor::<aes::Aes256>::new(key.as_slice().into(), iv.as_slice().into())
+                .encrypt_padded_vec_mut::<Pkcs7>(&plaintext);
+            assert_eq!(ciphertext.len(), expected.len() + 16);
+            assert_eq!(ciphertext[..expected.len()], expected);
+        }
+
+        /// RFC 4231, test cases 1 and 2.
+        #[test]
+        fn hmac_sha256_matches_rfc_4231() {
+            let cases = [
+                (
+                    vec![0x0b; 20],
+                    b"Hi There".to_vec(),
+                    "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+                ),
+                (
+                    b"Jefe".to_vec(),
+                    b"what do ya want for nothing?".to_vec(),
+                    "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+                ),
+            ];
+            for (key, data, expected) in cases {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).unwrap();
+                mac.update(&data);
+                assert_eq!(mac.finalize().into_bytes().to_vec(), bytes(expected));
+            }
+        }
+
+        #[test]
+        fn sealed_envelopes_open() {
+            let key = [5; KEY_LEN];
+            for (suite, nonce) in [(SUITE_AES256_CBC_HMAC_SHA256, vec![1; 16]), (SUITE_AES256_GCM, vec![1; 12])] {
+                let sealed = seal(envelope(suite, nonce), &key, b"hello").unwrap();
+                assert!(sealed.is_encrypted());
+                let decoded = Envelope::decode(&sealed.encode().unwrap()).unwrap();
+                assert_eq!(open(&decoded, &key).unwrap(), b"hello");
+                assert_eq!(open(&decoded, &[6; KEY_LEN]), Err(CipherError::AuthenticationFailed));
+            }
+        }
+
+        #[test]
+        fn tampering_fails_authentication() {
+            let key = [5; KEY_LEN];
+            for (suite, nonce) in [(SUITE_AES256_CBC_HMAC_SHA256, vec![1; 16]), (SUITE_AES256_GCM, vec![1; 12])] {
+                let sealed = seal(envelope(suite, nonce), &key, b"hello").unwrap();
+                let last = sealed.body.len() - 1;
+                let mut tampered = [sealed.clone(), sealed.clone(), sealed.clone(), sealed.clone()];
+                // The ciphertext, the tag, the header and an extension.
+                tampered[0].body[0] ^= 1;
+                tampered[1].body[last] ^= 1;
+                tampered[2].recipient_hint[0] ^= 1;
+                tampered[3].extensions[0].value[0] ^= 1;
+                for envelope in &tampered {
+                    assert_eq!(open(envelope, &key), Err(CipherError::AuthenticationFailed));
+                }
+                let mut truncated = sealed.clone();
+                truncated.body.truncate(8);
+                assert_eq!(open(&truncated, &key), Err(CipherError::AuthenticationFailed));
+            }
+        }
+
+        #[test]
+        fn rejects_nonces_of_the_wrong_length() {
+            assert_eq!(
+                seal(envelope(SUITE_AES256_GCM, vec![1; 16]), &[5; KEY_LEN], b"hello"),
+                Err(CipherError::InvalidNonce { suite: SUITE_AES256_GCM, len: 16 })
+            );
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! ECIES encryption to a secp256k1 public key.
+//!
+//! Suite `SUITE_SECP256K1_ECIES` lets a client encrypt to any bitcoin
+//! public key, whether or not its owner registered messaging keys:
+//!
+//! 1. The sender picks a fresh ephemeral secret key and computes the ECDH
+//!    shared secret with the recipient's public key (the SHA-256 of the
+//!    compressed shared point, as in libsecp256k1).
+//! 2. The message key is HKDF-SHA256 of the shared secret, salted with
+//!    the ephemeral and the recipient public key (both compressed) and
+//!    with `HKDF_INFO` as info.
+//! 3. The plaintext is encrypted with AES-256-GCM under the 12-byte nonce
+//!    of the envelope, authenticating the envelope with an empty body, as
+//!    for the suites of `cipher`.
+//!
+//! The body is the 33-byte compressed ephemeral public key followed by the
+//! ciphertext and the 16-byte tag. With the 26 bytes of envelope header
+//! and nonce that leaves only 5 bytes of plaintext in an 80-byte OP_RETURN
+//! payload.
+//!
+//! This module only depends on the envelope and on pure-Rust crates, so
+//! off-chain clients can use it as is. The canister can't pick good
+//! ephemeral keys for them anyway, so it never encrypts itself.
+//!
+//! Only keys whose private key someone holds can be recipients, e.g. one
+//! revealed on chain by an external address. The keys of canister
+//! accounts are threshold ECDSA keys: the canister can sign with them but
+//! can't compute an ECDH secret, and nobody holds the private key, so
+//! `send_message` rejects ECIES messages to the canister accounts it
+//! knows. Recipients that use the canister should register messaging keys
+//! instead (see `registry`).
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use bitcoin::secp256k1::{ecdh::SharedSecret, PublicKey, Secp256k1, SecretKey};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::message::cipher::{CipherError, SUITE_SECP256K1_ECIES};
+use crate::message::envelope::{Envelope, FLAG_ENCRYPTED};
+
+pub const HKDF_INFO: &[u8] = b"mtc-ecies-v1";
+
+const NONCE_LEN: usize = 12;
+const PUBLIC_KEY_LEN: usize = 33;
+
+/// Encrypts `plaintext` to `recipient`, a SEC1-encoded public key, into
+/// the body of `envelope`. `ephemeral_secret` must be 32 fresh random
+/// bytes and is never reused. The suite and the ENCRYPTED flag are set
+/// here; the 12-byte nonce must be set by the caller.
+pub fn seal(
+    mut envelope: Envelope,
+    recipient: &[u8],
+    ephemeral_secret: &[u8; 32],
+    plaintext: &[u8],
+) -> Result<Envelope, CipherError> {
+    check_nonce(&envelope)?;
+    let recipient = parse_public_key(recipient)?;
+    let ephemeral_secret = SecretKey::from_slice(ephemeral_secret).map_err(|_| CipherError::InvalidKey)?;
+    let ephemeral_public = PublicKey::from_secret_key(&Secp256k1::signing_only(), &ephemeral_secret);
+    let key = message_key(&SharedSecret::new(&recipient, &ephemeral_secret), &ephemeral_public, &recipient);
+
+    envelope.flags |= FLAG_ENCRYPTED;
+    envelope.suite = SUITE_SECP256K1_ECIES;
+    envelope.body = vec![];
+    let aad = envelope.encode()?;
+    let ciphertext = Aes256Gcm::new(&key.into())
+        .encrypt(envelope.nonce.as_slice().into(), Payload { msg: plaintext, aad: &aad })
+        .map_err(|_| CipherError::AuthenticationFailed)?;
+    let mut body = ephemeral_public.serialize().to_vec();
+    body.extend_from_slice(&ciphertext);
+    envelope.body = body;
+    envelope.validate()?;
+    Ok(envelope)
+}
+
+/// Decrypts an envelope sealed to the public key of `recipient_secret`.
+pub fn open(envelope: &Envelope, recipient_secret: &[u8; 32]) -> Result<Vec<u8>, CipherError> {
+    envelope.validate()?;
+    if envelope.suite != SUITE_SECP256K1_ECIES {
+        return Err(CipherError::UnsupportedSuite(envelope.suite));
+    }
+    check_nonce(envelope)?;
+    if envelope.body.len() < PUBLIC_KEY_LEN {
+        return Err(CipherError::AuthenticationFailed);
+    }
+    let (ephemeral_public, ciphertext) = envelope.body.split_at(PUBLIC_KEY_LEN);
+    let ephemeral_public = parse_public_key(ephemeral_public)?;
+    let recipient_secret = SecretKey::from_slice(recipient_secret).map_err(|_| CipherError::InvalidKey)?;
+    let recipient = PublicKey::from_secret_key(&Secp256k1::signing_only(), &recipient_secret);
+    let key = message_key(&SharedSecret::new(&ephemeral_public, &recipient_secret), &ephemeral_public, &recipient);
+
+    let aad = Envelope { body: vec![], ..envelope.clone() }.encode()?;
+    Aes256Gcm::new(&key.into())
+        .decrypt(envelope.nonce.as_slice().into(), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| CipherError::AuthenticationFailed)
+}
+
+fn message_key(shared_secret: &SharedSecret, ephemeral_public: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = ephemeral_public.serialize().to_vec();
+    salt.extend_from_slice(&recipient.serialize());
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_ref())
+        .expand(HKDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn parse_public_key(key: &[u8]) -> Result<PublicKey, CipherError> {
+    PublicKey::from_slice(key).map_err(|_| CipherError::InvalidKey)
+}
+
+fn check_nonce(envelope: &Envelope) -> Result<(), CipherError> {
+    if envelope.nonce.len() != NONCE_LEN {
+        return Err(CipherError::InvalidNonce { suite: SUITE_SECP256K1_ECIES, len: envelope.nonce.len() });
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+    use crate::message::cipher::CipherError;
+
+    const RECIPIENT_SECRET: [u8; 32] = [0x11; 32];
+    const EPHEMERAL_SECRET: [u8; 32] = [0x22; 32];
+
+    /// "hello" sealed to the public key of `RECIPIENT_SECRET` with
+    /// `EPHEMERAL_SECRET`, the recipient hint `01020304` and the nonce
+    /// `000102030405060708090a0b`. Clients can check their implementation
+    /// against it: the recipient key is
+    /// `034f355bdcb7cc0af728ef3cceb9615d90684bb5b2ca5f859ab0f0b704075871aa`
+    /// and the message key
+    /// `244e76a1ee49ff6541346817e212bc74470c8c8afc8e7f9ce92095938bdbaf63`.
+    const SEALED: &str = "4d5443 01 01 01020304 03 0c 000102030405060708090a0b 00 0036 \
+                          02466d7fcae563e5cb09a0d1870bb580344804617879a14949cf22285f1bae3f27 \
+                          81541e0a2b \
+                          714126d0ed793240093df66bb51c7859";
+
+    fn envelope(nonce: Vec<u8>) -> Envelope {
+        Envelope { flags: 0, recipient_hint: [1, 2, 3, 4], suite: 0, nonce, extensions: vec![], body: vec![] }
+    }
+
+    fn recipient_key() -> [u8; 33] {
+        let secret = SecretKey::from_slice(&RECIPIENT_SECRET).unwrap();
+        PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret).serialize()
+    }
+
+    fn sealed() -> Envelope {
+        seal(envelope((0..12).collect()), &recipient_key(), &EPHEMERAL_SECRET, b"hello").unwrap()
+    }
+
+    #[test]
+    fn sealing_with_a_fixed_ephemeral_key_matches_the_vector() {
+        let expected = hex::decode(SEALED.replace(' ', "")).unwrap();
+        let sealed = sealed();
+        assert_eq!(sealed.encode().unwrap(), expected);
+        assert_eq!(expected.len(), 80);
+        assert_eq!(open(&Envelope::decode(&expected).unwrap(), &RECIPIENT_SECRET).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn sealed_messages_open_with_the_recipient_secret() {
+        let sealed = seal(envelope(vec![5; 12]), &recipient_key(), &[0x33; 32], b"a longer message").unwrap();
+        assert!(sealed.is_encrypted());
+        assert_eq!(sealed.suite, SUITE_SECP256K1_ECIES);
+        let decoded = Envelope::decode(&sealed.encode().unwrap()).unwrap();
+        assert_eq!(open(&decoded, &RECIPIENT_SECRET).unwrap(), b"a longer message");
+    }
+
+    #[test]
+    fn other_secrets_dont_open_sealed_messages() {
+        assert_eq!(open(&sealed(), &[0x12; 32]), Err(CipherError::AuthenticationFailed));
+        assert_eq!(open(&sealed(), &[0; 32]), Err(CipherError::InvalidKey));
+    }
+
+    #[test]
+    fn tampered_envelopes_dont_open() {
+        let other_secret = SecretKey::from_slice(&[0x44; 32]).unwrap();
+        let other_ephemeral = PublicKey::from_secret_key(&Secp256k1::signing_only(), &other_secret);
+        let mut ephemeral = sealed();
+        ephemeral.body[..PUBLIC_KEY_LEN].copy_from_slice(&other_ephemeral.serialize());
+        assert_eq!(open(&ephemeral, &RECIPIENT_SECRET), Err(CipherError::AuthenticationFailed));
+
+        let mut not_a_key = sealed();
+        not_a_key.body[0] = 0x05;
+        assert_eq!(open(&not_a_key, &RECIPIENT_SECRET), Err(CipherError::InvalidKey));
+
+        let header = Envelope { recipient_hint: [1, 2, 3, 5], ..sealed() };
+        assert_eq!(open(&header, &RECIPIENT_SECRET), Err(CipherError::AuthenticationFailed));
+
+        let mut nonce = sealed();
+        nonce.nonce[0] ^= 1;
+        assert_eq!(open(&nonce, &RECIPIENT_SECRET), Err(CipherError::AuthenticationFailed));
+
+        let mut body = sealed();
+        body.body[PUBLIC_KEY_LEN] ^= 1;
+        assert_eq!(open(&body, &RECIPIENT_SECRET), Err(CipherError::AuthenticationFailed));
+
+        let mut tag = sealed();
+        *tag.body.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&tag, &RECIPIENT_SECRET), Err(CipherError::AuthenticationFailed));
+    }
+
+    #[test]
+    fn malformed_nonces_and_keys_are_rejected() {
+        let nonce_error = CipherError::InvalidNonce { suite: SUITE_SECP256K1_ECIES, len: 8 };
+        assert_eq!(seal(envelope(vec![0; 8]), &recipient_key(), &EPHEMERAL_SECRET, b"hello"), Err(nonce_error.clone()));
+        let short_nonce = Envelope { nonce: vec![0; 8], ..sealed() };
+        assert_eq!(open(&short_nonce, &RECIPIENT_SECRET), Err(nonce_error));
+
+        assert_eq!(
+            seal(envelope(vec![0; 12]), &recipient_key()[1..], &EPHEMERAL_SECRET, b"hello"),
+            Err(CipherError::InvalidKey)
+        );
+        let short_key = Envelope { body: sealed().body[..PUBLIC_KEY_LEN - 1].to_vec(), ..sealed() };
+        assert_eq!(open(&short_key, &RECIPIENT_SECRET), Err(CipherError::AuthenticationFailed));
+    }
+}
@@ -0,0 +1,418 @@
+//! The binary envelope every MTC message is wrapped in.
+//!
+//! All integers are unsigned and multi-byte integers are big-endian:
+//!
+//! ```text
+//! magic           3 bytes   "MTC"
+//! version         1 byte    1
+//! flags           1 byte    ENCRYPTED | COMPRESSED | FRAGMENTED
+//! recipient_hint  4 bytes   see `recipient_hint`
+//! suite           1 byte    the cipher suite, 0 for plaintext
+//! nonce_len       1 byte    at most MAX_NONCE_LEN
+//! nonce           nonce_len bytes
+//! ext_count       1 byte    at most MAX_EXTENSIONS
+//! extensions      ext_count times: type (1 byte), len (1 byte), value
+//! body_len        2 bytes
+//! body            body_len bytes, the ciphertext if ENCRYPTED is set
+//! ```
+//!
+//! Parsing is strict: unknown flags, an unsupported version, a nonce on a
+//! plaintext envelope, duplicate extension types, lengths that run past
+//! the input and trailing bytes are all rejected, so every envelope has a
+//! single encoding.
+//!
+//! For example, the plaintext envelope with recipient hint `01020304`, no
+//! extensions and the body "hi" encodes to
+//!
+//! ```text
+//! 4d5443 01 00 01020304 00 00 00 0002 6869
+//! ```
+//!
+//! and the envelope with the ENCRYPTED flag, suite 2, the 12-byte nonce
+//! `000102030405060708090a0b`, one extension of type `7f` with the value
+//! `ff` and the body `aabbcc` encodes to
+//!
+//! ```text
+//! 4d5443 01 01 01020304 02 0c 000102030405060708090a0b 01 7f 01 ff 0003 aabbcc
+//! ```
+use std::fmt;
+
+use crate::utils::sha256;
+
+pub const MAGIC: [u8; 3] = *b"MTC";
+pub const VERSION: u8 = 1;
+
+/// The body is encrypted with `suite`.
+pub const FLAG_ENCRYPTED: u8 = 0x01;
+/// The plaintext was compressed before it was encrypted.
+pub const FLAG_COMPRESSED: u8 = 0x02;
+/// The body is one fragment of a larger message.
+pub const FLAG_FRAGMENTED: u8 = 0x04;
+const KNOWN_FLAGS: u8 = FLAG_ENCRYPTED | FLAG_COMPRESSED | FLAG_FRAGMENTED;
+
+/// The suite of envelopes whose body is not encrypted.
+pub const SUITE_PLAINTEXT: u8 = 0;
+
+/// Marks a fragment of a larger message; see `fragment`.
+pub const EXT_FRAGMENT: u8 = 0x01;
+/// Carries a delivery receipt; see `receipt`.
+pub const EXT_RECEIPT: u8 = 0x02;
+/// Names the group and key epoch of a group message; see `group`.
+pub const EXT_GROUP: u8 = 0x03;
+/// Places the message in a conversation; see `thread`.
+pub const EXT_THREAD: u8 = 0x04;
+
+pub const MAX_NONCE_LEN: usize = 24;
+pub const MAX_EXTENSIONS: usize = 8;
+/// The size of an envelope without nonce, extensions and body.
+pub const HEADER_LEN: usize = 3 + 1 + 1 + 4 + 1 + 1 + 1 + 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub ext_type: u8,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub flags: u8,
+    pub recipient_hint: [u8; 4],
+    pub suite: u8,
+    pub nonce: Vec<u8>,
+    pub extensions: Vec<Extension>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvelopeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownFlags(u8),
+    /// The suite or nonce doesn't match the ENCRYPTED flag.
+    InconsistentSuite { flags: u8, suite: u8 },
+    NonceTooLong(usize),
+    TooManyExtensions(usize),
+    DuplicateExtension(u8),
+    ExtensionTooLong { ext_type: u8, len: usize },
+    BodyTooLong(usize),
+    /// The input ended before the field it names.
+    Truncated(&'static str),
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvelopeError::BadMagic => write!(f, "not an MTC envelope"),
+            EnvelopeError::UnsupportedVersion(version) => write!(f, "unsupported envelope version {}", version),
+            EnvelopeError::UnknownFlags(flags) => write!(f, "unknown flags {:#04x}", flags),
+            EnvelopeError::InconsistentSuite { flags, suite } => {
+                write!(f, "suite {} does not match flags {:#04x}", suite, flags)
+            }
+            EnvelopeError::NonceTooLong(len) => write!(f, "the nonce is {} bytes long, at most {} are allowed", len, MAX_NONCE_LEN),
+            EnvelopeError::TooManyExtensions(count) => write!(f, "{} extensions, at most {} are allowed", count, MAX_EXTENSIONS),
+            EnvelopeError::DuplicateExtension(ext_type) => write!(f, "extension {} appears twice", ext_type),
+            EnvelopeError::ExtensionTooLong { ext_type, len } => {
+                write!(f, "extension {} is {} bytes long, at most 255 are allowed", ext_type, len)
+            }
+            EnvelopeError::BodyTooLong(len) => write!(f, "the body is {} bytes long, at most {} are allowed", len, u16::MAX),
+            EnvelopeError::Truncated(field) => write!(f, "the envelope ends before its {}", field),
+            EnvelopeError::TrailingBytes(len) => write!(f, "{} bytes follow the envelope", len),
+        }
+    }
+}
+
+impl Envelope {
+    /// Checks the invariants `decode` enforces, so that only envelopes
+    /// that decode again are encoded.
+    pub fn validate(&self) -> Result<(), EnvelopeError> {
+        if self.flags & !KNOWN_FLAGS != 0 {
+            return Err(EnvelopeError::UnknownFlags(self.flags));
+        }
+        let encrypted = self.flags & FLAG_ENCRYPTED != 0;
+        if encrypted == (self.suite == SUITE_PLAINTEXT) || (!encrypted && !self.nonce.is_empty()) {
+            return Err(EnvelopeError::InconsistentSuite { flags: self.flags, suite: self.suite });
+        }
+        if self.nonce.len() > MAX_NONCE_LEN {
+            return Err(EnvelopeError::NonceTooLong(self.nonce.len()));
+        }
+        if self.extensions.len() > MAX_EXTENSIONS {
+            return Err(EnvelopeError::TooManyExtensions(self.extensions.len()));
+        }
+        for (index, extension) in self.extensions.iter().enumerate() {
+            if extension.value.len() > u8::MAX as usize {
+                return Err(EnvelopeError::ExtensionTooLong {
+                    ext_type: extension.ext_type,
+                    len: extension.value.len(),
+                });
+            }
+            if self.extensions[..index].iter().any(|other| other.ext_type == extension.ext_type) {
+                return Err(EnvelopeError::DuplicateExtension(extension.ext_type));
+            }
+        }
+        if self.body.len() > u16::MAX as usize {
+            return Err(EnvelopeError::BodyTooLong(self.body.len()));
+        }
+        Ok(())
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN
+            + self.nonce.len()
+            + self.extensions.iter().map(|extension| 2 + extension.value.len()).sum::<usize>()
+            + self.body.len()
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, EnvelopeError> {
+        self.validate()?;
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.flags);
+        bytes.extend_from_slice(&self.recipient_hint);
+        bytes.push(self.suite);
+        bytes.push(self.nonce.len() as u8);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.push(self.extensions.len() as u8);
+        for extension in &self.extensions {
+            bytes.push(extension.ext_type);
+            bytes.push(extension.value.len() as u8);
+            bytes.extend_from_slice(&extension.value);
+        }
+        bytes.extend_from_slice(&(self.body.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.body);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        let (envelope, len) = Self::decode_prefix(bytes)?;
+        if len != bytes.len() {
+            return Err(EnvelopeError::TrailingBytes(bytes.len() - len));
+        }
+        Ok(envelope)
+    }
+
+    /// Decodes the envelope at the start of `bytes` and returns it with
+    /// its encoded length.
+    pub fn decode_prefix(bytes: &[u8]) -> Result<(Self, usize), EnvelopeError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len(), "magic")? != MAGIC {
+            return Err(EnvelopeError::BadMagic);
+        }
+        let version = reader.byte("version")?;
+        if version != VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        let flags = reader.byte("flags")?;
+        let recipient_hint = reader
+            .take(4, "recipient hint")?
+            .try_into()
+            .expect("took 4 bytes");
+        let suite = reader.byte("suite")?;
+        let nonce_len = reader.byte("nonce length")? as usize;
+        if nonce_len > MAX_NONCE_LEN {
+            return Err(EnvelopeError::NonceTooLong(nonce_len));
+        }
+        let nonce = reader.take(nonce_len, "nonce")?.to_vec();
+        let ext_count = reader.byte("extension count")? as usize;
+        if ext_count > MAX_EXTENSIONS {
+            return Err(EnvelopeError::TooManyExtensions(ext_count));
+        }
+        let mut extensions = Vec::with_capacity(ext_count);
+        for _ in 0..ext_count {
+            let ext_type = reader.byte("extension type")?;
+            let len = reader.byte("extension length")? as usize;
+            let value = reader.take(len, "extension value")?.to_vec();
+            extensions.push(Extension { ext_type, value });
+        }
+        let body_len = u16::from_be_bytes(
+            reader.take(2, "body length")?.try_into().expect("took 2 bytes"),
+        ) as usize;
+        let body = reader.take(body_len, "body")?.to_vec();
+        let envelope = Envelope { flags, recipient_hint, suite, nonce, extensions, body };
+        envelope.validate()?;
+        Ok((envelope, bytes.len() - reader.bytes.len()))
+    }
+
+    /// Decodes a sequence of envelopes written back to back, as in a
+    /// taproot reveal script.
+    pub fn decode_all(mut bytes: &[u8]) -> Result<Vec<Self>, EnvelopeError> {
+        let mut envelopes = vec![];
+        while !bytes.is_empty() {
+            let (envelope, len) = Self::decode_prefix(bytes)?;
+            envelopes.push(envelope);
+            bytes = &bytes[len..];
+        }
+        Ok(envelopes)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    pub fn extension(&self, ext_type: u8) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.ext_type == ext_type)
+            .map(|extension| extension.value.as_slice())
+    }
+}
+
+/// Returns the recipient hint for an output script: the first 4 bytes of
+/// its SHA-256. It lets a recipient skip most envelopes addressed to
+/// someone else without trying to decrypt them.
+pub fn recipient_hint(script_pubkey: &[u8]) -> [u8; 4] {
+    sha256(script_pubkey)[..4].try_into().expect("SHA-256 is 32 bytes long")
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, field: &'static str) -> Result<&'a [u8], EnvelopeError> {
+        if self.bytes.len() < len {
+            return Err(EnvelopeError::Truncated(field));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self, field: &'static str) -> Result<u8, EnvelopeError> {
+        Ok(self.take(1, field)?[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The examples of the module documentation.
+    const PLAINTEXT: &str = "4d5443 01 00 01020304 00 00 00 0002 6869";
+    const ENCRYPTED: &str = "4d5443 01 01 01020304 02 0c 000102030405060708090a0b 01 7f 01 ff 0003 aabbcc";
+
+    fn golden(vector: &str) -> Vec<u8> {
+        hex::decode(vector.replace(' ', "")).unwrap()
+    }
+
+    fn plaintext() -> Envelope {
+        Envelope {
+            flags: 0,
+            recipient_hint: [1, 2, 3, 4],
+            suite: SUITE_PLAINTEXT,
+            nonce: vec![],
+            extensions: vec![],
+            body: b"hi".to_vec(),
+        }
+    }
+
+    fn encrypted() -> Envelope {
+        Envelope {
+            flags: FLAG_ENCRYPTED,
+            recipient_hint: [1, 2, 3, 4],
+            suite: 2,
+            nonce: (0..12).collect(),
+            extensions: vec![Extension { ext_type: 0x7f, value: vec![0xff] }],
+            body: vec![0xaa, 0xbb, 0xcc],
+        }
+    }
+
+    #[test]
+    fn golden_vectors_round_trip() {
+        for (vector, envelope) in [(PLAINTEXT, plaintext()), (ENCRYPTED, encrypted())] {
+            let bytes = golden(vector);
+            assert_eq!(envelope.encode().unwrap(), bytes);
+            assert_eq!(envelope.encoded_len(), bytes.len());
+            assert_eq!(Envelope::decode(&bytes).unwrap(), envelope);
+        }
+    }
+
+    #[test]
+    fn back_to_back_envelopes_decode() {
+        let mut bytes = golden(PLAINTEXT);
+        bytes.extend_from_slice(&golden(ENCRYPTED));
+        assert_eq!(Envelope::decode_all(&bytes).unwrap(), vec![plaintext(), encrypted()]);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = golden(PLAINTEXT);
+        bytes[2] = b'D';
+        assert_eq!(Envelope::decode(&bytes), Err(EnvelopeError::BadMagic));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = golden(PLAINTEXT);
+        bytes[3] = 2;
+        assert_eq!(Envelope::decode(&bytes), Err(EnvelopeError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let mut bytes = golden(PLAINTEXT);
+        bytes[4] = 0x08;
+        assert_eq!(Envelope::decode(&bytes), Err(EnvelopeError::UnknownFlags(0x08)));
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_input() {
+        let bytes = golden(PLAINTEXT);
+        // The body length claims one byte more than there is.
+        let mut long_body = bytes.clone();
+        long_body[13] = 3;
+        assert_eq!(Envelope::decode(&long_body), Err(EnvelopeError::Truncated("body")));
+        assert_eq!(Envelope::decode(&bytes[..bytes.len() - 1]), Err(EnvelopeError::Truncated("body")));
+        assert_eq!(Envelope::decode(&bytes[..11]), Err(EnvelopeError::Truncated("extension count")));
+
+        let mut long_nonce = golden(ENCRYPTED);
+        long_nonce[10] = MAX_NONCE_LEN as u8 + 1;
+        assert_eq!(Envelope::decode(&long_nonce), Err(EnvelopeError::NonceTooLong(MAX_NONCE_LEN + 1)));
+
+        let mut long_extension = golden(ENCRYPTED);
+        long_extension[25] = 0xff;
+        assert_eq!(Envelope::decode(&long_extension), Err(EnvelopeError::Truncated("extension value")));
+
+        let mut many_extensions = golden(PLAINTEXT);
+        many_extensions[11] = MAX_EXTENSIONS as u8 + 1;
+        assert_eq!(
+            Envelope::decode(&many_extensions),
+            Err(EnvelopeError::TooManyExtensions(MAX_EXTENSIONS + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = golden(PLAINTEXT);
+        bytes.push(0);
+        assert_eq!(Envelope::decode(&bytes), Err(EnvelopeError::TrailingBytes(1)));
+        // A sequence of envelopes can't end in a partial one.
+        assert_eq!(Envelope::decode_all(&bytes), Err(EnvelopeError::Truncated("magic")));
+    }
+
+    #[test]
+    fn rejects_suites_that_disagree_with_the_flags() {
+        // ENCRYPTED with the plaintext suite.
+        let mut bytes = golden(PLAINTEXT);
+        bytes[4] = FLAG_ENCRYPTED;
+        assert_eq!(
+            Envelope::decode(&bytes),
+            Err(EnvelopeError::InconsistentSuite { flags: FLAG_ENCRYPTED, suite: SUITE_PLAINTEXT })
+        );
+        // A cipher suite without ENCRYPTED.
+        let mut bytes = golden(ENCRYPTED);
+        bytes[4] = 0;
+        assert_eq!(Envelope::decode(&bytes), Err(EnvelopeError::InconsistentSuite { flags: 0, suite: 2 }));
+        // A nonce on a plaintext envelope.
+        let envelope = Envelope { nonce: vec![0; 12], ..plaintext() };
+        assert_eq!(envelope.encode(), Err(EnvelopeError::InconsistentSuite { flags: 0, suite: SUITE_PLAINTEXT }));
+    }
+
+    #[test]
+    fn rejects_duplicate_extensions() {
+        let extension = Extension { ext_type: EXT_THREAD, value: vec![] };
+        let envelope = Envelope { extensions: vec![extension.clone(), extension], ..plaintext() };
+        assert_eq!(envelope.encode(), Err(EnvelopeError::DuplicateExtension(EXT_THREAD)));
+    }
+}
@@ -0,0 +1,382 @@
+//! Splits messages too large for one OP_RETURN output into fragments.
+//!
+//! The encoded message envelope is cut into chunks, and every chunk is the
+//! body of a plaintext fragment envelope with the FRAGMENTED flag and an
+//! `EXT_FRAGMENT` extension. The extension value is 20 bytes:
+//!
+//! ```text
+//! message_id  16 bytes  the first 16 bytes of the SHA-256 of the message
+//! index        2 bytes  the position of the fragment, from 0
+//! total        2 bytes  the number of fragments
+//! ```
+//!
+//! The fragments are sent in a chain of transactions (see
+//! `send_btc::send_chain`), but they may be seen in any order, so
+//! `Reassembler` orders them by index and checks the content hash once all
+//! of them have arrived.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::message::envelope::{Envelope, EnvelopeError, Extension, EXT_FRAGMENT, FLAG_FRAGMENTED, HEADER_LEN, SUITE_PLAINTEXT};
+use crate::utils::sha256;
+
+pub const MESSAGE_ID_LEN: usize = 16;
+const FRAGMENT_EXT_LEN: usize = MESSAGE_ID_LEN + 2 + 2;
+/// The size of a fragment envelope without its body.
+pub const FRAGMENT_OVERHEAD: usize = HEADER_LEN + 2 + FRAGMENT_EXT_LEN;
+/// The largest number of fragments a message is split into. Every
+/// fragment is sent in its own transaction of a chain, and nodes don't
+/// relay chains longer than `send_btc::MAX_CHAIN_LENGTH`.
+pub const MAX_FRAGMENTS: usize = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentInfo {
+    pub message_id: [u8; MESSAGE_ID_LEN],
+    pub index: u16,
+    pub total: u16,
+}
+
+impl FragmentInfo {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.message_id.to_vec();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.total.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, FragmentError> {
+        if bytes.len() != FRAGMENT_EXT_LEN {
+            return Err(FragmentError::Malformed("the fragment extension must be 20 bytes long"));
+        }
+        let info = FragmentInfo {
+            message_id: bytes[..MESSAGE_ID_LEN].try_into().expect("checked the length"),
+            index: u16::from_be_bytes([bytes[16], bytes[17]]),
+            total: u16::from_be_bytes([bytes[18], bytes[19]]),
+        };
+        if info.total == 0 || info.index >= info.total {
+            return Err(FragmentError::Malformed("the fragment index must be below the total"));
+        }
+        if info.total as usize > MAX_FRAGMENTS {
+            return Err(FragmentError::Malformed("a message has at most 25 fragments"));
+        }
+        Ok(info)
+    }
+
+    /// Returns the fragment information of `envelope`, or `None` if it is
+    /// not a fragment.
+    pub fn of(envelope: &Envelope) -> Result<Option<Self>, FragmentError> {
+        if envelope.flags & FLAG_FRAGMENTED == 0 {
+            return Ok(None);
+        }
+        match envelope.extension(EXT_FRAGMENT) {
+            Some(value) => Self::decode(value).map(Some),
+            None => Err(FragmentError::Malformed("a fragmented envelope needs a fragment extension")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FragmentError {
+    Malformed(&'static str),
+    TooLarge { len: usize, max: usize },
+    /// The fragment belongs to another message, or disagrees with the
+    /// fragments seen so far about the total.
+    Mismatch,
+    /// Two fragments with the same index have different bodies.
+    Conflict(u16),
+    Missing(Vec<u16>),
+    HashMismatch,
+    Envelope(EnvelopeError),
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::Malformed(reason) => write!(f, "malformed fragment: {}", reason),
+            FragmentError::TooLarge { len, max } => {
+                write!(f, "the message is {} bytes long, at most {} bytes can be fragmented", len, max)
+            }
+            FragmentError::Mismatch => write!(f, "the fragment belongs to another message"),
+            FragmentError::Conflict(index) => write!(f, "fragment {} was seen with two different bodies", index),
+            FragmentError::Missing(indexes) => write!(f, "fragments {:?} are missing", indexes),
+            FragmentError::HashMismatch => write!(f, "the reassembled message does not match its id"),
+            FragmentError::Envelope(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<EnvelopeError> for FragmentError {
+    fn from(err: EnvelopeError) -> Self {
+        FragmentError::Envelope(err)
+    }
+}
+
+/// Returns the id of a message: the first 16 bytes of its SHA-256.
+pub fn message_id(message: &[u8]) -> [u8; MESSAGE_ID_LEN] {
+    sha256(message)[..MESSAGE_ID_LEN].try_into().expect("SHA-256 is 32 bytes long")
+}
+
+/// Splits the encoded envelope `message` into fragment envelopes of at
+/// most `max_size` bytes each.
+pub fn fragment(message: &[u8], recipient_hint: [u8; 4], max_size: usize) -> Result<Vec<Envelope>, FragmentError> {
+    let chunk_size = max_size.saturating_sub(FRAGMENT_OVERHEAD);
+    if chunk_size == 0 {
+        return Err(FragmentError::Malformed("fragments must be larger than their overhead"));
+    }
+    let max = chunk_size * MAX_FRAGMENTS;
+    if message.is_empty() || message.len() > max {
+        return Err(FragmentError::TooLarge { len: message.len(), max });
+    }
+    let message_id = message_id(message);
+    let chunks: Vec<&[u8]> = message.chunks(chunk_size).collect();
+    let total = chunks.len() as u16;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let info = FragmentInfo { message_id, index: index as u16, total };
+            let envelope = Envelope {
+                flags: FLAG_FRAGMENTED,
+                recipient_hint,
+                suite: SUITE_PLAINTEXT,
+                nonce: vec![],
+                extensions: vec![Extension { ext_type: EXT_FRAGMENT, value: info.encode() }],
+                body: chunk.to_vec(),
+            };
+            envelope.validate()?;
+            Ok(envelope)
+        })
+        .collect()
+}
+
+/// Groups `envelopes` into the messages they carry, in order of first
+/// appearance. Envelopes that aren't fragments are messages of their own;
+/// fragments must make up complete messages.
+pub fn collect_messages(envelopes: &[Envelope]) -> Result<Vec<([u8; MESSAGE_ID_LEN], Envelope)>, FragmentError> {
+    let mut messages = vec![];
+    let mut reassemblers: Vec<Reassembler> = vec![];
+    for envelope in envelopes {
+        let Some(info) = FragmentInfo::of(envelope)? else {
+            messages.push((message_id(&envelope.encode()?), envelope.clone()));
+            continue;
+        };
+        match reassemblers.iter_mut().find(|reassembler| reassembler.message_id() == Some(info.message_id)) {
+            Some(reassembler) => reassembler.add(envelope)?,
+            None => {
+                let mut reassembler = Reassembler::new();
+                reassembler.add(envelope)?;
+                reassemblers.push(reassembler);
+            }
+        }
+    }
+    for reassembler in reassemblers {
+        let message = reassembler.finish()?;
+        messages.push((reassembler.message_id().expect("fragments were added"), message));
+    }
+    Ok(messages)
+}
+
+/// Collects the fragments of one message.
+#[derive(Debug, Clone, Default)]
+pub struct Reassembler {
+    message_id: Option<[u8; MESSAGE_ID_LEN]>,
+    total: u16,
+    fragments: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a fragment envelope. Fragments seen twice are ignored.
+    pub fn add(&mut self, envelope: &Envelope) -> Result<(), FragmentError> {
+        let info = FragmentInfo::of(envelope)?.ok_or(FragmentError::Malformed("not a fragment"))?;
+        match self.message_id {
+            None => {
+                self.message_id = Some(info.message_id);
+                self.total = info.total;
+            }
+            Some(message_id) if message_id != info.message_id || self.total != info.total => {
+                return Err(FragmentError::Mismatch)
+            }
+            Some(_) => {}
+        }
+        match self.fragments.get(&info.index) {
+            Some(body) if *body != envelope.body => Err(FragmentError::Conflict(info.index)),
+            Some(_) => Ok(()),
+            None => {
+                self.fragments.insert(info.index, envelope.body.clone());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn message_id(&self) -> Option<[u8; MESSAGE_ID_LEN]> {
+        self.message_id
+    }
+
+    /// Returns the indexes of the fragments not seen yet.
+    pub fn missing(&self) -> Vec<u16> {
+        (0..self.total).filter(|index| !self.fragments.contains_key(index)).collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.message_id.is_some() && self.fragments.len() == self.total as usize
+    }
+
+    /// Returns the reassembled message envelope once every fragment has
+    /// been added and the content matches the message id.
+    pub fn finish(&self) -> Result<Envelope, FragmentError> {
+        let Some(expected_id) = self.message_id else {
+            return Err(FragmentError::Missing(vec![]));
+        };
+        let missing = self.missing();
+        if !missing.is_empty() {
+            return Err(FragmentError::Missing(missing));
+        }
+        let message: Vec<u8> = self.fragments.values().flatten().copied().collect();
+        if message_id(&message) != expected_id {
+            return Err(FragmentError::HashMismatch);
+        }
+        Ok(Envelope::decode(&message)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fragment envelope fits the 80 bytes of an OP_RETURN output.
+    const MAX_SIZE: usize = 80;
+
+    fn message(body_len: usize) -> Envelope {
+        Envelope {
+            flags: 0x01,
+            recipient_hint: [1, 2, 3, 4],
+            suite: 2,
+            nonce: vec![7; 12],
+            extensions: vec![],
+            body: (0..body_len).map(|byte| byte as u8).collect(),
+        }
+    }
+
+    fn fragments(body_len: usize) -> Vec<Envelope> {
+        fragment(&message(body_len).encode().unwrap(), [1, 2, 3, 4], MAX_SIZE).unwrap()
+    }
+
+    /// Rewrites the fragment extension of `envelope` with `f`.
+    fn with_info(envelope: &Envelope, f: impl FnOnce(&mut FragmentInfo)) -> Envelope {
+        let mut info = FragmentInfo::of(envelope).unwrap().unwrap();
+        f(&mut info);
+        let mut envelope = envelope.clone();
+        envelope.extensions = vec![Extension { ext_type: EXT_FRAGMENT, value: info.encode() }];
+        envelope
+    }
+
+    #[test]
+    fn fragments_reassemble_into_the_message() {
+        let fragments = fragments(150);
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|fragment| fragment.encode().unwrap().len() <= MAX_SIZE));
+        let mut reassembler = Reassembler::new();
+        for fragment in &fragments {
+            reassembler.add(fragment).unwrap();
+        }
+        assert!(reassembler.is_complete());
+        assert_eq!(reassembler.finish(), Ok(message(150)));
+    }
+
+    #[test]
+    fn fragments_can_arrive_in_any_order() {
+        let fragments = fragments(150);
+        let mut reassembler = Reassembler::new();
+        for index in [2, 0, 3, 0, 1] {
+            reassembler.add(&fragments[index]).unwrap();
+        }
+        assert_eq!(reassembler.finish(), Ok(message(150)));
+    }
+
+    #[test]
+    fn missing_fragments_are_listed() {
+        let fragments = fragments(150);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.finish(), Err(FragmentError::Missing(vec![])));
+        reassembler.add(&fragments[1]).unwrap();
+        reassembler.add(&fragments[3]).unwrap();
+        assert_eq!(reassembler.missing(), vec![0, 2]);
+        assert!(!reassembler.is_complete());
+        assert_eq!(reassembler.finish(), Err(FragmentError::Missing(vec![0, 2])));
+    }
+
+    #[test]
+    fn tampered_chunks_fail_the_content_hash() {
+        let mut fragments = fragments(150);
+        fragments[1].body[0] ^= 1;
+        let mut reassembler = Reassembler::new();
+        for fragment in &fragments {
+            reassembler.add(fragment).unwrap();
+        }
+        assert!(reassembler.is_complete());
+        assert_eq!(reassembler.finish(), Err(FragmentError::HashMismatch));
+    }
+
+    #[test]
+    fn a_second_body_for_an_index_conflicts() {
+        let fragments = fragments(150);
+        let mut other = fragments[2].clone();
+        other.body[0] ^= 1;
+        let mut reassembler = Reassembler::new();
+        reassembler.add(&fragments[2]).unwrap();
+        assert_eq!(reassembler.add(&other), Err(FragmentError::Conflict(2)));
+    }
+
+    #[test]
+    fn fragments_of_another_message_or_total_mismatch() {
+        let fragments = fragments(150);
+        let mut reassembler = Reassembler::new();
+        reassembler.add(&fragments[0]).unwrap();
+        let other_message = with_info(&fragments[1], |info| info.message_id = [9; MESSAGE_ID_LEN]);
+        assert_eq!(reassembler.add(&other_message), Err(FragmentError::Mismatch));
+        let other_total = with_info(&fragments[1], |info| info.total = 5);
+        assert_eq!(reassembler.add(&other_total), Err(FragmentError::Mismatch));
+        assert_eq!(reassembler.missing(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn messages_above_the_fragment_limit_are_too_large() {
+        let chunk_size = MAX_SIZE - FRAGMENT_OVERHEAD;
+        let max = chunk_size * MAX_FRAGMENTS;
+        assert_eq!(fragment(&vec![1; max], [1, 2, 3, 4], MAX_SIZE).unwrap().len(), MAX_FRAGMENTS);
+        assert_eq!(
+            fragment(&vec![1; max + 1], [1, 2, 3, 4], MAX_SIZE),
+            Err(FragmentError::TooLarge { len: max + 1, max })
+        );
+        assert_eq!(fragment(&[], [1, 2, 3, 4], MAX_SIZE), Err(FragmentError::TooLarge { len: 0, max }));
+    }
+
+    #[test]
+    fn announced_totals_above_the_fragment_limit_are_rejected() {
+        let info = FragmentInfo { message_id: [1; MESSAGE_ID_LEN], index: 0, total: MAX_FRAGMENTS as u16 };
+        assert_eq!(FragmentInfo::decode(&info.encode()), Ok(info));
+        let info = FragmentInfo { total: MAX_FRAGMENTS as u16 + 1, ..info };
+        assert!(matches!(FragmentInfo::decode(&info.encode()), Err(FragmentError::Malformed(_))));
+        let info = FragmentInfo { total: u16::MAX, ..info };
+        assert!(matches!(FragmentInfo::decode(&info.encode()), Err(FragmentError::Malformed(_))));
+    }
+
+    #[test]
+    fn collected_messages_group_fragments_by_id() {
+        let first = fragments(150);
+        let second = fragments(100);
+        let single = message(10);
+        let envelopes = vec![second[1].clone(), first[0].clone(), single.clone(), second[2].clone(), second[0].clone()]
+            .into_iter()
+            .chain(first[1..].iter().cloned())
+            .collect::<Vec<_>>();
+        let messages = collect_messages(&envelopes).unwrap();
+        let messages: Vec<Envelope> = messages.into_iter().map(|(_, message)| message).collect();
+        assert_eq!(messages, vec![single, message(100), message(150)]);
+        assert!(matches!(collect_messages(&first[1..]), Err(FragmentError::Missing(missing)) if missing == vec![0]));
+    }
+}
@@ -0,0 +1,594 @@
+//! Encrypted group conversations.
+//!
+//! A group is owned by the principal that created it and has up to
+//! `MAX_GROUP_MEMBERS` members, the owner included. Its messages are
+//! encrypted with a symmetric group key that changes on every membership
+//! change: each key is an epoch, numbered from 0, and the key of every
+//! epoch is wrapped to the registered X25519 agreement key of each member
+//! of that epoch (see `registry`). The canister never sees the group key:
+//! the client changing the membership generates the new key and submits
+//! it wrapped, and the canister checks that exactly the new members got a
+//! copy. A wrapped key is 92 bytes:
+//!
+//! ```text
+//! ephemeral_key  32 bytes  an X25519 public key
+//! nonce          12 bytes
+//! ciphertext     48 bytes  the AES-256-GCM encryption of the 32-byte key
+//! ```
+//!
+//! with the AES key HKDF-SHA256(X25519(ephemeral, agreement_key)), salted
+//! with `ephemeral_key || agreement_key` and the info `GROUP_KEY_INFO ||
+//! group_id || epoch` (epoch as 4 big-endian bytes).
+//!
+//! Every group has its own address, derived from a subaccount of the
+//! canister, so a group message is a single envelope sent to that address
+//! rather than one per member. It is encrypted with the current epoch key
+//! and carries an `EXT_GROUP` extension with the 16-byte group id and the
+//! 4-byte epoch, so members know which key opens it. Members index and
+//! read the group's messages through the canister, and only current
+//! members can: a removed member keeps the keys of the epochs it was part
+//! of, and can still open the messages of those epochs it read, but can't
+//! list them anymore.
+//!
+//! Every group message pays the group's address a small amount. Once
+//! the messages are indexed, the owner sweeps these outputs to its own
+//! wallet with `sweep_group`.
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::auth::{authorize, caller_account};
+use crate::config;
+use crate::message::envelope::{recipient_hint, Envelope, EXT_GROUP};
+use crate::message::inbox::{index_inbox, is_indexed, IndexReport};
+use crate::message::mailbox::{list_inbox, ListMessagesRequest, MessagePage};
+use crate::message::registry::read_active_keys;
+use crate::message::send_message::{parse_payload, send_message};
+use crate::utils::{read_public_key, sha256, AddressKind, CoinSelectionStrategy, MessageTransport, MtcError, SendBtcResponse, SendMessageResponse};
+use crate::wallet::address::account_to_p2wpkh_address;
+use crate::wallet::send_btc::{get_fee_per_byte, parse_address, sweep, FeePolicy};
+use crate::wallet::state::get_available_candidates_from_wallet;
+
+pub const MAX_GROUP_MEMBERS: usize = 32;
+pub const GROUP_ID_LEN: usize = 16;
+pub const WRAPPED_KEY_LEN: usize = 32 + 12 + 48;
+pub const GROUP_KEY_INFO: &[u8] = b"mtc-group-key-v1";
+const MAX_GROUP_NAME_LEN: usize = 64;
+const GROUP_SUBACCOUNT_DOMAIN: &[u8] = b"mtc-group";
+/// The most outputs `sweep_group` spends at once, which bounds the
+/// signatures a single call waits for.
+const MAX_SWEEP_INPUTS: usize = 100;
+
+thread_local! {
+    static GROUP_STATE: RefCell<GroupState> = RefCell::new(GroupState::init());
+}
+
+/// The group key of an epoch, wrapped to one member.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct WrappedKey {
+    pub member: Principal,
+    /// The agreement key the group key was wrapped to. It must be the
+    /// member's active key.
+    pub agreement_key: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct GroupEpoch {
+    pub epoch: u32,
+    pub members: Vec<Principal>,
+    pub wrapped_keys: Vec<WrappedKey>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub owner: Principal,
+    /// The P2WPKH address group messages are sent to.
+    pub address: String,
+    /// Every epoch, oldest first. The last one is current.
+    pub epochs: Vec<GroupEpoch>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    /// The members besides the caller, who owns the group.
+    pub members: Vec<Principal>,
+    /// The key of epoch 0, wrapped to the caller and every member.
+    pub wrapped_keys: Vec<WrappedKey>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct GroupMembershipRequest {
+    pub group_id: String,
+    pub member: Principal,
+    /// The key of the next epoch, wrapped to every member left after the
+    /// change.
+    pub wrapped_keys: Vec<WrappedKey>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct SendGroupMessageRequest {
+    pub group_id: String,
+    pub subaccount: Option<Subaccount>,
+    pub on_behalf_of: Option<Principal>,
+    /// The message, encrypted with the key of the current epoch.
+    pub payload: Vec<u8>,
+    pub transport: Option<MessageTransport>,
+    pub coin_selection: Option<CoinSelectionStrategy>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct GroupState {
+    pub groups: HashMap<String, Group>,
+    /// The number of groups ever created, which makes group ids unique.
+    pub created: u64,
+}
+
+impl Group {
+    pub fn current_epoch(&self) -> &GroupEpoch {
+        self.epochs.last().expect("a group always has an epoch")
+    }
+
+    pub fn is_member(&self, principal: &Principal) -> bool {
+        self.current_epoch().members.contains(principal)
+    }
+
+    /// Starts the next epoch with `members`.
+    fn rotate(&mut self, members: Vec<Principal>, wrapped_keys: Vec<WrappedKey>, now: u64) -> Result<(), MtcError> {
+        let epoch = self.current_epoch().epoch + 1;
+        check_wrapped_keys(&members, &wrapped_keys)?;
+        self.epochs.push(GroupEpoch { epoch, members, wrapped_keys, created_at: now });
+        Ok(())
+    }
+}
+
+impl GroupState {
+    pub fn init() -> Self {
+        Self { groups: HashMap::new(), created: 0 }
+    }
+
+    fn group_mut(&mut self, group_id: &str) -> Result<&mut Group, MtcError> {
+        self.groups
+            .get_mut(group_id)
+            .ok_or_else(|| MtcError::InvalidRequest(format!("unknown group {}", group_id)))
+    }
+
+    pub fn add_member(&mut self, caller: Principal, request: GroupMembershipRequest, now: u64) -> Result<Group, MtcError> {
+        let group = self.group_mut(&request.group_id)?;
+        if caller != group.owner {
+            return Err(MtcError::Unauthorized(format!("{} does not own group {}", caller, group.id)));
+        }
+        let mut members = group.current_epoch().members.clone();
+        if members.contains(&request.member) {
+            return Err(MtcError::InvalidRequest(format!("{} is a member already", request.member)));
+        }
+        members.push(request.member);
+        group.rotate(members, request.wrapped_keys, now)?;
+        Ok(group.clone())
+    }
+
+    /// Removes a member. The owner removes anyone but itself, and members
+    /// remove themselves.
+    pub fn remove_member(&mut self, caller: Principal, request: GroupMembershipRequest, now: u64) -> Result<Group, MtcError> {
+        let group = self.group_mut(&request.group_id)?;
+        if caller != group.owner && caller != request.member {
+            return Err(MtcError::Unauthorized(format!("{} cannot remove {} from group {}", caller, request.member, group.id)));
+        }
+        if request.member == group.owner {
+            return Err(MtcError::InvalidRequest("the owner cannot leave the group".to_string()));
+        }
+        let mut members = group.current_epoch().members.clone();
+        if !members.contains(&request.member) {
+            return Err(MtcError::InvalidRequest(format!("{} is not a member", request.member)));
+        }
+        members.retain(|member| *member != request.member);
+        group.rotate(members, request.wrapped_keys, now)?;
+        Ok(group.clone())
+    }
+}
+
+/// Checks that `wrapped_keys` holds exactly one key per member, wrapped to
+/// the member's active agreement key.
+fn check_wrapped_keys(members: &[Principal], wrapped_keys: &[WrappedKey]) -> Result<(), MtcError> {
+    if members.len() > MAX_GROUP_MEMBERS {
+        return Err(MtcError::InvalidRequest(format!("a group has at most {} members", MAX_GROUP_MEMBERS)));
+    }
+    if wrapped_keys.len() != members.len() {
+        return Err(MtcError::InvalidRequest(format!(
+            "expected {} wrapped keys, got {}",
+            members.len(),
+            wrapped_keys.len()
+        )));
+    }
+    for member in members {
+        let keys = read_active_keys(member)
+            .ok_or_else(|| MtcError::InvalidRequest(format!("{} has no messaging keys", member)))?;
+        let mut wrapped = wrapped_keys.iter().filter(|wrapped| wrapped.member == *member);
+        let (Some(wrapped), None) = (wrapped.next(), wrapped.next()) else {
+            return Err(MtcError::InvalidRequest(format!("expected one wrapped key for {}", member)));
+        };
+        if wrapped.agreement_key != keys.agreement_key {
+            return Err(MtcError::InvalidRequest(format!(
+                "the key of {} is not wrapped to its active agreement key",
+                member
+            )));
+        }
+        if wrapped.wrapped_key.len() != WRAPPED_KEY_LEN {
+            return Err(MtcError::InvalidRequest(format!(
+                "expected a {} bytes long wrapped key, got {} bytes",
+                WRAPPED_KEY_LEN,
+                wrapped.wrapped_key.len()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the account whose address receives the messages of the group.
+pub fn group_account(group_id: &str) -> Account {
+    let mut data = GROUP_SUBACCOUNT_DOMAIN.to_vec();
+    data.extend_from_slice(group_id.as_bytes());
+    let subaccount: Subaccount = sha256(&data).try_into().expect("SHA-256 is 32 bytes long");
+    Account { owner: ic_cdk::id(), subaccount: Some(subaccount) }
+}
+
+/// Returns the value of the `EXT_GROUP` extension of a group message.
+pub fn group_extension(group_id: &str, epoch: u32) -> Result<Vec<u8>, MtcError> {
+    let mut value = hex::decode(group_id)
+        .ok()
+        .filter(|id| id.len() == GROUP_ID_LEN)
+        .ok_or_else(|| MtcError::InvalidRequest(format!("invalid group id {}", group_id)))?;
+    value.extend_from_slice(&epoch.to_be_bytes());
+    Ok(value)
+}
+
+/// Checks that every envelope of a group message is for the current
+/// epoch of `group`. Fragments carry the extension in the message they
+/// reassemble to, so only whole messages are checked.
+pub fn check_group_message(group: &Group, messages: &[(String, Envelope)]) -> Result<(), MtcError> {
+    let expected = group_extension(&group.id, group.current_epoch().epoch)?;
+    for (_, message) in messages {
+        if message.extension(EXT_GROUP) != Some(expected.as_slice()) {
+            return Err(MtcError::InvalidEnvelope(format!(
+                "group messages must carry the group extension of epoch {}",
+                group.current_epoch().epoch
+            )));
+        }
+        if !message.is_encrypted() {
+            return Err(MtcError::InvalidEnvelope("group messages must be encrypted".to_string()));
+        }
+    }
+    Ok(())
+}
+
+pub fn take_group_state() -> GroupState {
+    GROUP_STATE.with(|state| state.replace(GroupState::init()))
+}
+
+pub fn restore_group_state(state: GroupState) {
+    GROUP_STATE.with(|group_state| *group_state.borrow_mut() = state);
+}
+
+/// Whether `address` receives the messages of a group.
+pub fn is_group_address(address: &str) -> bool {
+    GROUP_STATE.with(|state| state.borrow().groups.values().any(|group| group.address == address))
+}
+
+/// Returns the group `group_id` if `member` belongs to it.
+pub fn read_group(group_id: &str, member: &Principal) -> Result<Group, MtcError> {
+    GROUP_STATE.with(|state| {
+        let state = state.borrow();
+        let group = state
+            .groups
+            .get(group_id)
+            .ok_or_else(|| MtcError::InvalidRequest(format!("unknown group {}", group_id)))?;
+        if !group.is_member(member) {
+            return Err(MtcError::Unauthorized(format!("{} is not a member of group {}", member, group_id)));
+        }
+        Ok(group.clone())
+    })
+}
+
+/// Returns the groups the caller is a member of.
+pub fn read_groups() -> Vec<Group> {
+    let caller = ic_cdk::caller();
+    GROUP_STATE.with(|state| {
+        state
+            .borrow()
+            .groups
+            .values()
+            .filter(|group| group.is_member(&caller))
+            .cloned()
+            .collect()
+    })
+}
+
+pub async fn create_group(request: CreateGroupRequest) -> Result<Group, MtcError> {
+    let owner = caller_account(None)?.owner;
+    if request.name.len() > MAX_GROUP_NAME_LEN {
+        return Err(MtcError::InvalidRequest(format!(
+            "group names are at most {} bytes long",
+            MAX_GROUP_NAME_LEN
+        )));
+    }
+    let mut members = vec![owner];
+    for member in request.members {
+        if member == Principal::anonymous() || members.contains(&member) {
+            return Err(MtcError::InvalidRequest(format!("cannot add {} to the group", member)));
+        }
+        members.push(member);
+    }
+    check_wrapped_keys(&members, &request.wrapped_keys)?;
+
+    let created = GROUP_STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.created += 1;
+        state.created
+    });
+    let mut seed = owner.as_slice().to_vec();
+    seed.extend_from_slice(&created.to_be_bytes());
+    let id = hex::encode(&sha256(&seed)[..GROUP_ID_LEN]);
+    let pub_key = read_public_key().await?;
+    let address = account_to_p2wpkh_address(config::network(), &pub_key, &group_account(&id)).await?;
+
+    let now = ic_cdk::api::time();
+    let group = Group {
+        id: id.clone(),
+        name: request.name,
+        owner,
+        address,
+        epochs: vec![GroupEpoch { epoch: 0, members, wrapped_keys: request.wrapped_keys, created_at: now }],
+        created_at: now,
+    };
+    GROUP_STATE.with(|state| state.borrow_mut().groups.insert(id, group.clone()));
+    Ok(group)
+}
+
+pub fn add_member(request: GroupMembershipRequest) -> Result<Group, MtcError> {
+    let caller = caller_account(None)?.owner;
+    let now = ic_cdk::api::time();
+    GROUP_STATE.with(|state| state.borrow_mut().add_member(caller, request, now))
+}
+
+pub fn remove_member(request: GroupMembershipRequest) -> Result<Group, MtcError> {
+    let caller = caller_account(None)?.owner;
+    let now = ic_cdk::api::time();
+    GROUP_STATE.with(|state| state.borrow_mut().remove_member(caller, request, now))
+}
+
+/// Sends a message to every current member of a group the sending account
+/// belongs to.
+pub async fn send_group_message(request: SendGroupMessageRequest) -> Result<SendMessageResponse, MtcError> {
+    let account = authorize(request.on_behalf_of, request.subaccount, "send_group_message")?;
+    let group = read_group(&request.group_id, &account.owner)?;
+    let network = config::network();
+    let transport = request.transport.unwrap_or_default();
+    let hint = recipient_hint(parse_address(&group.address, network)?.script_pubkey().as_bytes());
+    check_group_message(&group, &parse_payload(&request.payload, transport, hint)?)?;
+    let strategy = request.coin_selection.unwrap_or_default();
+    send_message(network, group.address, request.payload, None, transport, strategy, &account).await
+}
+
+/// Indexes the messages sent to a group the caller belongs to.
+pub async fn index_group_inbox(group_id: String) -> Result<IndexReport, MtcError> {
+    read_group(&group_id, &ic_cdk::caller())?;
+    index_inbox(config::network(), &group_account(&group_id), AddressKind::P2wpkh).await
+}
+
+/// Indexes the messages sent to a group the caller owns, then sends the
+/// outputs that came with the indexed ones to the caller's wallet. Outputs
+/// of transactions that couldn't be fetched yet are kept, so that their
+/// messages can still be indexed.
+pub async fn sweep_group(group_id: String) -> Result<SendBtcResponse, MtcError> {
+    let owner = caller_account(None)?.owner;
+    let group = read_group(&group_id, &owner)?;
+    if group.owner != owner {
+        return Err(MtcError::Unauthorized(format!("{} does not own group {}", owner, group.id)));
+    }
+    let network = config::network();
+    let account = group_account(&group_id);
+    index_inbox(network, &account, AddressKind::P2wpkh).await?;
+    let mut inputs = vec![];
+    for candidate in get_available_candidates_from_wallet(&account, AddressKind::P2wpkh) {
+        if is_indexed(&account, &candidate.outpoint.to_outpoint()?.txid) {
+            inputs.push(candidate);
+        }
+    }
+    inputs.truncate(MAX_SWEEP_INPUTS);
+    let pub_key = read_public_key().await?;
+    let destination = account_to_p2wpkh_address(network, &pub_key, &Account::from(owner)).await?;
+    let fee_per_byte = get_fee_per_byte(network, &FeePolicy::default()).await?;
+    let key_name = config::ecdsa_key_name();
+    sweep(network, key_name, inputs, &parse_address(&destination, network)?, fee_per_byte, &account).await
+}
+
+/// Returns a page of the messages of a group the caller belongs to. Read
+/// state is not tracked for group messages.
+pub fn list_group_messages(group_id: String, mut request: ListMessagesRequest) -> Result<MessagePage, MtcError> {
+    read_group(&group_id, &ic_cdk::caller())?;
+    let account = group_account(&group_id);
+    request.subaccount = account.subaccount;
+    request.read = None;
+    list_inbox(&account.owner, &request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::registry::{restore_key_registry, KeyRegistryState, KeyStatus, RegisteredKeys};
+
+    const GROUP_ID: &str = "000102030405060708090a0b0c0d0e0f";
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    fn registered(agreement_key: Vec<u8>, status: KeyStatus) -> RegisteredKeys {
+        RegisteredKeys {
+            signing_key: vec![0; 32],
+            agreement_key,
+            timestamp: NOW,
+            signature: vec![0; 64],
+            registered_at: NOW,
+            status,
+        }
+    }
+
+    /// Registers the agreement key `[n; 32]` for principals 1 to 9, after
+    /// a rotated key `[n + 100; 32]`.
+    fn seed_registry() {
+        let mut registry = KeyRegistryState::init();
+        for n in 1..10 {
+            let history = vec![
+                registered(vec![n + 100; 32], KeyStatus::Rotated { at: NOW }),
+                registered(vec![n; 32], KeyStatus::Active),
+            ];
+            registry.keys.insert(principal(n), history);
+        }
+        restore_key_registry(registry);
+    }
+
+    fn wrapped(n: u8) -> WrappedKey {
+        WrappedKey { member: principal(n), agreement_key: vec![n; 32], wrapped_key: vec![n; WRAPPED_KEY_LEN] }
+    }
+
+    /// Returns a state with a group owned by principal 1, with principal 2
+    /// as its other member.
+    fn state() -> GroupState {
+        seed_registry();
+        let mut state = GroupState::init();
+        let group = Group {
+            id: GROUP_ID.to_string(),
+            name: "group".to_string(),
+            owner: principal(1),
+            address: "bc1qgroup".to_string(),
+            epochs: vec![GroupEpoch {
+                epoch: 0,
+                members: vec![principal(1), principal(2)],
+                wrapped_keys: vec![wrapped(1), wrapped(2)],
+                created_at: NOW,
+            }],
+            created_at: NOW,
+        };
+        state.groups.insert(GROUP_ID.to_string(), group);
+        state
+    }
+
+    fn request(member: u8, wrapped_keys: &[u8]) -> GroupMembershipRequest {
+        GroupMembershipRequest {
+            group_id: GROUP_ID.to_string(),
+            member: principal(member),
+            wrapped_keys: wrapped_keys.iter().map(|n| wrapped(*n)).collect(),
+        }
+    }
+
+    fn epochs(state: &GroupState) -> usize {
+        state.groups[GROUP_ID].epochs.len()
+    }
+
+    #[test]
+    fn every_membership_change_starts_an_epoch() {
+        let mut state = state();
+        let group = state.add_member(principal(1), request(3, &[1, 2, 3]), NOW + 1).unwrap();
+        let current = group.current_epoch();
+        assert_eq!(current.epoch, 1);
+        assert_eq!(current.members, vec![principal(1), principal(2), principal(3)]);
+        assert_eq!(current.created_at, NOW + 1);
+        assert_eq!(group.epochs[0].members, vec![principal(1), principal(2)]);
+
+        let group = state.remove_member(principal(1), request(2, &[1, 3]), NOW + 2).unwrap();
+        assert_eq!(group.current_epoch().epoch, 2);
+        assert_eq!(group.current_epoch().members, vec![principal(1), principal(3)]);
+        assert!(!group.is_member(&principal(2)));
+
+        let group = state.add_member(principal(1), request(2, &[1, 2, 3]), NOW + 3).unwrap();
+        assert_eq!(group.current_epoch().epoch, 3);
+        assert_eq!(epochs(&state), 4);
+    }
+
+    #[test]
+    fn only_the_owner_adds_members() {
+        let mut state = state();
+        let result = state.add_member(principal(2), request(3, &[1, 2, 3]), NOW);
+        assert!(matches!(result, Err(MtcError::Unauthorized(_))));
+        let result = state.add_member(principal(1), request(2, &[1, 2]), NOW);
+        assert!(matches!(result, Err(MtcError::InvalidRequest(_))));
+        let mut unknown = request(3, &[1, 2, 3]);
+        unknown.group_id = "ff".repeat(GROUP_ID_LEN);
+        assert!(matches!(state.add_member(principal(1), unknown, NOW), Err(MtcError::InvalidRequest(_))));
+        assert_eq!(epochs(&state), 1);
+    }
+
+    #[test]
+    fn members_remove_themselves_and_the_owner_stays() {
+        let mut state = state();
+        state.add_member(principal(1), request(3, &[1, 2, 3]), NOW).unwrap();
+        // Neither the owner nor the member removed.
+        let result = state.remove_member(principal(3), request(2, &[1, 3]), NOW);
+        assert!(matches!(result, Err(MtcError::Unauthorized(_))));
+        let result = state.remove_member(principal(2), request(1, &[2, 3]), NOW);
+        assert!(matches!(result, Err(MtcError::Unauthorized(_))));
+        let result = state.remove_member(principal(1), request(1, &[2, 3]), NOW);
+        assert!(matches!(result, Err(MtcError::InvalidRequest(_))));
+        let result = state.remove_member(principal(1), request(4, &[1, 2, 3]), NOW);
+        assert!(matches!(result, Err(MtcError::InvalidRequest(_))));
+        assert_eq!(epochs(&state), 2);
+
+        let group = state.remove_member(principal(2), request(2, &[1, 3]), NOW).unwrap();
+        assert_eq!(group.current_epoch().members, vec![principal(1), principal(3)]);
+        assert_eq!(group.current_epoch().epoch, 2);
+    }
+
+    #[test]
+    fn every_member_gets_exactly_one_valid_wrapped_key() {
+        let mut state = state();
+        let rejected = |state: &mut GroupState, request: GroupMembershipRequest| {
+            matches!(state.add_member(principal(1), request, NOW), Err(MtcError::InvalidRequest(_)))
+        };
+        // A member left out.
+        assert!(rejected(&mut state, request(3, &[1, 2])));
+        // A key for someone who is not a member.
+        assert!(rejected(&mut state, request(3, &[1, 2, 4])));
+        // Two keys for a member, none for another.
+        assert!(rejected(&mut state, request(3, &[1, 2, 2])));
+        // A wrapped key of the wrong length.
+        let mut truncated = request(3, &[1, 2, 3]);
+        truncated.wrapped_keys[2].wrapped_key.pop();
+        assert!(rejected(&mut state, truncated));
+        // A member without messaging keys.
+        let mut unregistered = request(10, &[1, 2]);
+        unregistered.wrapped_keys.push(WrappedKey {
+            member: principal(10),
+            agreement_key: vec![10; 32],
+            wrapped_key: vec![10; WRAPPED_KEY_LEN],
+        });
+        assert!(rejected(&mut state, unregistered));
+        assert_eq!(epochs(&state), 1);
+        state.add_member(principal(1), request(3, &[1, 2, 3]), NOW).unwrap();
+    }
+
+    #[test]
+    fn keys_wrapped_to_a_stale_agreement_key_are_rejected() {
+        let mut state = state();
+        let mut stale = request(3, &[1, 2, 3]);
+        stale.wrapped_keys[1].agreement_key = vec![102; 32];
+        let result = state.add_member(principal(1), stale, NOW);
+        assert!(matches!(result, Err(MtcError::InvalidRequest(_))));
+        assert_eq!(epochs(&state), 1);
+    }
+
+    #[test]
+    fn groups_have_at_most_max_group_members() {
+        let members: Vec<Principal> = (0..=MAX_GROUP_MEMBERS as u8).map(principal).collect();
+        let wrapped_keys: Vec<WrappedKey> = (0..=MAX_GROUP_MEMBERS as u8).map(wrapped).collect();
+        assert!(matches!(check_wrapped_keys(&members, &wrapped_keys), Err(MtcError::InvalidRequest(_))));
+    }
+}
@@ -0,0 +1,515 @@
+//! Finds the messages sent to an account and keeps them in its owner's
+//! inbox.
+//!
+//! Every message pays the recipient's address, so its transaction shows
+//! up among the outputs `bitcoin_get_utxos` reports for that address. The
+//! indexer refreshes the account's outputs, fetches every transaction it
+//! hasn't parsed yet from a `TransactionSource`, and extracts the
+//! envelopes addressed to the account from OP_RETURN outputs and from
+//! taproot reveal scripts. Fragments are kept aside until they reassemble
+//! to their message. Receipts for the account's own messages are applied
+//! to its outbox (see `receipt`).
+//!
+//! Only unspent outputs are reported, so a message must be indexed before
+//! the recipient spends the output that came with it. Inboxes are kept in
+//! stable memory.
+use bitcoin::{blockdata::script::Instruction, hashes::Hash, Address, CompressedPublicKey, Script, Transaction, Txid};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_stable_structures::StableBTreeMap;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::config::{self, to_bitcoin_network};
+use crate::message::envelope::{recipient_hint, Envelope};
+use crate::message::fragment::{self, FragmentInfo, Reassembler};
+use crate::message::inscription::parse_reveal_script;
+use crate::message::outbox::confirm_sent;
+use crate::message::receipt::{apply_receipt, Receipt};
+use crate::message::source::{check_transaction, configured_source};
+use crate::storage::{self, Memory, StableLists, TxidKey, INBOX_MEMORY, INDEXED_MEMORY};
+use crate::utils::{read_public_key, AddressKind, MessageTransport, MtcError};
+use crate::wallet::address::{account_to_p2pkh_address, account_to_p2wpkh_address};
+use crate::wallet::send_btc::parse_address;
+use crate::wallet::state::{get_all_utxo_from_wallet, update_utxo};
+
+thread_local! {
+    static INBOX_STATE: RefCell<InboxState> = RefCell::new(InboxState::init());
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopeExtension {
+    pub ext_type: u8,
+    pub value: Vec<u8>,
+}
+
+/// The decoded envelope of a message. The body is still encrypted.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageEnvelope {
+    pub flags: u8,
+    pub recipient_hint: Vec<u8>,
+    pub suite: u8,
+    pub nonce: Vec<u8>,
+    pub extensions: Vec<EnvelopeExtension>,
+    pub body: Vec<u8>,
+}
+
+impl MessageEnvelope {
+    /// Returns the value of the extension of type `ext_type`, if present.
+    pub fn extension(&self, ext_type: u8) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.ext_type == ext_type)
+            .map(|extension| extension.value.as_slice())
+    }
+}
+
+impl From<&Envelope> for MessageEnvelope {
+    fn from(envelope: &Envelope) -> Self {
+        Self {
+            flags: envelope.flags,
+            recipient_hint: envelope.recipient_hint.to_vec(),
+            suite: envelope.suite,
+            nonce: envelope.nonce.clone(),
+            extensions: envelope
+                .extensions
+                .iter()
+                .map(|extension| EnvelopeExtension { ext_type: extension.ext_type, value: extension.value.clone() })
+                .collect(),
+            body: envelope.body.clone(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct InboxEntry {
+    /// The first 16 bytes of the SHA-256 of the encoded envelope, hex
+    /// encoded.
+    pub id: String,
+    pub subaccount: Option<Subaccount>,
+    pub address_type: AddressKind,
+    /// The transaction that carries the message, or its first fragment.
+    pub txid: String,
+    /// Every transaction the message was read from, in fragment order.
+    pub txids: Vec<String>,
+    pub height: u32,
+    /// The P2WPKH address that paid for the message, if it can be told
+    /// from the transaction.
+    pub sender: Option<String>,
+    pub transport: MessageTransport,
+    pub envelope: MessageEnvelope,
+    pub indexed_at: u64,
+    pub read: bool,
+}
+
+/// The fragments of a message seen so far.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct PendingMessage {
+    pub subaccount: Option<Subaccount>,
+    pub address_type: AddressKind,
+    /// The encoded fragment envelopes, keyed by fragment index.
+    pub fragments: BTreeMap<u16, Vec<u8>>,
+    /// The transaction of each fragment, keyed by fragment index.
+    pub txids: BTreeMap<u16, String>,
+    pub height: u32,
+    pub sender: Option<String>,
+    pub transport: MessageTransport,
+}
+
+/// The part of the inbox state kept on the heap, and in upgrade
+/// snapshots.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PendingMessages {
+    pub pending: HashMap<(Principal, String), PendingMessage>,
+    pub awaiting: HashSet<String>,
+}
+
+pub struct InboxState {
+    /// The messages each principal received.
+    pub inboxes: StableLists<InboxEntry>,
+    /// The transactions already parsed for each account.
+    pub indexed: StableBTreeMap<TxidKey, (), Memory>,
+    /// Fragmented messages, keyed by recipient and message id.
+    pub pending: HashMap<(Principal, String), PendingMessage>,
+    /// The transactions the indexer couldn't fetch yet.
+    pub awaiting: HashSet<String>,
+}
+
+/// Returns the key of `txid` in `InboxState::indexed`.
+pub fn indexed_key(account: &Account, txid: &Txid) -> TxidKey {
+    TxidKey::new(account, txid.to_byte_array())
+}
+
+impl InboxState {
+    pub fn init() -> Self {
+        Self {
+            inboxes: StableLists::init(INBOX_MEMORY),
+            indexed: StableBTreeMap::init(storage::memory(INDEXED_MEMORY)),
+            pending: HashMap::new(),
+            awaiting: HashSet::new(),
+        }
+    }
+
+    /// Marks the messages `ids` of `owner` as read or unread. Returns how
+    /// many changed.
+    pub fn mark_read(&mut self, owner: &Principal, ids: &[String], read: bool) -> u32 {
+        let owner = Account::from(*owner);
+        let matching: Vec<(u64, InboxEntry)> = self
+            .inboxes
+            .iter(&owner)
+            .filter(|(_, entry)| entry.read != read && ids.contains(&entry.id))
+            .collect();
+        let mut changed = 0;
+        for (position, mut entry) in matching {
+            entry.read = read;
+            self.inboxes.set(&owner, position, entry);
+            changed += 1;
+        }
+        changed
+    }
+
+    /// Returns the receipts among the last `count` messages of `owner`,
+    /// with the txid that carried them.
+    fn latest_receipts(&self, owner: &Principal, count: u32) -> Vec<(String, Receipt)> {
+        let mut receipts: Vec<(String, Receipt)> = self
+            .inboxes
+            .iter(&Account::from(*owner))
+            .take(count as usize)
+            .filter_map(|(_, entry)| Receipt::of(&entry.envelope).map(|receipt| (entry.txid, receipt)))
+            .collect();
+        receipts.reverse();
+        receipts
+    }
+
+    fn is_indexed(&self, account: &Account, txid: &Txid) -> bool {
+        self.indexed.contains_key(&indexed_key(account, txid))
+    }
+
+    /// Stores the messages of `transaction`, unless it was parsed already.
+    /// Returns how many messages were added to the inbox.
+    fn add_transaction(&mut self, mut found: FoundTransaction, now: u64) -> u32 {
+        let owner = found.account.owner;
+        let txid = found.txid.to_string();
+        if self.indexed.insert(indexed_key(&found.account, &found.txid), ()).is_some() {
+            return 0;
+        }
+        self.awaiting.remove(&txid);
+        let mut added = 0;
+        for (envelope, transport) in std::mem::take(&mut found.envelopes) {
+            let entry = match FragmentInfo::of(&envelope) {
+                Ok(None) => Some(InboxEntry {
+                    id: message_id(&envelope),
+                    subaccount: found.account.subaccount,
+                    address_type: found.address_type,
+                    txid: txid.clone(),
+                    txids: vec![txid.clone()],
+                    height: found.height,
+                    sender: found.sender.clone(),
+                    transport,
+                    envelope: MessageEnvelope::from(&envelope),
+                    indexed_at: now,
+                    read: false,
+                }),
+                Ok(Some(info)) => self.add_fragment(&found, &txid, info, &envelope, transport, now),
+                Err(_) => None,
+            };
+            if let Some(entry) = entry {
+                self.inboxes.push(&Account::from(owner), entry);
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Keeps a fragment and returns its message once it is complete. The
+    /// fragments stay pending until they reassemble to a message whose
+    /// content hash matches, so that a fragment resent in another
+    /// transaction can still complete it.
+    fn add_fragment(
+        &mut self,
+        found: &FoundTransaction,
+        txid: &str,
+        info: FragmentInfo,
+        envelope: &Envelope,
+        transport: MessageTransport,
+        now: u64,
+    ) -> Option<InboxEntry> {
+        let key = (found.account.owner, hex::encode(info.message_id));
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| PendingMessage {
+            subaccount: found.account.subaccount,
+            address_type: found.address_type,
+            fragments: BTreeMap::new(),
+            txids: BTreeMap::new(),
+            height: found.height,
+            sender: found.sender.clone(),
+            transport,
+        });
+        pending.fragments.insert(info.index, envelope.encode().ok()?);
+        pending.txids.insert(info.index, txid.to_string());
+        pending.height = pending.height.max(found.height);
+        if info.index == 0 {
+            pending.sender = found.sender.clone();
+        }
+
+        let mut reassembler = Reassembler::new();
+        for fragment in pending.fragments.values() {
+            // A fragment that disagrees with the first one is not part of
+            // this message.
+            let _ = reassembler.add(&Envelope::decode(fragment).ok()?);
+        }
+        if !reassembler.is_complete() {
+            return None;
+        }
+        let message = reassembler.finish().ok()?;
+        let pending = self.pending.remove(&key)?;
+        let txids: Vec<String> = pending.txids.into_values().collect();
+        Some(InboxEntry {
+            id: key.1,
+            subaccount: pending.subaccount,
+            address_type: pending.address_type,
+            txid: txids[0].clone(),
+            txids,
+            height: pending.height,
+            sender: pending.sender,
+            transport: pending.transport,
+            envelope: MessageEnvelope::from(&message),
+            indexed_at: now,
+            read: false,
+        })
+    }
+}
+
+/// The envelopes of a transaction addressed to an account.
+struct FoundTransaction {
+    account: Account,
+    address_type: AddressKind,
+    txid: Txid,
+    height: u32,
+    sender: Option<String>,
+    envelopes: Vec<(Envelope, MessageTransport)>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct IndexReport {
+    /// The number of messages added to the inbox.
+    pub new_messages: u32,
+    /// The transactions that couldn't be fetched. With the relayed source,
+    /// push them with `submit_raw_transactions` and index again.
+    pub awaiting: Vec<String>,
+}
+
+/// Returns the id of a message that is not fragmented.
+pub fn message_id(envelope: &Envelope) -> String {
+    let encoded = envelope.encode().expect("decoded envelopes are valid");
+    hex::encode(fragment::message_id(&encoded))
+}
+
+/// Returns the data pushed after the OP_RETURN of `script`.
+pub fn op_return_data(script: &Script) -> Option<Vec<u8>> {
+    if !script.is_op_return() {
+        return None;
+    }
+    let mut data = vec![];
+    for instruction in script.instructions().skip(1) {
+        match instruction.ok()? {
+            Instruction::PushBytes(bytes) => data.extend_from_slice(bytes.as_bytes()),
+            Instruction::Op(_) => return None,
+        }
+    }
+    Some(data)
+}
+
+/// Returns the envelopes of `transaction` whose recipient hint is `hint`.
+pub fn parse_envelopes(transaction: &Transaction, hint: [u8; 4]) -> Vec<(Envelope, MessageTransport)> {
+    let mut envelopes = vec![];
+    for output in &transaction.output {
+        if let Some(envelope) = op_return_data(&output.script_pubkey).and_then(|data| Envelope::decode(&data).ok()) {
+            envelopes.push((envelope, MessageTransport::OpReturn));
+        }
+    }
+    for input in &transaction.input {
+        let Some(payload) = input.witness.tapscript().and_then(parse_reveal_script) else {
+            continue;
+        };
+        if let Ok(decoded) = Envelope::decode_all(&payload) {
+            envelopes.extend(decoded.into_iter().map(|envelope| (envelope, MessageTransport::Taproot)));
+        }
+    }
+    envelopes.retain(|(envelope, _)| envelope.recipient_hint == hint);
+    envelopes
+}
+
+/// Returns the P2WPKH address of the first input, if it spends one.
+pub fn sender_address(transaction: &Transaction, network: BitcoinNetwork) -> Option<String> {
+    let witness = &transaction.input.first()?.witness;
+    if witness.len() != 2 {
+        return None;
+    }
+    let public_key = CompressedPublicKey::from_slice(witness.nth(1)?).ok()?;
+    Some(Address::p2wpkh(&public_key, to_bitcoin_network(network)).to_string())
+}
+
+/// Marks the messages `ids` in the caller's inbox as read or unread.
+pub fn mark_read(ids: Vec<String>, read: bool) -> u32 {
+    let owner = ic_cdk::caller();
+    mutate_inbox_state(|state| state.mark_read(&owner, &ids, read))
+}
+
+/// Moves the fragments and the awaited transactions out for an upgrade
+/// snapshot. The inboxes stay in stable memory.
+pub fn take_pending_messages() -> PendingMessages {
+    mutate_inbox_state(|state| PendingMessages {
+        pending: std::mem::take(&mut state.pending),
+        awaiting: std::mem::take(&mut state.awaiting),
+    })
+}
+
+pub fn restore_pending_messages(pending: PendingMessages) {
+    mutate_inbox_state(|state| {
+        state.pending = pending.pending;
+        state.awaiting = pending.awaiting;
+    })
+}
+
+pub fn read_inbox_state<R>(f: impl FnOnce(&InboxState) -> R) -> R {
+    INBOX_STATE.with(|state| f(&state.borrow()))
+}
+
+pub fn mutate_inbox_state<R>(f: impl FnOnce(&mut InboxState) -> R) -> R {
+    INBOX_STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Whether the messages `txid` carries to `account` were indexed.
+pub fn is_indexed(account: &Account, txid: &Txid) -> bool {
+    read_inbox_state(|state| state.is_indexed(account, txid))
+}
+
+/// Whether the indexer waits for the raw bytes of `txid`.
+pub fn is_awaited(txid: &Txid) -> bool {
+    read_inbox_state(|state| state.awaiting.contains(&txid.to_string()))
+}
+
+/// Indexes the messages received by `account` on its address of kind
+/// `kind`. Refreshing the P2WPKH outputs also confirms the messages the
+/// account sent.
+pub async fn index_inbox(network: BitcoinNetwork, account: &Account, kind: AddressKind) -> Result<IndexReport, MtcError> {
+    update_utxo(network, account, kind).await?;
+    if kind == AddressKind::P2wpkh {
+        confirm_sent(network, account).await?;
+    }
+    let ecdsa_key = read_public_key().await?;
+    let address = match kind {
+        AddressKind::P2wpkh => account_to_p2wpkh_address(network, &ecdsa_key, account).await?,
+        AddressKind::P2pkh => account_to_p2pkh_address(network, &ecdsa_key, account).await?,
+    };
+    let hint = recipient_hint(parse_address(&address, network)?.script_pubkey().as_bytes());
+
+    // Every transaction paying the account, with the height it was mined
+    // at.
+    let mut candidates: BTreeMap<Txid, u32> = BTreeMap::new();
+    for (outpoint, utxo) in get_all_utxo_from_wallet(account, kind) {
+        candidates.insert(outpoint.to_outpoint()?.txid, utxo.height);
+    }
+    candidates.retain(|txid, _| !read_inbox_state(|state| state.is_indexed(account, txid)));
+
+    let source = configured_source(config::read_config(|config| config.esplora_url.clone()));
+    let mut report = IndexReport { new_messages: 0, awaiting: vec![] };
+    for (txid, height) in candidates {
+        let transaction = source
+            .fetch(txid)
+            .await?
+            .and_then(|bytes| check_transaction(txid, &bytes));
+        let Some(transaction) = transaction else {
+            mutate_inbox_state(|state| state.awaiting.insert(txid.to_string()));
+            report.awaiting.push(txid.to_string());
+            continue;
+        };
+        let found = FoundTransaction {
+            account: *account,
+            address_type: kind,
+            txid,
+            height,
+            sender: sender_address(&transaction, network),
+            envelopes: parse_envelopes(&transaction, hint),
+        };
+        let now = ic_cdk::api::time();
+        let added = mutate_inbox_state(|state| state.add_transaction(found, now));
+        report.new_messages += added;
+        for (txid, receipt) in read_inbox_state(|state| state.latest_receipts(&account.owner, added)) {
+            // Receipts that don't verify stay in the inbox as plain messages.
+            let _ = apply_receipt(&account.owner, &receipt, Some(txid));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::cipher::SUITE_AES256_GCM;
+    use crate::message::envelope::{FLAG_ENCRYPTED, FLAG_FRAGMENTED};
+    use crate::message::fragment::fragment;
+
+    const HINT: [u8; 4] = [1, 2, 3, 4];
+
+    fn found(account: Account, id: u8, envelopes: Vec<Envelope>) -> FoundTransaction {
+        FoundTransaction {
+            account,
+            address_type: AddressKind::P2wpkh,
+            txid: Txid::from_byte_array([id; 32]),
+            height: 100 + id as u32,
+            sender: None,
+            envelopes: envelopes.into_iter().map(|envelope| (envelope, MessageTransport::OpReturn)).collect(),
+        }
+    }
+
+    fn message() -> Envelope {
+        Envelope {
+            flags: FLAG_ENCRYPTED,
+            recipient_hint: HINT,
+            suite: SUITE_AES256_GCM,
+            nonce: vec![7; 12],
+            extensions: vec![],
+            body: vec![9; 150],
+        }
+    }
+
+    #[test]
+    fn fragments_stay_pending_until_they_reassemble() {
+        let account = Account::from(Principal::from_slice(&[1; 29]));
+        let fragments = fragment(&message().encode().unwrap(), HINT, 80).unwrap();
+        assert_eq!(fragments.len(), 4);
+        let mut tampered = fragments[1].clone();
+        tampered.body[0] ^= 1;
+        assert_eq!(tampered.flags, FLAG_FRAGMENTED);
+
+        let mut state = InboxState::init();
+        assert_eq!(state.add_transaction(found(account, 1, vec![fragments[0].clone(), tampered]), 0), 0);
+        assert_eq!(state.add_transaction(found(account, 3, fragments[2..].to_vec()), 0), 0);
+        // Every fragment was seen, but the content hash doesn't match.
+        assert_eq!(state.pending.len(), 1);
+
+        assert_eq!(state.add_transaction(found(account, 2, vec![fragments[1].clone()]), 0), 1);
+        assert!(state.pending.is_empty());
+        let (_, entry) = state.inboxes.iter(&account).next().unwrap();
+        assert_eq!(entry.envelope, MessageEnvelope::from(&message()));
+        assert_eq!(entry.height, 103);
+    }
+
+    #[test]
+    fn transactions_are_indexed_per_account() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let default = Account::from(owner);
+        let subaccount = Account { owner, subaccount: Some([1; 32]) };
+        let mut state = InboxState::init();
+        assert_eq!(state.add_transaction(found(default, 1, vec![message()]), 0), 1);
+        assert!(state.is_indexed(&default, &Txid::from_byte_array([1; 32])));
+        assert!(!state.is_indexed(&subaccount, &Txid::from_byte_array([1; 32])));
+        // The same transaction can pay another account of the owner.
+        assert_eq!(state.add_transaction(found(subaccount, 1, vec![message()]), 0), 1);
+        assert_eq!(state.add_transaction(found(subaccount, 1, vec![message()]), 0), 0);
+        assert_eq!(state.inboxes.len(&default), 2);
+    }
+}
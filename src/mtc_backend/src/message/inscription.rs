@@ -0,0 +1,341 @@
+//! Writes messages into taproot script-path witnesses.
+//!
+//! Large messages are cheaper in a witness than in OP_RETURN outputs, and
+//! a witness can hold hundreds of kilobytes. Two transactions are sent:
+//!
+//! * The commit transaction pays, from the sender's P2WPKH wallet, a P2TR
+//!   output whose only spending path is the reveal script below. Its
+//!   internal key is the BIP-341 NUMS point, so it has no key path.
+//! * The reveal transaction spends that output through the script path and
+//!   pays the recipient. The payload is in its witness:
+//!
+//! ```text
+//! <sender x-only key> OP_CHECKSIG
+//! OP_FALSE OP_IF
+//!   "mtc"
+//!   <payload, in pushes of at most 520 bytes>
+//! OP_ENDIF
+//! ```
+//!
+//! The payload is one or more MTC envelopes back to back. A single
+//! envelope body is at most 64 KiB, so larger messages are sent as the
+//! fragments of `fragment`, all in the same reveal transaction.
+//!
+//! The sender key comes from the Schnorr canister, at the derivation path
+//! of the sending account, and signs the reveal transaction.
+use bitcoin::{
+    absolute::LockTime,
+    blockdata::{
+        opcodes,
+        script::{Builder, Instruction},
+        witness::Witness,
+    },
+    hashes::Hash,
+    key::{Secp256k1, XOnlyPublicKey},
+    script::{PushBytes, PushBytesBuf},
+    sighash::{Prevouts, SighashCache, TapSighashType},
+    taproot::{LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo},
+    transaction::Version,
+    Amount, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+};
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, MillisatoshiPerByte};
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::utils::{derivation_path, schnorr_public_key, sign_with_schnorr, CoinSelectionStrategy, MtcError};
+use crate::wallet::send_btc::{broadcast_chain, get_fee_per_byte, sign_chain, FeePolicy};
+use crate::wallet::weight::fee_for_weight;
+
+/// Marks the data of an MTC reveal script.
+pub const PROTOCOL_TAG: &[u8] = b"mtc";
+/// The largest payload written into one reveal transaction. It keeps the
+/// transaction well below the 400,000 weight units relayed by default.
+pub const MAX_INSCRIPTION_SIZE: usize = 350_000;
+/// The largest push allowed in a tapscript.
+const MAX_PUSH_SIZE: usize = 520;
+/// The x coordinate of the BIP-341 NUMS point H, which nobody knows the
+/// discrete logarithm of.
+const NUMS_INTERNAL_KEY: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// Returns the reveal script holding `payload`.
+pub fn reveal_script(sender: &XOnlyPublicKey, payload: &[u8]) -> ScriptBuf {
+    let tag = PushBytesBuf::try_from(PROTOCOL_TAG.to_vec()).expect("the tag is short");
+    let mut builder = Builder::new()
+        .push_x_only_key(sender)
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .push_opcode(opcodes::OP_FALSE)
+        .push_opcode(opcodes::all::OP_IF)
+        .push_slice(tag);
+    for chunk in payload.chunks(MAX_PUSH_SIZE) {
+        let chunk: &PushBytes = chunk.try_into().expect("chunks are at most 520 bytes");
+        builder = builder.push_slice(chunk);
+    }
+    builder.push_opcode(opcodes::all::OP_ENDIF).into_script()
+}
+
+/// Returns the payload of a reveal script, or `None` if `script` is not
+/// one.
+pub fn parse_reveal_script(script: &Script) -> Option<Vec<u8>> {
+    let mut instructions = script.instructions();
+    match instructions.next()?.ok()? {
+        Instruction::PushBytes(key) if key.len() == 32 => {}
+        _ => return None,
+    }
+    if instructions.next()?.ok()? != Instruction::Op(opcodes::all::OP_CHECKSIG) {
+        return None;
+    }
+    // OP_FALSE is parsed as an empty push.
+    match instructions.next()?.ok()? {
+        Instruction::PushBytes(bytes) if bytes.is_empty() => {}
+        _ => return None,
+    }
+    if instructions.next()?.ok()? != Instruction::Op(opcodes::all::OP_IF) {
+        return None;
+    }
+    match instructions.next()?.ok()? {
+        Instruction::PushBytes(tag) if tag.as_bytes() == PROTOCOL_TAG => {}
+        _ => return None,
+    }
+    let mut payload = vec![];
+    loop {
+        match instructions.next()?.ok()? {
+            Instruction::PushBytes(chunk) => payload.extend_from_slice(chunk.as_bytes()),
+            Instruction::Op(opcodes::all::OP_ENDIF) => break,
+            Instruction::Op(_) => return None,
+        }
+    }
+    // Nothing may follow OP_ENDIF.
+    if instructions.next().is_some() {
+        return None;
+    }
+    Some(payload)
+}
+
+/// Returns the taproot tree with `script` as its only leaf.
+pub fn spend_info(script: &ScriptBuf) -> TaprootSpendInfo {
+    let internal_key = XOnlyPublicKey::from_slice(&NUMS_INTERNAL_KEY).expect("H is a valid point");
+    TaprootBuilder::new()
+        .add_leaf(0, script.clone())
+        .expect("a single leaf at depth 0 is a valid tree")
+        .finalize(&Secp256k1::verification_only(), internal_key)
+        .expect("the tree has a single leaf")
+}
+
+/// Builds the reveal transaction spending `commit` and paying `output`,
+/// with a placeholder signature of the right size.
+fn reveal_transaction(commit: OutPoint, output: TxOut, script: &ScriptBuf, spend_info: &TaprootSpendInfo) -> Transaction {
+    let control_block = spend_info
+        .control_block(&(script.clone(), LeafVersion::TapScript))
+        .expect("the script is a leaf of the tree");
+    let mut witness = Witness::new();
+    witness.push([0; 64]);
+    witness.push(script.as_bytes());
+    witness.push(control_block.serialize());
+    Transaction {
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: commit,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness,
+        }],
+        output: vec![output],
+    }
+}
+
+/// Returns the fee of the reveal transaction, which only depends on its
+/// weight.
+fn reveal_fee(output: &TxOut, script: &ScriptBuf, spend_info: &TaprootSpendInfo, fee_per_byte: MillisatoshiPerByte) -> u64 {
+    let placeholder = OutPoint { txid: Txid::all_zeros(), vout: 0 };
+    let reveal = reveal_transaction(placeholder, output.clone(), script, spend_info);
+    fee_for_weight(reveal.weight().to_wu(), fee_per_byte)
+}
+
+/// Returns the x-only key the Schnorr canister derives for `account`.
+async fn sender_key(key_name: &str, account: &Account) -> Result<XOnlyPublicKey, MtcError> {
+    let public_key = schnorr_public_key(key_name, account_path(account)).await?;
+    // The Schnorr canister returns SEC1 compressed keys; BIP-340 only uses
+    // the x coordinate.
+    let x_only = match public_key.len() {
+        33 => &public_key[1..],
+        32 => &public_key[..],
+        len => {
+            return Err(MtcError::InvalidPublicKey(format!(
+                "expected a 32 or 33 bytes long Schnorr public key, got {} bytes",
+                len
+            )))
+        }
+    };
+    XOnlyPublicKey::from_slice(x_only)
+        .map_err(|err| MtcError::InvalidPublicKey(format!("invalid Schnorr public key: {}", err)))
+}
+
+fn account_path(account: &Account) -> Vec<Vec<u8>> {
+    derivation_path(account).into_iter().map(|path| path.into_vec()).collect()
+}
+
+/// Sends `payload` to `recipient_output` in a commit and a reveal
+/// transaction and returns both txids.
+pub async fn send_inscription(
+    network: BitcoinNetwork,
+    key_name: String,
+    schnorr_key_name: String,
+    recipient_output: TxOut,
+    payload: &[u8],
+    strategy: CoinSelectionStrategy,
+    account: &Account,
+) -> Result<Vec<String>, MtcError> {
+    if payload.len() > MAX_INSCRIPTION_SIZE {
+        return Err(MtcError::InvalidRequest(format!(
+            "the payload is {} bytes long, at most {} bytes can be inscribed",
+            payload.len(),
+            MAX_INSCRIPTION_SIZE
+        )));
+    }
+    let sender = sender_key(&schnorr_key_name, account).await?;
+    let script = reveal_script(&sender, payload);
+    let spend_info = spend_info(&script);
+    let fee_per_byte = get_fee_per_byte(network, &FeePolicy::default()).await?;
+    let fee = reveal_fee(&recipient_output, &script, &spend_info, fee_per_byte);
+    let commit_value = recipient_output.value.to_sat() + fee;
+    let commit_output = TxOut {
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        value: Amount::from_sat(commit_value),
+    };
+
+    let mut signed_chain = sign_chain(network, key_name, vec![vec![commit_output.clone()]], fee_per_byte, strategy, None, account).await?;
+    let commit = OutPoint { txid: signed_chain.transactions[0].compute_txid(), vout: 0 };
+    let reveal = match sign_reveal(&schnorr_key_name, account, commit, commit_output.clone(), recipient_output, &script, &spend_info).await {
+        Ok(reveal) => reveal,
+        Err(err) => {
+            signed_chain.release(account);
+            return Err(err);
+        }
+    };
+    signed_chain.payments.push(reveal.output.len() as u32);
+    signed_chain.transactions.push(reveal);
+    signed_chain.fees.push(fee);
+    signed_chain.prevouts.push(vec![commit_output]);
+    let responses = broadcast_chain(network, signed_chain, account).await?;
+    Ok(responses.into_iter().map(|response| response.txid).collect())
+}
+
+async fn sign_reveal(
+    schnorr_key_name: &str,
+    account: &Account,
+    commit: OutPoint,
+    commit_output: TxOut,
+    recipient_output: TxOut,
+    script: &ScriptBuf,
+    spend_info: &TaprootSpendInfo,
+) -> Result<Transaction, MtcError> {
+    let mut reveal = reveal_transaction(commit, recipient_output, script, spend_info);
+    let leaf_hash = TapLeafHash::from_script(script, LeafVersion::TapScript);
+    let sighash = SighashCache::new(&reveal)
+        .taproot_script_spend_signature_hash(0, &Prevouts::All(&[commit_output]), leaf_hash, TapSighashType::Default)
+        .map_err(|err| MtcError::SigningFailed(err.to_string()))?;
+    let signature = sign_with_schnorr(schnorr_key_name, account_path(account), sighash.to_byte_array().to_vec()).await?;
+    if signature.len() != 64 {
+        return Err(MtcError::SigningFailed(format!(
+            "expected a 64 bytes long Schnorr signature, got {} bytes",
+            signature.len()
+        )));
+    }
+    // With the default sighash type the signature is used as is.
+    let mut witness: Vec<Vec<u8>> = reveal.input[0].witness.to_vec();
+    witness[0] = signature;
+    reveal.input[0].witness = Witness::from_slice(&witness);
+    Ok(reveal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::opcodes::all::{OP_DROP, OP_PUSHNUM_1};
+
+    fn sender() -> XOnlyPublicKey {
+        XOnlyPublicKey::from_slice(&NUMS_INTERNAL_KEY).unwrap()
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|byte| byte as u8).collect()
+    }
+
+    /// The reveal script of `payload` up to its data pushes.
+    fn script_prefix() -> Builder {
+        Builder::new()
+            .push_x_only_key(&sender())
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .push_opcode(opcodes::OP_FALSE)
+            .push_opcode(opcodes::all::OP_IF)
+            .push_slice(b"mtc")
+    }
+
+    #[test]
+    fn reveal_scripts_round_trip() {
+        for len in [0, 1, MAX_PUSH_SIZE, 1200] {
+            let script = reveal_script(&sender(), &payload(len));
+            assert_eq!(parse_reveal_script(&script), Some(payload(len)), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn payloads_are_pushed_in_chunks_of_520_bytes() {
+        let script = reveal_script(&sender(), &payload(1200));
+        let pushes: Vec<usize> = script
+            .instructions()
+            .skip(5)
+            .filter_map(|instruction| match instruction.unwrap() {
+                Instruction::PushBytes(bytes) => Some(bytes.len()),
+                Instruction::Op(_) => None,
+            })
+            .collect();
+        assert_eq!(pushes, vec![520, 520, 160]);
+    }
+
+    #[test]
+    fn scripts_with_other_opcodes_are_rejected() {
+        let trailing = script_prefix()
+            .push_slice([1; 10])
+            .push_opcode(opcodes::all::OP_ENDIF)
+            .push_opcode(OP_PUSHNUM_1)
+            .into_script();
+        assert_eq!(parse_reveal_script(&trailing), None);
+        let garbage = script_prefix()
+            .push_slice([1; 10])
+            .push_opcode(OP_DROP)
+            .push_opcode(opcodes::all::OP_ENDIF)
+            .into_script();
+        assert_eq!(parse_reveal_script(&garbage), None);
+        let unterminated = script_prefix().push_slice([1; 10]).into_script();
+        assert_eq!(parse_reveal_script(&unterminated), None);
+        let other_tag = Builder::new()
+            .push_x_only_key(&sender())
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .push_opcode(opcodes::OP_FALSE)
+            .push_opcode(opcodes::all::OP_IF)
+            .push_slice(b"ord")
+            .push_opcode(opcodes::all::OP_ENDIF)
+            .into_script();
+        assert_eq!(parse_reveal_script(&other_tag), None);
+        let truncated = ScriptBuf::from_bytes(reveal_script(&sender(), &payload(100)).as_bytes()[..80].to_vec());
+        assert_eq!(parse_reveal_script(&truncated), None);
+    }
+
+    #[test]
+    fn reveal_fees_never_undercut_the_rate() {
+        let output = TxOut { value: Amount::from_sat(1_000), script_pubkey: ScriptBuf::new_op_return([1; 10]) };
+        for len in [10, 1_000, 10_001] {
+            let script = reveal_script(&sender(), &payload(len));
+            let spend_info = spend_info(&script);
+            let placeholder = OutPoint { txid: Txid::all_zeros(), vout: 0 };
+            let reveal = reveal_transaction(placeholder, output.clone(), &script, &spend_info);
+            let fee = reveal_fee(&output, &script, &spend_info, 1_500);
+            assert_eq!(fee, fee_for_weight(reveal.weight().to_wu(), 1_500));
+            assert!(fee * 1000 >= reveal.vsize() as u64 * 1_500);
+        }
+    }
+}
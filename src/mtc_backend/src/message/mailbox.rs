@@ -0,0 +1,241 @@
+//! Reads the caller's inbox and outbox.
+//!
+//! Both are append-only lists, so a position in them is a stable cursor.
+//! Pages are returned newest first, and `next_cursor` is the position the
+//! next page continues from, towards older messages.
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde::Serialize;
+
+use crate::message::inbox::{read_inbox_state, InboxEntry, MessageEnvelope};
+use crate::message::outbox::{read_outbox_state, OutboxEntry};
+use crate::message::receipt::ReceiptRecord;
+use crate::utils::{MessageTransport, MtcError};
+
+/// The number of messages in a page when the request doesn't set one.
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+/// A page stops growing once the bodies it holds reach this size, so that
+/// the reply stays below the 3 MiB limit of a query response. A single
+/// message is always returned, however large.
+pub const MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageDirection {
+    #[serde(rename = "inbound")]
+    Inbound,
+    #[serde(rename = "outbound")]
+    Outbound,
+}
+
+/// A message as returned to its sender or recipient. The body is still
+/// encrypted; fragmented messages are returned reassembled.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct MessageView {
+    pub id: String,
+    pub direction: MessageDirection,
+    pub subaccount: Option<Subaccount>,
+    /// The sender of an inbound message, if known, or the recipient of an
+    /// outbound one.
+    pub counterparty: Option<String>,
+    pub txid: String,
+    pub txids: Vec<String>,
+    pub transport: MessageTransport,
+    /// The height of the block the message was confirmed in.
+    pub height: Option<u32>,
+    /// When the message was indexed or sent, in nanoseconds since the
+    /// epoch.
+    pub timestamp: u64,
+    /// Outbound messages are always read.
+    pub read: bool,
+    pub envelope: MessageEnvelope,
+    /// The newest receipt of an outbound message.
+    pub receipt: Option<ReceiptRecord>,
+}
+
+/// Selects a page of messages. Every filter left empty matches all
+/// messages.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ListMessagesRequest {
+    pub subaccount: Option<Subaccount>,
+    /// The sender or recipient address.
+    pub counterparty: Option<String>,
+    /// Only messages with a timestamp at or after this time.
+    pub since: Option<u64>,
+    /// Only messages with a timestamp before this time.
+    pub until: Option<u64>,
+    /// Only messages that are, or aren't, in a block yet. Messages are
+    /// only indexed once they are in a block, so the filter only applies
+    /// to outboxes.
+    pub confirmed: Option<bool>,
+    pub read: Option<bool>,
+    /// The `next_cursor` of the previous page.
+    pub cursor: Option<u64>,
+    /// At most 100, 20 by default.
+    pub limit: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct MessagePage {
+    pub messages: Vec<MessageView>,
+    /// Set if older messages may match the request.
+    pub next_cursor: Option<u64>,
+}
+
+/// A message in an inbox or outbox.
+pub trait MailboxEntry {
+    fn subaccount(&self) -> Option<Subaccount>;
+    fn counterparty(&self) -> Option<&str>;
+    fn timestamp(&self) -> u64;
+    fn height(&self) -> Option<u32>;
+    fn is_read(&self) -> bool;
+    fn envelope(&self) -> &MessageEnvelope;
+    fn view(&self) -> MessageView;
+}
+
+impl MailboxEntry for InboxEntry {
+    fn subaccount(&self) -> Option<Subaccount> {
+        self.subaccount
+    }
+
+    fn counterparty(&self) -> Option<&str> {
+        self.sender.as_deref()
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.indexed_at
+    }
+
+    fn height(&self) -> Option<u32> {
+        Some(self.height)
+    }
+
+    fn is_read(&self) -> bool {
+        self.read
+    }
+
+    fn envelope(&self) -> &MessageEnvelope {
+        &self.envelope
+    }
+
+    fn view(&self) -> MessageView {
+        MessageView {
+            id: self.id.clone(),
+            direction: MessageDirection::Inbound,
+            subaccount: self.subaccount,
+            counterparty: self.sender.clone(),
+            txid: self.txid.clone(),
+            txids: self.txids.clone(),
+            transport: self.transport,
+            height: Some(self.height),
+            timestamp: self.indexed_at,
+            read: self.read,
+            envelope: self.envelope.clone(),
+            receipt: None,
+        }
+    }
+}
+
+impl MailboxEntry for OutboxEntry {
+    fn subaccount(&self) -> Option<Subaccount> {
+        self.subaccount
+    }
+
+    fn counterparty(&self) -> Option<&str> {
+        Some(&self.recipient)
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.sent_at
+    }
+
+    fn height(&self) -> Option<u32> {
+        self.height
+    }
+
+    fn is_read(&self) -> bool {
+        true
+    }
+
+    fn envelope(&self) -> &MessageEnvelope {
+        &self.envelope
+    }
+
+    fn view(&self) -> MessageView {
+        MessageView {
+            id: self.id.clone(),
+            direction: MessageDirection::Outbound,
+            subaccount: self.subaccount,
+            counterparty: Some(self.recipient.clone()),
+            txid: self.txid.clone(),
+            txids: self.txids.clone(),
+            transport: self.transport,
+            height: self.height,
+            timestamp: self.sent_at,
+            read: true,
+            envelope: self.envelope.clone(),
+            receipt: self.receipt.clone(),
+        }
+    }
+}
+
+impl ListMessagesRequest {
+    fn matches(&self, entry: &impl MailboxEntry) -> bool {
+        self.subaccount.map_or(true, |subaccount| entry.subaccount() == Some(subaccount))
+            && self.counterparty.as_deref().map_or(true, |counterparty| entry.counterparty() == Some(counterparty))
+            && self.since.map_or(true, |since| entry.timestamp() >= since)
+            && self.until.map_or(true, |until| entry.timestamp() < until)
+            && self.confirmed.map_or(true, |confirmed| entry.height().is_some() == confirmed)
+            && self.read.map_or(true, |read| entry.is_read() == read)
+    }
+
+    /// Returns a page of `entries`, which are read newest first from the
+    /// cursor on.
+    fn page(&self, entries: impl Iterator<Item = (u64, impl MailboxEntry)>) -> MessagePage {
+        let limit = self
+            .limit
+            .map_or(DEFAULT_PAGE_SIZE, |limit| (limit as usize).clamp(1, MAX_PAGE_SIZE));
+        let mut messages = vec![];
+        let mut size = 0;
+        let mut next_cursor = None;
+        for (position, entry) in entries {
+            if !self.matches(&entry) {
+                continue;
+            }
+            if messages.len() == limit || (size >= MAX_PAGE_BYTES && !messages.is_empty()) {
+                next_cursor = Some(position + 1);
+                break;
+            }
+            let message = entry.view();
+            size += message.envelope.body.len();
+            messages.push(message);
+        }
+        MessagePage { messages, next_cursor }
+    }
+}
+
+/// Returns a page of the messages received by `owner`.
+pub fn list_inbox(owner: &Principal, request: &ListMessagesRequest) -> Result<MessagePage, MtcError> {
+    if request.confirmed.is_some() {
+        return Err(MtcError::InvalidRequest(
+            "received messages are always confirmed, only outboxes can be filtered by confirmation".to_string(),
+        ));
+    }
+    let end = request.cursor.unwrap_or(u64::MAX);
+    Ok(read_inbox_state(|state| request.page(state.inboxes.iter_before(&Account::from(*owner), end))))
+}
+
+/// Returns a page of the messages sent by `owner`.
+pub fn list_outbox(owner: &Principal, request: &ListMessagesRequest) -> MessagePage {
+    let end = request.cursor.unwrap_or(u64::MAX);
+    read_outbox_state(|state| request.page(state.outboxes.iter_before(&Account::from(*owner), end)))
+}
+
+/// Returns the message `id` received or sent by `owner`.
+pub fn get_message(owner: &Principal, id: &str) -> Option<MessageView> {
+    let owner = Account::from(*owner);
+    let inbound = read_inbox_state(|state| state.inboxes.find(&owner, |entry| entry.id == id).map(|(_, entry)| entry.view()));
+    inbound.or_else(|| {
+        read_outbox_state(|state| state.outboxes.find(&owner, |entry| entry.id == id).map(|(_, entry)| entry.view()))
+    })
+}
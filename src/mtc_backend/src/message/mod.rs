@@ -0,0 +1,15 @@
+pub mod cipher;
+#[cfg(feature = "encryption")]
+pub mod ecies;
+pub mod envelope;
+pub mod fragment;
+pub mod group;
+pub mod inbox;
+pub mod inscription;
+pub mod mailbox;
+pub mod outbox;
+pub mod receipt;
+pub mod registry;
+pub mod send_message;
+pub mod source;
+pub mod thread;
@@ -0,0 +1,280 @@
+//! Keeps the messages an account sent.
+//!
+//! A message is recorded once its transactions were broadcast. It counts
+//! as confirmed once an output of its last transaction is reported in a
+//! block: the one paying the recipient, which every message has, or the
+//! change paid back to the sender. The transactions of a message spend
+//! each other, so the last one is mined with or after the others. A
+//! message also shows the newest receipt of its recipient (see
+//! `receipt`). Outboxes are kept in stable memory.
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_stable_structures::{storable::Blob, StableBTreeMap};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::message::envelope::Envelope;
+use crate::message::inbox::MessageEnvelope;
+use crate::message::receipt::ReceiptRecord;
+use crate::storage::{self, Candid, Memory, StableLists, OUTBOX_MEMORY, SENDERS_MEMORY};
+use crate::utils::{AddressKind, MessageTransport, MtcError};
+use crate::wallet::state::{get_all_utxo_from_wallet, get_utxos};
+
+thread_local! {
+    static OUTBOX_STATE: RefCell<OutboxState> = RefCell::new(OutboxState::init());
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub subaccount: Option<Subaccount>,
+    pub recipient: String,
+    /// The transaction that carries the message, or its first fragment.
+    pub txid: String,
+    /// Every transaction sent for the message.
+    pub txids: Vec<String>,
+    pub transport: MessageTransport,
+    pub envelope: MessageEnvelope,
+    pub sent_at: u64,
+    /// The height of the block the message was confirmed in.
+    pub height: Option<u32>,
+    pub receipt: Option<ReceiptRecord>,
+}
+
+impl OutboxEntry {
+    fn is_referenced_by(&self, reference: &str) -> bool {
+        self.id.eq_ignore_ascii_case(reference) || self.txids.iter().any(|txid| txid.eq_ignore_ascii_case(reference))
+    }
+}
+
+pub struct OutboxState {
+    /// The messages each principal sent.
+    pub outboxes: StableLists<OutboxEntry>,
+    /// The sender of every message, keyed by message id and by txid.
+    pub senders: StableBTreeMap<Blob<32>, Candid<Principal>, Memory>,
+}
+
+/// Returns the key of a message id or txid in `OutboxState::senders`.
+fn reference_key(reference: &str) -> Option<Blob<32>> {
+    Blob::try_from(hex::decode(reference).ok()?.as_slice()).ok()
+}
+
+impl OutboxState {
+    pub fn init() -> Self {
+        Self {
+            outboxes: StableLists::init(OUTBOX_MEMORY),
+            senders: StableBTreeMap::init(storage::memory(SENDERS_MEMORY)),
+        }
+    }
+
+    /// Returns the message of `sender` with the id or txid `reference`.
+    pub fn find(&self, sender: &Principal, reference: &str) -> Option<OutboxEntry> {
+        self.outboxes
+            .find(&Account::from(*sender), |entry| entry.is_referenced_by(reference))
+            .map(|(_, entry)| entry)
+    }
+
+    /// Applies `f` to the message of `sender` with the id or txid
+    /// `reference`. Returns whether there is one.
+    pub fn update(&mut self, sender: &Principal, reference: &str, f: impl FnOnce(&mut OutboxEntry)) -> bool {
+        let account = Account::from(*sender);
+        let Some((position, mut entry)) = self.outboxes.find(&account, |entry| entry.is_referenced_by(reference)) else {
+            return false;
+        };
+        f(&mut entry);
+        self.outboxes.set(&account, position, entry);
+        true
+    }
+
+    /// Returns the recipients of the messages of `account` that aren't
+    /// confirmed yet.
+    pub fn unconfirmed_recipients(&self, account: &Account) -> BTreeSet<String> {
+        self.outboxes
+            .iter(&Account::from(account.owner))
+            .filter(|(_, entry)| entry.height.is_none() && entry.subaccount == account.subaccount)
+            .map(|(_, entry)| entry.recipient)
+            .collect()
+    }
+
+    /// Sets the height of the messages of `account` whose last transaction
+    /// is in `confirmed`.
+    pub fn confirm(&mut self, account: &Account, confirmed: &HashMap<String, u32>) {
+        let owner = Account::from(account.owner);
+        let changed: Vec<(u64, OutboxEntry)> = self
+            .outboxes
+            .iter(&owner)
+            .filter(|(_, entry)| entry.height.is_none() && entry.subaccount == account.subaccount)
+            .filter_map(|(position, mut entry)| {
+                entry.height = Some(*confirmed.get(entry.txids.last()?)?);
+                Some((position, entry))
+            })
+            .collect();
+        for (position, entry) in changed {
+            self.outboxes.set(&owner, position, entry);
+        }
+    }
+
+    pub fn insert_sender(&mut self, reference: &str, sender: Principal) {
+        if let Some(key) = reference_key(reference) {
+            self.senders.insert(key, Candid(sender));
+        }
+    }
+
+    pub fn sender(&self, reference: &str) -> Option<Principal> {
+        self.senders.get(&reference_key(reference)?).map(|sender| sender.0)
+    }
+}
+
+pub fn read_outbox_state<R>(f: impl FnOnce(&OutboxState) -> R) -> R {
+    OUTBOX_STATE.with(|state| f(&state.borrow()))
+}
+
+pub fn mutate_outbox_state<R>(f: impl FnOnce(&mut OutboxState) -> R) -> R {
+    OUTBOX_STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Points the messages of `account` carried by `old_txid` at the
+/// transaction that replaced it.
+pub fn replace_txid(account: &Account, old_txid: &str, new_txid: &str) {
+    mutate_outbox_state(|state| {
+        let owner = Account::from(account.owner);
+        let changed: Vec<(u64, OutboxEntry)> = state
+            .outboxes
+            .iter(&owner)
+            .filter(|(_, entry)| entry.subaccount == account.subaccount && entry.is_referenced_by(old_txid))
+            .map(|(position, mut entry)| {
+                if entry.txid.eq_ignore_ascii_case(old_txid) {
+                    entry.txid = new_txid.to_string();
+                }
+                for txid in entry.txids.iter_mut().filter(|txid| txid.eq_ignore_ascii_case(old_txid)) {
+                    *txid = new_txid.to_string();
+                }
+                (position, entry)
+            })
+            .collect();
+        if !changed.is_empty() {
+            state.insert_sender(new_txid, account.owner);
+        }
+        for (position, entry) in changed {
+            state.outboxes.set(&owner, position, entry);
+        }
+    })
+}
+
+/// Returns the principal that sent the message with the id or txid
+/// `reference`.
+pub fn find_sender(reference: &str) -> Option<Principal> {
+    read_outbox_state(|state| state.sender(reference))
+}
+
+/// Records the messages `account` sent to `recipient` in `txids`.
+pub fn record_sent(
+    account: &Account,
+    recipient: &str,
+    txid: &str,
+    txids: &[String],
+    transport: MessageTransport,
+    messages: &[(String, Envelope)],
+) {
+    let now = ic_cdk::api::time();
+    mutate_outbox_state(|state| {
+        for txid in txids {
+            state.insert_sender(txid, account.owner);
+        }
+        for (id, envelope) in messages {
+            state.insert_sender(id, account.owner);
+            state.outboxes.push(&Account::from(account.owner), OutboxEntry {
+                id: id.clone(),
+                subaccount: account.subaccount,
+                recipient: recipient.to_string(),
+                txid: txid.to_string(),
+                txids: txids.to_vec(),
+                transport,
+                envelope: MessageEnvelope::from(envelope),
+                sent_at: now,
+                height: None,
+                receipt: None,
+            });
+        }
+    });
+}
+
+/// Confirms the sent messages of `account` whose last transaction has an
+/// output in a block, among the outputs paying their recipients and the
+/// P2WPKH wallet of `account`. A message whose outputs were all spent
+/// before they were seen stays unconfirmed.
+pub async fn confirm_sent(network: BitcoinNetwork, account: &Account) -> Result<(), MtcError> {
+    let recipients = read_outbox_state(|state| state.unconfirmed_recipients(account));
+    if recipients.is_empty() {
+        return Ok(());
+    }
+    let mut confirmed: HashMap<String, u32> = HashMap::new();
+    for (outpoint, utxo) in get_all_utxo_from_wallet(account, AddressKind::P2wpkh) {
+        confirmed.insert(outpoint.to_outpoint()?.txid.to_string(), utxo.height);
+    }
+    for recipient in recipients {
+        for (outpoint, (_, height)) in get_utxos(network, &recipient).await? {
+            confirmed.insert(outpoint.to_outpoint()?.txid.to_string(), height);
+        }
+    }
+    mutate_outbox_state(|state| state.confirm(account, &confirmed));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(recipient: &str, txids: &[&str]) -> OutboxEntry {
+        OutboxEntry {
+            id: txids[0].to_string(),
+            subaccount: None,
+            recipient: recipient.to_string(),
+            txid: txids[0].to_string(),
+            txids: txids.iter().map(|txid| txid.to_string()).collect(),
+            transport: MessageTransport::OpReturn,
+            envelope: MessageEnvelope {
+                flags: 1,
+                recipient_hint: vec![1, 2, 3, 4],
+                suite: 2,
+                nonce: vec![7; 12],
+                extensions: vec![],
+                body: vec![],
+            },
+            sent_at: 1,
+            height: None,
+            receipt: None,
+        }
+    }
+
+    #[test]
+    fn messages_are_confirmed_with_their_last_transaction() {
+        let account = Account::from(Principal::from_slice(&[1; 29]));
+        let mut state = OutboxState::init();
+        state.outboxes.push(&account, entry("alice", &["a1", "a2"]));
+        state.outboxes.push(&account, entry("bob", &["b1"]));
+        state.outboxes.push(&account, entry("carol", &["c1"]));
+        // Another account of the owner.
+        state.outboxes.push(&account, OutboxEntry { subaccount: Some([1; 32]), ..entry("dave", &["d1"]) });
+        assert_eq!(
+            state.unconfirmed_recipients(&account),
+            BTreeSet::from(["alice".to_string(), "bob".to_string(), "carol".to_string()])
+        );
+
+        // The first fragment of alice's message is mined, the last isn't.
+        let confirmed = HashMap::from([("a1".to_string(), 10), ("b1".to_string(), 11), ("d1".to_string(), 12)]);
+        state.confirm(&account, &confirmed);
+        assert_eq!(state.find(&account.owner, "a1").unwrap().height, None);
+        assert_eq!(state.find(&account.owner, "b1").unwrap().height, Some(11));
+        assert_eq!(state.find(&account.owner, "d1").unwrap().height, None);
+        assert_eq!(
+            state.unconfirmed_recipients(&account),
+            BTreeSet::from(["alice".to_string(), "carol".to_string()])
+        );
+
+        state.confirm(&account, &HashMap::from([("a2".to_string(), 13)]));
+        assert_eq!(state.find(&account.owner, "a1").unwrap().height, Some(13));
+    }
+}
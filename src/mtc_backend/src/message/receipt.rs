@@ -0,0 +1,364 @@
+//! Delivery receipts.
+//!
+//! The recipient of a message tells its sender that the message was
+//! delivered, read or rejected with a receipt signed by the recipient's
+//! registered ed25519 key (see `registry`). A receipt is either submitted
+//! to the canister with `submit_receipt` or sent on chain, to the sender's
+//! address, as a plaintext envelope with an `EXT_RECEIPT` extension and an
+//! empty body. The extension value is:
+//!
+//! ```text
+//! reference_type  1 byte    0: message id, 1: txid
+//! reference       16 bytes  the message id, or
+//!                 32 bytes  any txid of the message, in display order
+//! state           1 byte    0: delivered, 1: read, 2: rejected
+//! timestamp       8 bytes   nanoseconds since the epoch
+//! signature       64 bytes  ed25519 over RECEIPT_DOMAIN || the above
+//! ```
+//!
+//! The sender's outbox keeps the newest valid receipt of each message. A
+//! receipt is valid if it was signed by the keys the recipient's address
+//! had registered at its timestamp; keys that were revoked since never
+//! verify. The timestamp may not be ahead of the canister's time by more
+//! than the clock drift the registry allows, so a receipt can't be dated
+//! in the future to outrank every later one.
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::message::envelope::{Envelope, Extension, EXT_RECEIPT, SUITE_PLAINTEXT};
+use crate::message::fragment::MESSAGE_ID_LEN;
+use crate::message::inbox::MessageEnvelope;
+use crate::message::outbox::{find_sender, mutate_outbox_state, read_outbox_state};
+use crate::message::registry::{
+    parse_signing_key, read_address_owner, read_key_registry, verify_signature, KeyRegistryState, MAX_CLOCK_DRIFT_NANOS,
+};
+use crate::utils::MtcError;
+
+const RECEIPT_DOMAIN: &[u8] = b"mtc-receipt-v1";
+const TXID_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum MessageReference {
+    MessageId(String),
+    Txid(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptState {
+    Delivered,
+    Read,
+    Rejected,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub reference: MessageReference,
+    pub state: ReceiptState,
+    /// When the recipient signed the receipt, in nanoseconds since the
+    /// epoch.
+    pub timestamp: u64,
+    /// The ed25519 signature of `signed_message` by the recipient's
+    /// signing key.
+    pub signature: Vec<u8>,
+}
+
+/// The receipt shown in the sender's outbox.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct ReceiptRecord {
+    pub state: ReceiptState,
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+    /// The transaction that carried the receipt, or `None` if it was
+    /// submitted to the canister.
+    pub txid: Option<String>,
+    pub recorded_at: u64,
+}
+
+impl MessageReference {
+    /// Returns the reference as stored in the outbox: a hex message id or
+    /// a txid.
+    pub fn as_str(&self) -> &str {
+        match self {
+            MessageReference::MessageId(id) | MessageReference::Txid(id) => id,
+        }
+    }
+
+    /// Encodes the reference as a type byte, 0 for a message id and 1 for
+    /// a txid, followed by its bytes.
+    pub fn encode(&self) -> Result<Vec<u8>, MtcError> {
+        let (reference_type, expected_len, value) = match self {
+            MessageReference::MessageId(id) => (0, MESSAGE_ID_LEN, id),
+            MessageReference::Txid(txid) => (1, TXID_LEN, txid),
+        };
+        let bytes = hex::decode(value)
+            .ok()
+            .filter(|bytes| bytes.len() == expected_len)
+            .ok_or_else(|| MtcError::InvalidRequest(format!("invalid message reference {}", value)))?;
+        let mut encoded = vec![reference_type];
+        encoded.extend_from_slice(&bytes);
+        Ok(encoded)
+    }
+
+    /// Decodes a reference at the start of `bytes` and returns it with the
+    /// bytes that follow it.
+    pub fn decode_prefix(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        let (&reference_type, rest) = bytes.split_first()?;
+        let reference_len = match reference_type {
+            0 => MESSAGE_ID_LEN,
+            1 => TXID_LEN,
+            _ => return None,
+        };
+        if rest.len() < reference_len {
+            return None;
+        }
+        let (reference, rest) = rest.split_at(reference_len);
+        let reference = match reference_type {
+            0 => MessageReference::MessageId(hex::encode(reference)),
+            _ => MessageReference::Txid(hex::encode(reference)),
+        };
+        Some((reference, rest))
+    }
+}
+
+impl ReceiptState {
+    fn to_byte(self) -> u8 {
+        match self {
+            ReceiptState::Delivered => 0,
+            ReceiptState::Read => 1,
+            ReceiptState::Rejected => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ReceiptState::Delivered),
+            1 => Some(ReceiptState::Read),
+            2 => Some(ReceiptState::Rejected),
+            _ => None,
+        }
+    }
+}
+
+impl Receipt {
+    /// Returns the bytes the recipient signs.
+    pub fn signed_message(&self) -> Result<Vec<u8>, MtcError> {
+        let mut message = RECEIPT_DOMAIN.to_vec();
+        message.extend_from_slice(&self.encode_unsigned()?);
+        Ok(message)
+    }
+
+    fn encode_unsigned(&self) -> Result<Vec<u8>, MtcError> {
+        let mut encoded = self.reference.encode()?;
+        encoded.push(self.state.to_byte());
+        encoded.extend_from_slice(&self.timestamp.to_be_bytes());
+        Ok(encoded)
+    }
+
+    /// Returns the value of the `EXT_RECEIPT` extension.
+    pub fn encode(&self) -> Result<Vec<u8>, MtcError> {
+        if self.signature.len() != SIGNATURE_LEN {
+            return Err(MtcError::InvalidSignature(format!(
+                "expected a 64 bytes long signature, got {} bytes",
+                self.signature.len()
+            )));
+        }
+        let mut encoded = self.encode_unsigned()?;
+        encoded.extend_from_slice(&self.signature);
+        Ok(encoded)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (reference, rest) = MessageReference::decode_prefix(bytes)?;
+        if rest.len() != 1 + 8 + SIGNATURE_LEN {
+            return None;
+        }
+        Some(Self {
+            reference,
+            state: ReceiptState::from_byte(rest[0])?,
+            timestamp: u64::from_be_bytes(rest[1..9].try_into().expect("checked the length")),
+            signature: rest[9..].to_vec(),
+        })
+    }
+
+    /// Returns the envelope that sends this receipt on chain to the address
+    /// with `recipient_hint`.
+    pub fn to_envelope(&self, recipient_hint: [u8; 4]) -> Result<Envelope, MtcError> {
+        Ok(Envelope {
+            flags: 0,
+            recipient_hint,
+            suite: SUITE_PLAINTEXT,
+            nonce: vec![],
+            extensions: vec![Extension { ext_type: EXT_RECEIPT, value: self.encode()? }],
+            body: vec![],
+        })
+    }
+
+    /// Returns the receipt carried by `envelope`, if any.
+    pub fn of(envelope: &MessageEnvelope) -> Option<Self> {
+        envelope.extension(EXT_RECEIPT).and_then(Self::decode)
+    }
+}
+
+/// Checks that `receipt` was signed by the keys `recipient` had registered
+/// at its timestamp, and that it isn't dated after `now`.
+pub fn verify_receipt(registry: &KeyRegistryState, recipient: &Principal, receipt: &Receipt, now: u64) -> Result<(), MtcError> {
+    if receipt.timestamp > now.saturating_add(MAX_CLOCK_DRIFT_NANOS) {
+        return Err(MtcError::InvalidRequest(format!(
+            "the receipt timestamp {} is ahead of the current time {}",
+            receipt.timestamp, now
+        )));
+    }
+    let keys = registry.keys_at(recipient, receipt.timestamp).ok_or_else(|| {
+        MtcError::InvalidSignature(format!("{} had no valid keys at {}", recipient, receipt.timestamp))
+    })?;
+    verify_signature(&parse_signing_key(&keys.signing_key)?, &receipt.signed_message()?, &receipt.signature)
+}
+
+/// Checks `receipt` against the keys of the recipient of the message it
+/// references in the outbox of `sender`, and records it if it is newer
+/// than the receipt recorded so far. `txid` is the transaction that
+/// carried the receipt, if it was sent on chain.
+pub fn apply_receipt(sender: &Principal, receipt: &Receipt, txid: Option<String>) -> Result<(), MtcError> {
+    let reference = receipt.reference.as_str();
+    let recipient = read_outbox_state(|state| state.find(sender, reference).map(|entry| entry.recipient))
+    .ok_or_else(|| MtcError::InvalidRequest(format!("{} sent no message {}", sender, reference)))?;
+    let recipient_owner = read_address_owner(&recipient)
+        .ok_or_else(|| MtcError::InvalidRequest(format!("{} has no messaging keys", recipient)))?;
+    let now = ic_cdk::api::time();
+    read_key_registry(|registry| verify_receipt(registry, &recipient_owner, receipt, now))?;
+
+    mutate_outbox_state(|state| {
+        state.update(sender, reference, |entry| {
+            if entry.receipt.as_ref().map_or(true, |current| receipt.timestamp > current.timestamp) {
+                entry.receipt = Some(ReceiptRecord {
+                    state: receipt.state,
+                    timestamp: receipt.timestamp,
+                    signature: receipt.signature.clone(),
+                    txid,
+                    recorded_at: now,
+                });
+            }
+        })
+    });
+    Ok(())
+}
+
+/// Records a receipt submitted to the canister. Anyone may submit it: the
+/// signature shows that the recipient issued it.
+pub fn submit_receipt(receipt: Receipt) -> Result<(), MtcError> {
+    let sender = find_sender(receipt.reference.as_str())
+        .ok_or_else(|| MtcError::InvalidRequest(format!("unknown message {}", receipt.reference.as_str())))?;
+    apply_receipt(&sender, &receipt, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::registry::{registration_message, KeyRegistration};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const MINUTE_NANOS: u64 = 60 * 1_000_000_000;
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn recipient() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    /// Registers the keys of `seed` for the recipient at `now`.
+    fn register(registry: &mut KeyRegistryState, seed: u8, rotate: bool, now: u64) {
+        let signing_key = signing_key(seed);
+        let verifying_key = signing_key.verifying_key().to_bytes().to_vec();
+        let agreement_key = vec![seed; 32];
+        let message = registration_message(&recipient(), &verifying_key, &agreement_key, now);
+        let registration = KeyRegistration {
+            signing_key: verifying_key,
+            agreement_key,
+            timestamp: now,
+            signature: signing_key.sign(&message).to_bytes().to_vec(),
+        };
+        registry.register(recipient(), "bc1qrecipient".to_string(), registration, rotate, now).unwrap();
+    }
+
+    /// Returns a receipt for message 0101...01, signed at `timestamp` by
+    /// the keys of `seed`.
+    fn receipt(seed: u8, timestamp: u64) -> Receipt {
+        let mut receipt = Receipt {
+            reference: MessageReference::MessageId("01".repeat(MESSAGE_ID_LEN)),
+            state: ReceiptState::Read,
+            timestamp,
+            signature: vec![],
+        };
+        receipt.signature = signing_key(seed).sign(&receipt.signed_message().unwrap()).to_bytes().to_vec();
+        receipt
+    }
+
+    fn is_invalid_signature(result: Result<(), MtcError>) -> bool {
+        matches!(result, Err(MtcError::InvalidSignature(_)))
+    }
+
+    #[test]
+    fn receipts_round_trip() {
+        let by_id = receipt(1, NOW);
+        assert_eq!(Receipt::decode(&by_id.encode().unwrap()), Some(by_id.clone()));
+        let by_txid = Receipt { reference: MessageReference::Txid("ab".repeat(TXID_LEN)), ..by_id };
+        let encoded = by_txid.encode().unwrap();
+        assert_eq!(encoded.len(), 1 + TXID_LEN + 1 + 8 + SIGNATURE_LEN);
+        assert_eq!(Receipt::decode(&encoded), Some(by_txid));
+    }
+
+    #[test]
+    fn malformed_receipts_dont_decode() {
+        let encoded = receipt(1, NOW).encode().unwrap();
+        assert_eq!(Receipt::decode(&encoded[..encoded.len() - 1]), None);
+        let mut unknown_type = encoded.clone();
+        unknown_type[0] = 2;
+        assert_eq!(Receipt::decode(&unknown_type), None);
+        let mut unknown_state = encoded.clone();
+        unknown_state[1 + MESSAGE_ID_LEN] = 3;
+        assert_eq!(Receipt::decode(&unknown_state), None);
+
+        let unsigned = Receipt { signature: vec![0; 63], ..receipt(1, NOW) };
+        assert!(matches!(unsigned.encode(), Err(MtcError::InvalidSignature(_))));
+        let bad_reference = Receipt { reference: MessageReference::Txid("ab".repeat(16)), ..receipt(1, NOW) };
+        assert!(matches!(bad_reference.encode(), Err(MtcError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn receipts_verify_against_the_keys_at_their_timestamp() {
+        let mut registry = KeyRegistryState::init();
+        register(&mut registry, 1, false, NOW);
+        let now = NOW + 10 * MINUTE_NANOS;
+        assert_eq!(verify_receipt(&registry, &recipient(), &receipt(1, NOW + 1), now), Ok(()));
+        assert!(is_invalid_signature(verify_receipt(&registry, &recipient(), &receipt(2, NOW + 1), now)));
+        // Before the recipient had keys.
+        assert!(is_invalid_signature(verify_receipt(&registry, &recipient(), &receipt(1, NOW - 1), now)));
+
+        register(&mut registry, 2, true, NOW + MINUTE_NANOS);
+        // Receipts signed before the rotation still verify with the old
+        // keys, later ones only with the new keys.
+        assert_eq!(verify_receipt(&registry, &recipient(), &receipt(1, NOW + 1), now), Ok(()));
+        assert!(is_invalid_signature(verify_receipt(&registry, &recipient(), &receipt(1, NOW + 2 * MINUTE_NANOS), now)));
+        assert_eq!(verify_receipt(&registry, &recipient(), &receipt(2, NOW + 2 * MINUTE_NANOS), now), Ok(()));
+
+        registry.revoke(recipient(), NOW + 3 * MINUTE_NANOS).unwrap();
+        assert!(is_invalid_signature(verify_receipt(&registry, &recipient(), &receipt(2, NOW + 2 * MINUTE_NANOS), now)));
+        assert_eq!(verify_receipt(&registry, &recipient(), &receipt(1, NOW + 1), now), Ok(()));
+    }
+
+    #[test]
+    fn receipts_from_the_future_are_rejected() {
+        let mut registry = KeyRegistryState::init();
+        register(&mut registry, 1, false, NOW);
+        let latest = NOW + MAX_CLOCK_DRIFT_NANOS;
+        assert_eq!(verify_receipt(&registry, &recipient(), &receipt(1, latest), NOW), Ok(()));
+        for timestamp in [latest + 1, u64::MAX] {
+            let result = verify_receipt(&registry, &recipient(), &receipt(1, timestamp), NOW);
+            assert!(matches!(result, Err(MtcError::InvalidRequest(_))), "{}", timestamp);
+        }
+    }
+}
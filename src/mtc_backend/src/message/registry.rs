@@ -0,0 +1,376 @@
+//! The registry of the keys principals receive messages with.
+//!
+//! A principal registers an ed25519 signing key, which signs receipts and
+//! the registration itself, and an X25519 agreement key that senders
+//! derive message keys with. Registrations are never deleted: rotating or
+//! revoking keys only retires them, so messages encrypted to old keys can
+//! still be decrypted and old signatures checked.
+//!
+//! Every registration carries an ed25519 signature by its own signing key
+//! over `registration_message`, which binds the keys to the principal and
+//! to a timestamp chosen by the client. The timestamp must be close to the
+//! canister's time and newer than the principal's previous registration,
+//! so a registration can't be replayed.
+use candid::{CandidType, Deserialize, Principal};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::auth::caller_account;
+use crate::config;
+use crate::utils::{read_public_key, MtcError};
+use crate::wallet::address::account_to_p2wpkh_address;
+
+/// How far the timestamp of a registration may be from the canister's
+/// time, in nanoseconds.
+pub const MAX_CLOCK_DRIFT_NANOS: u64 = 10 * 60 * 1_000_000_000;
+const REGISTRATION_DOMAIN: &[u8] = b"mtc-key-registration-v1";
+
+thread_local! {
+    static KEY_REGISTRY: RefCell<KeyRegistryState> = RefCell::new(KeyRegistryState::init());
+}
+
+/// The argument of `register_messaging_keys` and `rotate_messaging_keys`.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct KeyRegistration {
+    /// The 32-byte ed25519 public key.
+    pub signing_key: Vec<u8>,
+    /// The 32-byte X25519 public key.
+    pub agreement_key: Vec<u8>,
+    /// When the client created the registration, in nanoseconds since the
+    /// epoch.
+    pub timestamp: u64,
+    /// The ed25519 signature of `registration_message` by `signing_key`.
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum KeyStatus {
+    Active,
+    Rotated { at: u64 },
+    Revoked { at: u64 },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct RegisteredKeys {
+    pub signing_key: Vec<u8>,
+    pub agreement_key: Vec<u8>,
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+    pub registered_at: u64,
+    pub status: KeyStatus,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct KeyRegistryState {
+    /// Every registration of a principal, oldest first. Only the last one
+    /// can be active.
+    pub keys: HashMap<Principal, Vec<RegisteredKeys>>,
+    /// The P2WPKH address of each principal's default account.
+    pub addresses: HashMap<String, Principal>,
+}
+
+impl KeyRegistryState {
+    pub fn init() -> Self {
+        Self { keys: HashMap::new(), addresses: HashMap::new() }
+    }
+
+    pub fn active_keys(&self, owner: &Principal) -> Option<&RegisteredKeys> {
+        self.keys
+            .get(owner)
+            .and_then(|history| history.last())
+            .filter(|keys| keys.status == KeyStatus::Active)
+    }
+
+    /// Returns the keys of `owner` that were active at `time`. Revoked
+    /// keys are never returned, whatever the time.
+    pub fn keys_at(&self, owner: &Principal, time: u64) -> Option<&RegisteredKeys> {
+        let keys = self.keys.get(owner)?.iter().rev().find(|keys| keys.timestamp <= time)?;
+        match keys.status {
+            KeyStatus::Active => Some(keys),
+            KeyStatus::Rotated { at } if time < at => Some(keys),
+            _ => None,
+        }
+    }
+
+    /// Adds `registration` as the active keys of `owner`. With `rotate`
+    /// the current keys are retired, otherwise there must be none.
+    pub fn register(
+        &mut self,
+        owner: Principal,
+        address: String,
+        registration: KeyRegistration,
+        rotate: bool,
+        now: u64,
+    ) -> Result<(), MtcError> {
+        match (self.active_keys(&owner).is_some(), rotate) {
+            (true, false) => {
+                return Err(MtcError::InvalidRequest(format!(
+                    "{} already has messaging keys, rotate them instead",
+                    owner
+                )))
+            }
+            (false, true) => {
+                return Err(MtcError::InvalidRequest(format!("{} has no messaging keys to rotate", owner)))
+            }
+            _ => {}
+        }
+        let last_timestamp = self.keys.get(&owner).and_then(|history| history.last()).map(|keys| keys.timestamp);
+        verify_registration(&owner, &registration, last_timestamp, now)?;
+        let history = self.keys.entry(owner).or_default();
+        if let Some(current) = history.last_mut() {
+            if current.status == KeyStatus::Active {
+                current.status = KeyStatus::Rotated { at: now };
+            }
+        }
+        history.push(RegisteredKeys {
+            signing_key: registration.signing_key,
+            agreement_key: registration.agreement_key,
+            timestamp: registration.timestamp,
+            signature: registration.signature,
+            registered_at: now,
+            status: KeyStatus::Active,
+        });
+        self.addresses.insert(address, owner);
+        Ok(())
+    }
+
+    pub fn revoke(&mut self, owner: Principal, now: u64) -> Result<(), MtcError> {
+        match self.keys.get_mut(&owner).and_then(|history| history.last_mut()) {
+            Some(current) if current.status == KeyStatus::Active => {
+                current.status = KeyStatus::Revoked { at: now };
+                Ok(())
+            }
+            _ => Err(MtcError::InvalidRequest(format!("{} has no messaging keys to revoke", owner))),
+        }
+    }
+}
+
+/// Returns the bytes a registration signs:
+/// `REGISTRATION_DOMAIN || len(owner) || owner || signing_key ||
+/// agreement_key || timestamp`, with the length as one byte and the
+/// timestamp as 8 big-endian bytes.
+pub fn registration_message(owner: &Principal, signing_key: &[u8], agreement_key: &[u8], timestamp: u64) -> Vec<u8> {
+    let owner = owner.as_slice();
+    let mut message = REGISTRATION_DOMAIN.to_vec();
+    message.push(owner.len() as u8);
+    message.extend_from_slice(owner);
+    message.extend_from_slice(signing_key);
+    message.extend_from_slice(agreement_key);
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message
+}
+
+/// Parses a 32-byte ed25519 public key.
+pub fn parse_signing_key(key: &[u8]) -> Result<VerifyingKey, MtcError> {
+    let key: &[u8; 32] = key
+        .try_into()
+        .map_err(|_| MtcError::InvalidRequest(format!("expected a 32 bytes long ed25519 key, got {} bytes", key.len())))?;
+    VerifyingKey::from_bytes(key).map_err(|err| MtcError::InvalidRequest(format!("invalid ed25519 key: {}", err)))
+}
+
+/// Checks an ed25519 signature, rejecting malleable and weak-key
+/// signatures.
+pub fn verify_signature(key: &VerifyingKey, message: &[u8], signature: &[u8]) -> Result<(), MtcError> {
+    let signature = Signature::from_slice(signature).map_err(|err| MtcError::InvalidSignature(err.to_string()))?;
+    key.verify_strict(message, &signature)
+        .map_err(|err| MtcError::InvalidSignature(err.to_string()))
+}
+
+fn verify_registration(
+    owner: &Principal,
+    registration: &KeyRegistration,
+    last_timestamp: Option<u64>,
+    now: u64,
+) -> Result<(), MtcError> {
+    let signing_key = parse_signing_key(&registration.signing_key)?;
+    if registration.agreement_key.len() != 32 || registration.agreement_key.iter().all(|byte| *byte == 0) {
+        return Err(MtcError::InvalidRequest("expected a non-zero 32 bytes long X25519 key".to_string()));
+    }
+    if registration.timestamp.abs_diff(now) > MAX_CLOCK_DRIFT_NANOS {
+        return Err(MtcError::InvalidRequest(format!(
+            "the registration timestamp {} is too far from the current time {}",
+            registration.timestamp, now
+        )));
+    }
+    if let Some(last_timestamp) = last_timestamp {
+        if registration.timestamp <= last_timestamp {
+            return Err(MtcError::InvalidRequest(
+                "the registration must be newer than the previous one".to_string(),
+            ));
+        }
+    }
+    let message = registration_message(
+        owner,
+        &registration.signing_key,
+        &registration.agreement_key,
+        registration.timestamp,
+    );
+    verify_signature(&signing_key, &message, &registration.signature)
+}
+
+pub fn take_key_registry() -> KeyRegistryState {
+    KEY_REGISTRY.with(|state| state.replace(KeyRegistryState::init()))
+}
+
+pub fn restore_key_registry(state: KeyRegistryState) {
+    KEY_REGISTRY.with(|key_registry| *key_registry.borrow_mut() = state);
+}
+
+/// Registers the caller's keys, or rotates them with `rotate`.
+pub async fn register_keys(registration: KeyRegistration, rotate: bool) -> Result<(), MtcError> {
+    let account = caller_account(None)?;
+    let pub_key = read_public_key().await?;
+    let address = account_to_p2wpkh_address(config::network(), &pub_key, &account).await?;
+    let now = ic_cdk::api::time();
+    KEY_REGISTRY.with(|state| state.borrow_mut().register(account.owner, address, registration, rotate, now))
+}
+
+pub fn revoke_keys() -> Result<(), MtcError> {
+    let owner = caller_account(None)?.owner;
+    let now = ic_cdk::api::time();
+    KEY_REGISTRY.with(|state| state.borrow_mut().revoke(owner, now))
+}
+
+pub fn read_active_keys(owner: &Principal) -> Option<RegisteredKeys> {
+    KEY_REGISTRY.with(|state| state.borrow().active_keys(owner).cloned())
+}
+
+pub fn read_key_registry<R>(f: impl FnOnce(&KeyRegistryState) -> R) -> R {
+    KEY_REGISTRY.with(|state| f(&state.borrow()))
+}
+
+pub fn read_key_history(owner: &Principal) -> Vec<RegisteredKeys> {
+    KEY_REGISTRY.with(|state| state.borrow().keys.get(owner).cloned().unwrap_or_default())
+}
+
+/// Returns the principal whose default account has the P2WPKH `address`,
+/// if it registered messaging keys.
+pub fn read_address_owner(address: &str) -> Option<Principal> {
+    KEY_REGISTRY.with(|state| state.borrow().addresses.get(address).copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const MINUTE_NANOS: u64 = 60 * 1_000_000_000;
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    /// Returns the keys of `seed`, registered for `owner` at `timestamp`
+    /// and signed by their own signing key.
+    fn registration(owner: &Principal, seed: u8, timestamp: u64) -> KeyRegistration {
+        let signing_key = SigningKey::from_bytes(&[seed; 32]);
+        let verifying_key = signing_key.verifying_key().to_bytes().to_vec();
+        let agreement_key = vec![seed; 32];
+        let message = registration_message(owner, &verifying_key, &agreement_key, timestamp);
+        KeyRegistration {
+            signing_key: verifying_key,
+            agreement_key,
+            timestamp,
+            signature: signing_key.sign(&message).to_bytes().to_vec(),
+        }
+    }
+
+    fn register(state: &mut KeyRegistryState, registration: KeyRegistration, rotate: bool, now: u64) -> Result<(), MtcError> {
+        state.register(owner(), "bc1qowner".to_string(), registration, rotate, now)
+    }
+
+    fn rejected(result: Result<(), MtcError>) -> bool {
+        matches!(result, Err(MtcError::InvalidRequest(_)))
+    }
+
+    #[test]
+    fn self_signed_registrations_are_active() {
+        let mut state = KeyRegistryState::init();
+        register(&mut state, registration(&owner(), 1, NOW), false, NOW).unwrap();
+        let keys = state.active_keys(&owner()).unwrap();
+        assert_eq!(keys.agreement_key, vec![1; 32]);
+        assert_eq!(keys.registered_at, NOW);
+        assert_eq!(state.addresses.get("bc1qowner"), Some(&owner()));
+    }
+
+    #[test]
+    fn registrations_with_a_bad_signature_are_rejected() {
+        let mut state = KeyRegistryState::init();
+        let mut forged = registration(&owner(), 1, NOW);
+        forged.agreement_key = vec![2; 32];
+        assert!(matches!(register(&mut state, forged, false, NOW), Err(MtcError::InvalidSignature(_))));
+        // Signed for another principal.
+        let other = registration(&Principal::from_slice(&[2; 29]), 1, NOW);
+        assert!(matches!(register(&mut state, other, false, NOW), Err(MtcError::InvalidSignature(_))));
+        let mut truncated = registration(&owner(), 1, NOW);
+        truncated.signature.pop();
+        assert!(matches!(register(&mut state, truncated, false, NOW), Err(MtcError::InvalidSignature(_))));
+        assert!(state.active_keys(&owner()).is_none());
+    }
+
+    #[test]
+    fn registrations_must_be_newer_than_the_previous_one() {
+        let mut state = KeyRegistryState::init();
+        register(&mut state, registration(&owner(), 1, NOW), false, NOW).unwrap();
+        // The same registration replayed, and one as old as it.
+        assert!(rejected(register(&mut state, registration(&owner(), 1, NOW), true, NOW + 1)));
+        assert!(rejected(register(&mut state, registration(&owner(), 2, NOW), true, NOW + 1)));
+        assert!(rejected(register(&mut state, registration(&owner(), 2, NOW - 1), true, NOW + 1)));
+        register(&mut state, registration(&owner(), 2, NOW + 1), true, NOW + 1).unwrap();
+    }
+
+    #[test]
+    fn timestamps_must_be_close_to_the_current_time() {
+        let mut state = KeyRegistryState::init();
+        let drift = MAX_CLOCK_DRIFT_NANOS;
+        assert!(rejected(register(&mut state, registration(&owner(), 1, NOW - drift - 1), false, NOW)));
+        assert!(rejected(register(&mut state, registration(&owner(), 1, NOW + drift + 1), false, NOW)));
+        register(&mut state, registration(&owner(), 1, NOW + drift), false, NOW).unwrap();
+    }
+
+    #[test]
+    fn keys_are_registered_once_and_rotated_afterwards() {
+        let mut state = KeyRegistryState::init();
+        assert!(rejected(register(&mut state, registration(&owner(), 1, NOW), true, NOW)));
+        register(&mut state, registration(&owner(), 1, NOW), false, NOW).unwrap();
+        assert!(rejected(register(&mut state, registration(&owner(), 2, NOW + 1), false, NOW + 1)));
+        register(&mut state, registration(&owner(), 2, NOW + 1), true, NOW + 1).unwrap();
+        assert_eq!(state.active_keys(&owner()).unwrap().agreement_key, vec![2; 32]);
+        assert_eq!(state.keys[&owner()][0].status, KeyStatus::Rotated { at: NOW + 1 });
+
+        // Revoked keys are registered again, not rotated.
+        state.revoke(owner(), NOW + 2).unwrap();
+        assert!(rejected(state.revoke(owner(), NOW + 3)));
+        assert!(rejected(register(&mut state, registration(&owner(), 3, NOW + 3), true, NOW + 3)));
+        register(&mut state, registration(&owner(), 3, NOW + 3), false, NOW + 3).unwrap();
+    }
+
+    #[test]
+    fn keys_at_follows_rotations() {
+        let mut state = KeyRegistryState::init();
+        register(&mut state, registration(&owner(), 1, NOW), false, NOW).unwrap();
+        register(&mut state, registration(&owner(), 2, NOW + MINUTE_NANOS), true, NOW + MINUTE_NANOS).unwrap();
+
+        let agreement_key_at = |time| state.keys_at(&owner(), time).map(|keys| keys.agreement_key[0]);
+        assert_eq!(agreement_key_at(NOW - 1), None);
+        assert_eq!(agreement_key_at(NOW), Some(1));
+        assert_eq!(agreement_key_at(NOW + MINUTE_NANOS - 1), Some(1));
+        assert_eq!(agreement_key_at(NOW + MINUTE_NANOS), Some(2));
+        assert_eq!(agreement_key_at(u64::MAX), Some(2));
+    }
+
+    #[test]
+    fn revoked_keys_are_never_returned() {
+        let mut state = KeyRegistryState::init();
+        register(&mut state, registration(&owner(), 1, NOW), false, NOW).unwrap();
+        state.revoke(owner(), NOW + MINUTE_NANOS).unwrap();
+        assert!(state.active_keys(&owner()).is_none());
+        // Not even for the time before the revocation.
+        assert!(state.keys_at(&owner(), NOW).is_none());
+        assert!(state.keys_at(&owner(), NOW + 2 * MINUTE_NANOS).is_none());
+        assert_eq!(state.keys[&owner()].len(), 1);
+    }
+}
@@ -0,0 +1,242 @@
+//! Sends messages as bitcoin transactions.
+//!
+//! A message is a transaction from the sender's P2WPKH address that pays
+//! the recipient's address a small amount, so that the recipient can find
+//! it by watching its own address, and carries the payload in an OP_RETURN
+//! output. The payload is an envelope (see `envelope`) whose body the
+//! client encrypted before submitting it, so the canister never sees the
+//! plaintext.
+//!
+//! Payloads that don't fit in one OP_RETURN output are split into
+//! fragments (see `fragment`), each sent in its own transaction of a
+//! chain, unless the taproot transport is chosen (see `inscription`).
+//!
+//! Sent messages are recorded in the sender's outbox (see `outbox`).
+use bitcoin::{script::PushBytesBuf, Amount, ScriptBuf, TxOut};
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Satoshi};
+use icrc_ledger_types::icrc1::account::Account;
+
+use crate::message::envelope::{recipient_hint, Envelope, EXT_RECEIPT};
+use crate::config;
+use crate::message::cipher::SUITE_SECP256K1_ECIES;
+use crate::message::fragment::{collect_messages, fragment, FragmentError};
+use crate::message::group::is_group_address;
+use crate::message::inscription::send_inscription;
+use crate::message::outbox::record_sent;
+use crate::message::registry::read_address_owner;
+use crate::utils::{CoinSelectionStrategy, MessageTransport, MtcError, SendMessageResponse};
+use crate::wallet::send_btc::{parse_address, send_chain, FeePolicy};
+
+/// The largest OP_RETURN payload relayed by nodes running the default
+/// policy.
+pub const MAX_PAYLOAD_SIZE: usize = 80;
+/// The amount paid to the recipient when the request doesn't set one. It
+/// is above the dust limit of every standard output type.
+pub const MESSAGE_AMOUNT: Satoshi = 546;
+
+/// Sends `payload` to `recipient` from `account` and returns the txids and
+/// the message id.
+pub async fn send_message(
+    network: BitcoinNetwork,
+    recipient: String,
+    payload: Vec<u8>,
+    amount: Option<Satoshi>,
+    transport: MessageTransport,
+    strategy: CoinSelectionStrategy,
+    account: &Account,
+) -> Result<SendMessageResponse, MtcError> {
+    let amount = amount.unwrap_or(MESSAGE_AMOUNT);
+    if amount < MESSAGE_AMOUNT {
+        return Err(MtcError::InvalidRequest(format!(
+            "the amount paid to the recipient must be at least {} satoshi",
+            MESSAGE_AMOUNT
+        )));
+    }
+    let recipient = parse_address(&recipient, network)?;
+    let hint = recipient_hint(recipient.script_pubkey().as_bytes());
+    let messages = parse_payload(&payload, transport, hint)?;
+    check_ecies_recipient(&recipient.to_string(), &messages)?;
+    let message_id = messages[0].0.clone();
+    let key_name = config::ecdsa_key_name();
+    let recipient_output = TxOut {
+        script_pubkey: recipient.script_pubkey(),
+        value: Amount::from_sat(amount),
+    };
+
+    if transport == MessageTransport::Taproot {
+        let schnorr_key_name = config::read_config(|config| config.schnorr_key_name.clone());
+        let txids = send_inscription(network, key_name, schnorr_key_name, recipient_output, &payload, strategy, account).await?;
+        // The message is in the reveal transaction.
+        let txid = txids[txids.len() - 1].clone();
+        record_sent(account, &recipient.to_string(), &txid, &txids, transport, &messages);
+        return Ok(SendMessageResponse { txid, message_id, txids });
+    }
+
+    let chain = op_return_payloads(payload, hint)?
+        .into_iter()
+        .map(|data| {
+            let data = PushBytesBuf::try_from(data).expect("OP_RETURN payloads are at most 80 bytes");
+            vec![
+                recipient_output.clone(),
+                TxOut {
+                    script_pubkey: ScriptBuf::new_op_return(data),
+                    value: Amount::ZERO,
+                },
+            ]
+        })
+        .collect();
+    let txids: Vec<String> = send_chain(network, key_name, chain, strategy, FeePolicy::default(), account)
+        .await?
+        .into_iter()
+        .map(|response| response.txid)
+        .collect();
+    let txid = txids[0].clone();
+    record_sent(account, &recipient.to_string(), &txid, &txids, transport, &messages);
+    Ok(SendMessageResponse { txid, message_id, txids })
+}
+
+/// Returns the OP_RETURN payloads `payload` is sent in: itself if it fits
+/// in one, otherwise its fragments. Payloads that need more than
+/// `MAX_FRAGMENTS` are rejected; the taproot transport carries them.
+pub fn op_return_payloads(payload: Vec<u8>, hint: [u8; 4]) -> Result<Vec<Vec<u8>>, MtcError> {
+    if payload.len() <= MAX_PAYLOAD_SIZE {
+        return Ok(vec![payload]);
+    }
+    let fragments = fragment(&payload, hint, MAX_PAYLOAD_SIZE).map_err(|err| match err {
+        FragmentError::TooLarge { .. } => {
+            MtcError::InvalidRequest(format!("{}; send it with the taproot transport", err))
+        }
+        err => MtcError::InvalidEnvelope(err.to_string()),
+    })?;
+    Ok(fragments
+        .iter()
+        .map(|fragment| fragment.encode().expect("fragments are valid envelopes"))
+        .collect())
+}
+
+/// Decodes the envelopes of `payload` and returns the messages they carry
+/// with their ids. Every envelope must be addressed to `hint`, and every
+/// message must be encrypted unless it is a receipt.
+pub fn parse_payload(payload: &[u8], transport: MessageTransport, hint: [u8; 4]) -> Result<Vec<(String, Envelope)>, MtcError> {
+    let envelopes = match transport {
+        MessageTransport::OpReturn => Envelope::decode(payload).map(|envelope| vec![envelope]),
+        MessageTransport::Taproot => Envelope::decode_all(payload),
+    }
+    .map_err(|err| MtcError::InvalidEnvelope(err.to_string()))?;
+    if envelopes.is_empty() || envelopes.iter().any(|envelope| envelope.recipient_hint != hint) {
+        return Err(MtcError::InvalidEnvelope(
+            "the recipient hint does not match the recipient address".to_string(),
+        ));
+    }
+    let messages = collect_messages(&envelopes).map_err(|err| MtcError::InvalidEnvelope(err.to_string()))?;
+    if messages.iter().any(|(_, message)| !message.is_encrypted() && !is_receipt(message)) {
+        return Err(MtcError::InvalidEnvelope(
+            "messages must be encrypted, only receipts may be sent in plaintext".to_string(),
+        ));
+    }
+    Ok(messages.into_iter().map(|(id, envelope)| (hex::encode(id), envelope)).collect())
+}
+
+/// Rejects ECIES messages to an address of a canister account, which
+/// nobody can decrypt (see `ecies`). Only the addresses of accounts that
+/// registered messaging keys and of groups are known.
+fn check_ecies_recipient(recipient: &str, messages: &[(String, Envelope)]) -> Result<(), MtcError> {
+    if !messages.iter().any(|(_, message)| message.suite == SUITE_SECP256K1_ECIES) {
+        return Ok(());
+    }
+    if read_address_owner(recipient).is_some() || is_group_address(recipient) {
+        return Err(MtcError::InvalidEnvelope(format!(
+            "{} belongs to a canister account, which can't decrypt ECIES messages",
+            recipient
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `envelope` only carries a receipt, which is sent in plaintext
+/// so that the sender of the message can check it (see `receipt`).
+fn is_receipt(envelope: &Envelope) -> bool {
+    envelope.extension(EXT_RECEIPT).is_some() && envelope.body.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::cipher::SUITE_AES256_GCM;
+    use crate::message::envelope::{Extension, FLAG_ENCRYPTED, SUITE_PLAINTEXT};
+    use crate::message::fragment::{FRAGMENT_OVERHEAD, MAX_FRAGMENTS};
+
+    const HINT: [u8; 4] = [1, 2, 3, 4];
+
+    fn envelope(encrypted: bool, extensions: Vec<Extension>, body: &[u8]) -> Envelope {
+        Envelope {
+            flags: if encrypted { FLAG_ENCRYPTED } else { 0 },
+            recipient_hint: HINT,
+            suite: if encrypted { SUITE_AES256_GCM } else { SUITE_PLAINTEXT },
+            nonce: if encrypted { vec![0; 12] } else { vec![] },
+            extensions,
+            body: body.to_vec(),
+        }
+    }
+
+    fn receipt_extension() -> Extension {
+        Extension { ext_type: EXT_RECEIPT, value: vec![0; 16] }
+    }
+
+    #[test]
+    fn encrypted_messages_are_accepted() {
+        let payload = envelope(true, vec![], b"ciphertext").encode().unwrap();
+        let messages = parse_payload(&payload, MessageTransport::OpReturn, HINT).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].1.is_encrypted());
+    }
+
+    #[test]
+    fn plaintext_messages_are_rejected() {
+        let payload = envelope(false, vec![], b"hello").encode().unwrap();
+        assert!(matches!(
+            parse_payload(&payload, MessageTransport::OpReturn, HINT),
+            Err(MtcError::InvalidEnvelope(_))
+        ));
+    }
+
+    #[test]
+    fn plaintext_receipts_are_accepted() {
+        let payload = envelope(false, vec![receipt_extension()], b"").encode().unwrap();
+        assert_eq!(parse_payload(&payload, MessageTransport::OpReturn, HINT).unwrap().len(), 1);
+        // A receipt can't smuggle a plaintext body.
+        let payload = envelope(false, vec![receipt_extension()], b"hello").encode().unwrap();
+        assert!(parse_payload(&payload, MessageTransport::OpReturn, HINT).is_err());
+    }
+
+    #[test]
+    fn one_plaintext_message_rejects_a_taproot_payload() {
+        let mut payload = envelope(true, vec![], b"ciphertext").encode().unwrap();
+        payload.extend(envelope(false, vec![], b"hello").encode().unwrap());
+        assert!(parse_payload(&payload, MessageTransport::Taproot, HINT).is_err());
+    }
+
+    #[test]
+    fn op_return_payloads_fit_in_a_relayable_chain() {
+        let chunk_size = MAX_PAYLOAD_SIZE - FRAGMENT_OVERHEAD;
+        assert_eq!(op_return_payloads(vec![0; MAX_PAYLOAD_SIZE], HINT).unwrap().len(), 1);
+        let payloads = op_return_payloads(vec![0; chunk_size * MAX_FRAGMENTS], HINT).unwrap();
+        assert_eq!(payloads.len(), MAX_FRAGMENTS);
+        assert!(payloads.iter().all(|payload| payload.len() <= MAX_PAYLOAD_SIZE));
+        assert!(matches!(
+            op_return_payloads(vec![0; chunk_size * MAX_FRAGMENTS + 1], HINT),
+            Err(MtcError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn plaintext_messages_are_rejected_after_reassembly() {
+        let payload = envelope(false, vec![], &[7; 200]).encode().unwrap();
+        let fragments: Vec<u8> = fragment(&payload, HINT, MAX_PAYLOAD_SIZE)
+            .unwrap()
+            .iter()
+            .flat_map(|fragment| fragment.encode().unwrap())
+            .collect();
+        assert!(parse_payload(&fragments, MessageTransport::Taproot, HINT).is_err());
+    }
+}
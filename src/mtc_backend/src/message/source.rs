@@ -0,0 +1,118 @@
+//! Where the inbox indexer gets raw transactions from.
+//!
+//! `bitcoin_get_utxos` only reports the outpoints paying an address, not
+//! the transactions themselves, so the indexer asks a `TransactionSource`
+//! for the raw bytes of every txid it has to parse. Whatever the source,
+//! the bytes are only accepted if they hash to the requested txid, so a
+//! source can delay indexing but never forge a message.
+use bitcoin::{consensus::deserialize, Transaction, Txid};
+use futures::future::{FutureExt, LocalBoxFuture};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs, TransformContext,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::utils::MtcError;
+
+/// The largest transaction a source returns. Standard transactions are at
+/// most 400,000 weight units, so no larger one is relayed.
+pub const MAX_RAW_TRANSACTION_SIZE: u64 = 400_000;
+/// The cycles attached to an HTTPS outcall. They cover a response of
+/// `MAX_RAW_TRANSACTION_SIZE` bytes on a 13-node subnet; the rest is
+/// refunded.
+const HTTP_OUTCALL_CYCLES: u128 = 5_000_000_000;
+
+thread_local! {
+    /// Raw transactions pushed by relayers, keyed by txid, until the
+    /// indexer consumes them.
+    static RELAYED: RefCell<HashMap<Txid, Vec<u8>>> = RefCell::default();
+}
+
+pub trait TransactionSource {
+    /// Returns the raw bytes of `txid`, or `None` if the source doesn't
+    /// have them (yet).
+    fn fetch(&self, txid: Txid) -> LocalBoxFuture<'_, Result<Option<Vec<u8>>, MtcError>>;
+}
+
+/// Returns the transaction in `bytes` if it is `txid`.
+pub fn check_transaction(txid: Txid, bytes: &[u8]) -> Option<Transaction> {
+    deserialize::<Transaction>(bytes)
+        .ok()
+        .filter(|transaction| transaction.compute_txid() == txid)
+}
+
+/// Transactions pushed to the canister with `submit_raw_transactions`.
+pub struct RelayedSource;
+
+impl TransactionSource for RelayedSource {
+    fn fetch(&self, txid: Txid) -> LocalBoxFuture<'_, Result<Option<Vec<u8>>, MtcError>> {
+        let bytes = RELAYED.with(|relayed| relayed.borrow_mut().remove(&txid));
+        futures::future::ready(Ok(bytes)).boxed_local()
+    }
+}
+
+/// Keeps the raw transactions the indexer waits for. Transactions nobody
+/// waits for are dropped, so relayers can't fill the canister's memory.
+/// Returns how many were kept.
+pub fn relay_transactions(transactions: Vec<Vec<u8>>, is_awaited: impl Fn(&Txid) -> bool) -> u32 {
+    let mut kept = 0;
+    for bytes in transactions {
+        let Ok(transaction) = deserialize::<Transaction>(&bytes) else {
+            continue;
+        };
+        let txid = transaction.compute_txid();
+        if is_awaited(&txid) {
+            RELAYED.with(|relayed| relayed.borrow_mut().insert(txid, bytes));
+            kept += 1;
+        }
+    }
+    kept
+}
+
+/// Fetches transactions from an Esplora HTTP API with HTTPS outcalls.
+pub struct EsploraSource {
+    /// The API root, e.g. `https://blockstream.info/testnet/api`.
+    pub base_url: String,
+}
+
+impl TransactionSource for EsploraSource {
+    fn fetch(&self, txid: Txid) -> LocalBoxFuture<'_, Result<Option<Vec<u8>>, MtcError>> {
+        async move {
+            let arg = CanisterHttpRequestArgument {
+                url: format!("{}/tx/{}/raw", self.base_url.trim_end_matches('/'), txid),
+                method: HttpMethod::GET,
+                body: None,
+                max_response_bytes: Some(MAX_RAW_TRANSACTION_SIZE),
+                transform: Some(TransformContext::from_name("transform_raw_transaction".to_string(), vec![])),
+                headers: vec![],
+            };
+            let (response,) = http_request(arg, HTTP_OUTCALL_CYCLES)
+                .await
+                .map_err(|err| MtcError::rejected("http_request", err))?;
+            if response.status != candid::Nat::from(200u32) {
+                return Ok(None);
+            }
+            Ok(Some(response.body))
+        }
+        .boxed_local()
+    }
+}
+
+/// Drops the headers of a response so that every replica sees the same
+/// bytes.
+pub fn transform_raw_transaction(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+/// Returns the source selected by the configuration.
+pub fn configured_source(esplora_url: Option<String>) -> Box<dyn TransactionSource> {
+    match esplora_url {
+        Some(base_url) => Box::new(EsploraSource { base_url }),
+        None => Box::new(RelayedSource),
+    }
+}
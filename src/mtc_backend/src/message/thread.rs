@@ -0,0 +1,344 @@
+//! Conversations and replies.
+//!
+//! An envelope joins a conversation with an `EXT_THREAD` extension:
+//!
+//! ```text
+//! conversation_id  16 bytes  chosen at random by whoever starts it
+//! parent           optional, the message replied to, encoded as in a
+//!                  receipt: 0 and a message id, or 1 and a txid
+//! ```
+//!
+//! The extension is authenticated but not encrypted, so anyone can tell
+//! which messages belong to the same conversation, though not what they
+//! say.
+//!
+//! Messages are indexed in the order their transactions are found, which
+//! is not the order they were written in: a reply can be mined before its
+//! parent, and the fragments of a message are reassembled whenever the
+//! last one arrives. `get_thread` therefore rebuilds the tree every time
+//! it is called, from whatever the caller's inbox and outbox hold at that
+//! point. A reply whose parent hasn't been indexed yet is listed as an
+//! orphan at the top level, and moves under its parent once it arrives.
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::message::envelope::EXT_THREAD;
+use crate::message::fragment::MESSAGE_ID_LEN;
+use crate::message::inbox::{read_inbox_state, MessageEnvelope};
+use crate::message::mailbox::{MailboxEntry, MessageView, MAX_PAGE_BYTES};
+use crate::message::outbox::read_outbox_state;
+use crate::message::receipt::MessageReference;
+use crate::utils::MtcError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub conversation_id: String,
+    pub parent: Option<MessageReference>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct ThreadEntry {
+    pub message: MessageView,
+    /// The id of the message this one replies to, if it is in the thread.
+    pub parent: Option<String>,
+    /// The number of ancestors in the thread.
+    pub depth: u32,
+    /// The message replies to one that isn't in the thread (yet).
+    pub orphan: bool,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct Thread {
+    pub conversation_id: String,
+    /// The messages in depth-first order: every message is followed by its
+    /// replies, oldest first.
+    pub messages: Vec<ThreadEntry>,
+    /// Set if the thread was cut short to fit in a response.
+    pub truncated: bool,
+}
+
+impl ThreadInfo {
+    pub fn encode(&self) -> Result<Vec<u8>, MtcError> {
+        let mut value = hex::decode(&self.conversation_id)
+            .ok()
+            .filter(|id| id.len() == MESSAGE_ID_LEN)
+            .ok_or_else(|| MtcError::InvalidRequest(format!("invalid conversation id {}", self.conversation_id)))?;
+        if let Some(parent) = &self.parent {
+            value.extend_from_slice(&parent.encode()?);
+        }
+        Ok(value)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < MESSAGE_ID_LEN {
+            return None;
+        }
+        let (conversation_id, rest) = bytes.split_at(MESSAGE_ID_LEN);
+        let parent = match rest {
+            [] => None,
+            _ => match MessageReference::decode_prefix(rest)? {
+                (parent, []) => Some(parent),
+                _ => return None,
+            },
+        };
+        Some(Self { conversation_id: hex::encode(conversation_id), parent })
+    }
+
+    /// Returns the thread information of `envelope`, if any.
+    pub fn of(envelope: &MessageEnvelope) -> Option<Self> {
+        envelope.extension(EXT_THREAD).and_then(Self::decode)
+    }
+}
+
+/// A message of the thread being rebuilt.
+struct Node {
+    view: MessageView,
+    parent_reference: Option<MessageReference>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+impl Node {
+    fn is_referenced_by(&self, reference: &MessageReference) -> bool {
+        match reference {
+            MessageReference::MessageId(id) => self.view.id.eq_ignore_ascii_case(id),
+            MessageReference::Txid(txid) => self.view.txids.iter().any(|own| own.eq_ignore_ascii_case(txid)),
+        }
+    }
+}
+
+/// Rebuilds the conversation `conversation_id` from the messages `owner`
+/// received and sent.
+pub fn get_thread(owner: &Principal, conversation_id: &str) -> Thread {
+    let mut nodes: Vec<Node> = vec![];
+    let mut ids = HashSet::new();
+    let mut add = |entry: &dyn MailboxEntry| {
+        let Some(info) = ThreadInfo::of(entry.envelope()) else {
+            return;
+        };
+        if !info.conversation_id.eq_ignore_ascii_case(conversation_id) {
+            return;
+        }
+        let view = entry.view();
+        // A message sent to oneself is in both the inbox and the outbox.
+        if ids.insert(view.id.clone()) {
+            nodes.push(Node { view, parent_reference: info.parent, parent: None, children: vec![] });
+        }
+    };
+    let account = Account::from(*owner);
+    read_inbox_state(|state| state.inboxes.iter(&account).for_each(|(_, entry)| add(&entry)));
+    read_outbox_state(|state| state.outboxes.iter(&account).for_each(|(_, entry)| add(&entry)));
+
+    // Siblings are ordered by block, unconfirmed messages last, then by
+    // the time they were indexed or sent.
+    nodes.sort_by(|a, b| {
+        let key = |node: &Node| (node.view.height.unwrap_or(u32::MAX), node.view.timestamp);
+        key(a).cmp(&key(b)).then_with(|| a.view.id.cmp(&b.view.id))
+    });
+    for index in 0..nodes.len() {
+        let parent = nodes[index].parent_reference.as_ref().and_then(|reference| {
+            nodes
+                .iter()
+                .position(|node| node.is_referenced_by(reference))
+                .filter(|parent| *parent != index)
+        });
+        nodes[index].parent = parent;
+        if let Some(parent) = parent {
+            nodes[parent].children.push(index);
+        }
+    }
+
+    // Roots come first. Messages left over afterwards are in a cycle of
+    // replies, which only a malformed client can produce; they are listed
+    // as orphans.
+    let roots = (0..nodes.len()).filter(|index| nodes[*index].parent.is_none());
+    let order: Vec<usize> = roots.chain(0..nodes.len()).collect();
+    let mut visited = vec![false; nodes.len()];
+    let mut messages = vec![];
+    let mut size = 0;
+    let mut truncated = false;
+    for root in order {
+        let mut stack = vec![(root, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            if visited[index] {
+                continue;
+            }
+            visited[index] = true;
+            let node = &nodes[index];
+            if size >= MAX_PAGE_BYTES && !messages.is_empty() {
+                truncated = true;
+                break;
+            }
+            size += node.view.envelope.body.len();
+            let parent = if depth == 0 { None } else { node.parent.map(|parent| nodes[parent].view.id.clone()) };
+            messages.push(ThreadEntry {
+                message: node.view.clone(),
+                parent,
+                depth,
+                orphan: node.parent_reference.is_some() && (depth == 0),
+            });
+            stack.extend(node.children.iter().rev().map(|child| (*child, depth + 1)));
+        }
+        if truncated {
+            break;
+        }
+    }
+    Thread { conversation_id: conversation_id.to_ascii_lowercase(), messages, truncated }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::inbox::{mutate_inbox_state, EnvelopeExtension, InboxEntry};
+    use crate::message::outbox::{mutate_outbox_state, OutboxEntry};
+    use crate::utils::{AddressKind, MessageTransport};
+
+    const CONVERSATION: &str = "0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f";
+
+    fn owner() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    fn id(n: u8) -> String {
+        format!("{:02x}", n).repeat(MESSAGE_ID_LEN)
+    }
+
+    fn txid(n: u8) -> String {
+        format!("{:02x}", n).repeat(32)
+    }
+
+    fn reply_to(n: u8) -> Option<MessageReference> {
+        Some(MessageReference::MessageId(id(n)))
+    }
+
+    fn envelope(conversation_id: &str, parent: Option<MessageReference>) -> MessageEnvelope {
+        let info = ThreadInfo { conversation_id: conversation_id.to_string(), parent };
+        MessageEnvelope {
+            flags: 1,
+            recipient_hint: vec![1, 2, 3, 4],
+            suite: 2,
+            nonce: vec![7; 12],
+            extensions: vec![EnvelopeExtension { ext_type: EXT_THREAD, value: info.encode().unwrap() }],
+            body: vec![1; 10],
+        }
+    }
+
+    fn inbox_entry(n: u8, parent: Option<MessageReference>, height: u32) -> InboxEntry {
+        InboxEntry {
+            id: id(n),
+            subaccount: None,
+            address_type: AddressKind::P2wpkh,
+            txid: txid(n),
+            txids: vec![txid(n)],
+            height,
+            sender: None,
+            transport: MessageTransport::OpReturn,
+            envelope: envelope(CONVERSATION, parent),
+            indexed_at: n as u64,
+            read: false,
+        }
+    }
+
+    /// Indexes message `n`, replying to `parent`, into the owner's inbox.
+    fn receive(n: u8, parent: Option<MessageReference>, height: u32) {
+        let entry = inbox_entry(n, parent, height);
+        mutate_inbox_state(|state| state.inboxes.push(&Account::from(owner()), entry));
+    }
+
+    /// Records message `n`, replying to `parent`, in the owner's outbox.
+    fn send(n: u8, parent: Option<MessageReference>, height: Option<u32>) {
+        let entry = OutboxEntry {
+            id: id(n),
+            subaccount: None,
+            recipient: "bc1qrecipient".to_string(),
+            txid: txid(n),
+            txids: vec![txid(n)],
+            transport: MessageTransport::OpReturn,
+            envelope: envelope(CONVERSATION, parent),
+            sent_at: n as u64,
+            height,
+            receipt: None,
+        };
+        mutate_outbox_state(|state| state.outboxes.push(&Account::from(owner()), entry));
+    }
+
+    /// Returns the id, depth and orphan flag of every message of the
+    /// thread, in order.
+    fn layout() -> Vec<(String, u32, bool)> {
+        let thread = get_thread(&owner(), CONVERSATION);
+        assert!(!thread.truncated);
+        thread.messages.into_iter().map(|entry| (entry.message.id, entry.depth, entry.orphan)).collect()
+    }
+
+    #[test]
+    fn thread_info_round_trips() {
+        for parent in [None, reply_to(1), Some(MessageReference::Txid(txid(2)))] {
+            let info = ThreadInfo { conversation_id: CONVERSATION.to_string(), parent };
+            assert_eq!(ThreadInfo::decode(&info.encode().unwrap()), Some(info));
+        }
+    }
+
+    #[test]
+    fn malformed_thread_info_is_rejected() {
+        let info = ThreadInfo { conversation_id: CONVERSATION.to_string(), parent: reply_to(1) };
+        let encoded = info.encode().unwrap();
+        assert_eq!(ThreadInfo::decode(&encoded[..MESSAGE_ID_LEN - 1]), None);
+        assert_eq!(ThreadInfo::decode(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(ThreadInfo::decode(&[encoded.as_slice(), &[0]].concat()), None);
+        let mut unknown_reference = encoded.clone();
+        unknown_reference[MESSAGE_ID_LEN] = 2;
+        assert_eq!(ThreadInfo::decode(&unknown_reference), None);
+        let short_id = ThreadInfo { conversation_id: "0f0f".to_string(), parent: None };
+        assert!(matches!(short_id.encode(), Err(MtcError::InvalidRequest(_))));
+    }
+
+    #[test]
+    fn replies_wait_as_orphans_for_their_parent() {
+        receive(2, reply_to(1), 101);
+        assert_eq!(layout(), vec![(id(2), 0, true)]);
+
+        receive(1, None, 100);
+        assert_eq!(layout(), vec![(id(1), 0, false), (id(2), 1, false)]);
+        let thread = get_thread(&owner(), CONVERSATION);
+        assert_eq!(thread.messages[1].parent, Some(id(1)));
+    }
+
+    #[test]
+    fn threads_are_rebuilt_whatever_the_indexing_order() {
+        // A reply by txid to message 3, indexed first.
+        receive(5, Some(MessageReference::Txid(txid(3))), 103);
+        // An unconfirmed reply the owner sent.
+        send(4, reply_to(1), None);
+        receive(3, reply_to(1), 102);
+        receive(2, reply_to(1), 101);
+        receive(1, None, 100);
+
+        // Siblings are ordered by block, unconfirmed messages last.
+        assert_eq!(
+            layout(),
+            vec![(id(1), 0, false), (id(2), 1, false), (id(3), 1, false), (id(5), 2, false), (id(4), 1, false)]
+        );
+    }
+
+    #[test]
+    fn other_conversations_and_duplicates_are_left_out() {
+        receive(1, None, 100);
+        // A message to oneself is in both the inbox and the outbox.
+        send(1, None, Some(100));
+        let other = InboxEntry { envelope: envelope("1e1e1e1e1e1e1e1e1e1e1e1e1e1e1e1e", None), ..inbox_entry(2, None, 101) };
+        mutate_inbox_state(|state| state.inboxes.push(&Account::from(owner()), other));
+        assert_eq!(layout(), vec![(id(1), 0, false)]);
+        assert!(get_thread(&Principal::from_slice(&[2; 29]), CONVERSATION).messages.is_empty());
+    }
+
+    #[test]
+    fn reply_cycles_are_listed_as_orphans() {
+        receive(1, reply_to(2), 100);
+        receive(2, reply_to(1), 101);
+        // A message replying to itself.
+        receive(3, reply_to(3), 102);
+        assert_eq!(layout(), vec![(id(3), 0, true), (id(1), 0, true), (id(2), 1, false)]);
+    }
+}
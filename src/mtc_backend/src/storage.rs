@@ -0,0 +1,431 @@
+//! Keeps the canister state across upgrades.
+//!
+//! Stable memory is split into virtual memories by a `MemoryManager`. The
+//! collections that grow with every message or transaction (the inboxes
+//! and outboxes, the delegation log and the send history) are stable
+//! structures in memories of their own, which stay in place across
+//! upgrades. The rest of the state is small: `pre_upgrade` writes it into
+//! `UPGRADE_MEMORY` as a single Candid-encoded snapshot and `post_upgrade`
+//! reads it back. The snapshot is wrapped in a version tag: sections added
+//! after a version was released are `Option`s, so snapshots written by
+//! older builds keep decoding, while incompatible changes get a new
+//! variant together with a migration from the previous one.
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, StableBTreeMap, Storable};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::auth::{restore_delegations, take_delegations, Delegation};
+use crate::config::{self, MtcConfig};
+use crate::message::group::{restore_group_state, take_group_state, GroupState};
+use crate::message::inbox::{restore_pending_messages, take_pending_messages, PendingMessages};
+use crate::message::registry::{restore_key_registry, take_key_registry, KeyRegistryState};
+use crate::utils::{restore_public_key, take_public_key, ECDSAPublicKey};
+use crate::wallet::state::{restore_wallet_state, take_wallet_state, WalletState};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Holds the snapshot written by `pre_upgrade`.
+const UPGRADE_MEMORY: MemoryId = MemoryId::new(0);
+pub const INBOX_MEMORY: MemoryId = MemoryId::new(1);
+pub const INDEXED_MEMORY: MemoryId = MemoryId::new(2);
+pub const OUTBOX_MEMORY: MemoryId = MemoryId::new(3);
+pub const SENDERS_MEMORY: MemoryId = MemoryId::new(4);
+pub const DELEGATION_LOG_MEMORY: MemoryId = MemoryId::new(5);
+pub const SEND_HISTORY_MEMORY: MemoryId = MemoryId::new(6);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+/// Returns the virtual memory `id`.
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+/// Stores a value Candid-encoded, without a bound on its size.
+pub struct Candid<T>(pub T);
+
+impl<T: CandidType + DeserializeOwned> Storable for Candid<T> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(&self.0).expect("values kept in stable memory encode"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(candid::decode_one(&bytes).expect("stable memory holds values written by `to_bytes`"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The length of an encoded account: the length of the principal, the
+/// principal padded to 29 bytes, and the subaccount.
+const ACCOUNT_KEY_LEN: usize = 1 + 29 + 32;
+
+fn encode_account(owner: &Principal, subaccount: &Subaccount) -> Vec<u8> {
+    let owner = owner.as_slice();
+    let mut bytes = Vec::with_capacity(ACCOUNT_KEY_LEN + 32);
+    bytes.push(owner.len() as u8);
+    bytes.extend_from_slice(owner);
+    bytes.resize(1 + 29, 0);
+    bytes.extend_from_slice(subaccount);
+    bytes
+}
+
+fn decode_account(bytes: &[u8]) -> (Principal, Subaccount, &[u8]) {
+    let owner = Principal::from_slice(&bytes[1..1 + bytes[0] as usize]);
+    let subaccount = bytes[30..ACCOUNT_KEY_LEN].try_into().expect("the subaccount is 32 bytes long");
+    (owner, subaccount, &bytes[ACCOUNT_KEY_LEN..])
+}
+
+/// Orders the values of `StableLists` by account, then newest first.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ListKey {
+    owner: Principal,
+    subaccount: Subaccount,
+    /// `u64::MAX` minus the position of the value.
+    rank: u64,
+}
+
+impl ListKey {
+    fn new(account: &Account, position: u64) -> Self {
+        Self { owner: account.owner, subaccount: *account.effective_subaccount(), rank: u64::MAX - position }
+    }
+
+    fn position(&self) -> u64 {
+        u64::MAX - self.rank
+    }
+}
+
+impl Storable for ListKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = encode_account(&self.owner, &self.subaccount);
+        bytes.extend_from_slice(&self.rank.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (owner, subaccount, rank) = decode_account(&bytes);
+        let rank = u64::from_be_bytes(rank.try_into().expect("the rank is 8 bytes long"));
+        Self { owner, subaccount, rank }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: ACCOUNT_KEY_LEN as u32 + 8, is_fixed_size: true };
+}
+
+/// Keys a transaction by the account it was indexed for.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TxidKey {
+    owner: Principal,
+    subaccount: Subaccount,
+    txid: [u8; 32],
+}
+
+impl TxidKey {
+    pub fn new(account: &Account, txid: [u8; 32]) -> Self {
+        Self { owner: account.owner, subaccount: *account.effective_subaccount(), txid }
+    }
+}
+
+impl Storable for TxidKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = encode_account(&self.owner, &self.subaccount);
+        bytes.extend_from_slice(&self.txid);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (owner, subaccount, txid) = decode_account(&bytes);
+        Self { owner, subaccount, txid: txid.try_into().expect("the txid is 32 bytes long") }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: ACCOUNT_KEY_LEN as u32 + 32, is_fixed_size: true };
+}
+
+/// Append-only lists of `T` in stable memory, one per account. Positions
+/// count from 0 in the order the values were pushed, and lists are read
+/// newest first.
+pub struct StableLists<T: CandidType + DeserializeOwned> {
+    map: StableBTreeMap<ListKey, Candid<T>, Memory>,
+}
+
+impl<T: CandidType + DeserializeOwned> StableLists<T> {
+    pub fn init(memory_id: MemoryId) -> Self {
+        Self { map: StableBTreeMap::init(memory(memory_id)) }
+    }
+
+    /// Returns the number of values in the list of `account`.
+    pub fn len(&self, account: &Account) -> u64 {
+        self.iter(account).next().map_or(0, |(position, _)| position + 1)
+    }
+
+    /// Appends `value` to the list of `account` and returns its position.
+    pub fn push(&mut self, account: &Account, value: T) -> u64 {
+        let position = self.len(account);
+        self.map.insert(ListKey::new(account, position), Candid(value));
+        position
+    }
+
+    /// Overwrites the value at `position`, which must exist.
+    pub fn set(&mut self, account: &Account, position: u64, value: T) {
+        let previous = self.map.insert(ListKey::new(account, position), Candid(value));
+        debug_assert!(previous.is_some(), "set past the end of a list");
+    }
+
+    /// Returns the values of `account` with their positions, newest first.
+    pub fn iter(&self, account: &Account) -> impl Iterator<Item = (u64, T)> + '_ {
+        self.iter_before(account, u64::MAX)
+    }
+
+    /// Returns the values of `account` before position `end`, newest
+    /// first.
+    pub fn iter_before(&self, account: &Account, end: u64) -> impl Iterator<Item = (u64, T)> + '_ {
+        let range = (
+            std::ops::Bound::Excluded(ListKey::new(account, end)),
+            std::ops::Bound::Included(ListKey::new(account, 0)),
+        );
+        self.map.range(range).map(|(key, value)| (key.position(), value.0))
+    }
+
+    /// Returns the first value of `account`, newest first, that matches
+    /// `predicate`.
+    pub fn find(&self, account: &Account, predicate: impl Fn(&T) -> bool) -> Option<(u64, T)> {
+        self.iter(account).find(|(_, value)| predicate(value))
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum StableState {
+    V1(StateV1),
+}
+
+/// The heap state. The collections that grow with every message or
+/// transaction live in stable structures instead.
+#[derive(CandidType, Deserialize)]
+pub struct StateV1 {
+    pub config: MtcConfig,
+    pub ecdsa_public_key: Option<ECDSAPublicKey>,
+    pub wallet: WalletState,
+    /// Delegations keyed by the principal that granted them.
+    pub delegations: HashMap<Principal, Vec<Delegation>>,
+    pub key_registry: KeyRegistryState,
+    pub pending_messages: PendingMessages,
+    pub groups: GroupState,
+}
+
+impl StableState {
+    /// Returns the state in the latest schema, migrating older versions.
+    pub fn into_latest(self) -> StateV1 {
+        match self {
+            StableState::V1(state) => state,
+        }
+    }
+}
+
+/// Moves the heap state out of the canister into a snapshot.
+pub fn take_state() -> StableState {
+    StableState::V1(StateV1 {
+        config: config::read_config(|config| config.clone()),
+        ecdsa_public_key: take_public_key(),
+        wallet: take_wallet_state(),
+        delegations: take_delegations(),
+        key_registry: take_key_registry(),
+        pending_messages: take_pending_messages(),
+        groups: take_group_state(),
+    })
+}
+
+/// Replaces the heap state with the contents of a snapshot.
+pub fn restore_state(state: StableState) {
+    let state = state.into_latest();
+    config::replace_config(state.config);
+    restore_public_key(state.ecdsa_public_key);
+    restore_wallet_state(state.wallet);
+    restore_delegations(state.delegations);
+    restore_key_registry(state.key_registry);
+    restore_pending_messages(state.pending_messages);
+    restore_group_state(state.groups);
+}
+
+/// Writes the snapshot, prefixed with its length, into `UPGRADE_MEMORY`.
+pub fn save_to_stable_memory() {
+    let bytes = candid::encode_one(take_state()).expect("the snapshot encodes");
+    let mut memory = memory(UPGRADE_MEMORY);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|()| writer.write(&bytes))
+        .expect("stable memory can grow to hold the snapshot");
+}
+
+/// Restores the snapshot written by `pre_upgrade`. Returns `false`, and
+/// leaves the heap state as it is, if there is none: the canister was
+/// installed by a build that didn't write one.
+pub fn restore_from_stable_memory() -> bool {
+    let memory = memory(UPGRADE_MEMORY);
+    if memory.size() == 0 {
+        return false;
+    }
+    let mut len = [0; 8];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+    memory.read(len.len() as u64, &mut bytes);
+    match candid::decode_one(&bytes) {
+        Ok(state) => restore_state(state),
+        Err(err) => ic_cdk::trap(&format!("the snapshot in stable memory doesn't decode: {}", err)),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{self, DelegationAction};
+    use crate::message::inbox::{self, InboxEntry, MessageEnvelope};
+    use crate::message::registry::read_address_owner;
+    use crate::utils::{AddressKind, MessageTransport};
+    use crate::wallet::history::{self, SentTransaction};
+    use crate::wallet::state::{get_all_utxo_from_wallet, JsonOutPoint, UtxoStatus, WalletUtxo};
+    use bitcoin::{hashes::Hash, OutPoint, Txid};
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn envelope(body: &[u8]) -> MessageEnvelope {
+        MessageEnvelope {
+            flags: 1,
+            recipient_hint: vec![1, 2, 3, 4],
+            suite: 1,
+            nonce: vec![7; 12],
+            extensions: vec![],
+            body: body.to_vec(),
+        }
+    }
+
+    fn inbox_entry(id: &str) -> InboxEntry {
+        InboxEntry {
+            id: id.to_string(),
+            subaccount: None,
+            address_type: AddressKind::P2wpkh,
+            txid: "aa".repeat(32),
+            txids: vec!["aa".repeat(32)],
+            height: 100,
+            sender: None,
+            transport: MessageTransport::OpReturn,
+            envelope: envelope(id.as_bytes()),
+            indexed_at: 1,
+            read: false,
+        }
+    }
+
+    #[test]
+    fn stable_lists_are_read_newest_first() {
+        let mut lists = StableLists::<String>::init(INBOX_MEMORY);
+        let alice = Account::from(principal(1));
+        let bob = Account { owner: principal(1), subaccount: Some([1; 32]) };
+        for value in ["a", "b", "c"] {
+            lists.push(&alice, value.to_string());
+        }
+        lists.push(&bob, "x".to_string());
+        lists.set(&alice, 1, "B".to_string());
+
+        assert_eq!(lists.len(&alice), 3);
+        assert_eq!(lists.len(&bob), 1);
+        assert_eq!(lists.len(&Account::from(principal(2))), 0);
+        let all: Vec<_> = lists.iter(&alice).collect();
+        assert_eq!(all, vec![(2, "c".to_string()), (1, "B".to_string()), (0, "a".to_string())]);
+        let older: Vec<_> = lists.iter_before(&alice, 2).map(|(position, _)| position).collect();
+        assert_eq!(older, vec![1, 0]);
+        assert_eq!(lists.iter_before(&alice, 0).count(), 0);
+    }
+
+    #[test]
+    fn empty_stable_memory_has_no_snapshot() {
+        assert!(!restore_from_stable_memory());
+        assert_eq!(config::network(), BitcoinNetwork::Testnet);
+    }
+
+    #[test]
+    fn upgrade_round_trips_a_populated_state() {
+        let owner = principal(1);
+        let delegate = principal(2);
+        let account = Account::from(owner);
+        let mut config = MtcConfig::new(BitcoinNetwork::Regtest);
+        config.fee_percentile = 75;
+        config::replace_config(config);
+        restore_public_key(Some(ECDSAPublicKey { public_key: vec![2; 33], chain_code: vec![3; 32] }));
+        let outpoint = JsonOutPoint::from(OutPoint::new(Txid::from_byte_array([4; 32]), 1));
+        let utxo = WalletUtxo {
+            value: 5_000,
+            height: 7,
+            status: UtxoStatus::Reserved { txid: "dd".repeat(32), reserved_at: 2 },
+        };
+        let mut wallet = WalletState::init();
+        wallet.utxos.insert((account, AddressKind::P2wpkh), HashMap::from([(outpoint.clone(), utxo)]));
+        restore_wallet_state(wallet);
+        let mut key_registry = KeyRegistryState::init();
+        key_registry.addresses.insert("bcrt1qowner".to_string(), owner);
+        restore_key_registry(key_registry);
+        let mut groups = GroupState::init();
+        groups.created = 3;
+        restore_group_state(groups);
+        auth::mutate_delegation_state(|state| state.grant(owner, delegate, Some(5), 1));
+        inbox::mutate_inbox_state(|state| {
+            state.inboxes.push(&account, inbox_entry("01"));
+            state.inboxes.push(&account, inbox_entry("02"));
+            state.awaiting.insert("bb".repeat(32));
+        });
+        history::mutate_send_history(|history| {
+            history.record(&account, SentTransaction {
+                txid: "ee".repeat(32),
+                transaction: vec![],
+                input_values: vec![5_000],
+                payments: 1,
+                fee: 200,
+                fee_rate: 1_000,
+                sent_at: 3,
+                replaces: None,
+                replaced_by: None,
+            })
+        });
+
+        save_to_stable_memory();
+        // An upgrade drops the heap.
+        config::replace_config(MtcConfig::new(BitcoinNetwork::Testnet));
+        assert!(crate::utils::cached_public_key().is_none());
+        assert!(get_all_utxo_from_wallet(&account, AddressKind::P2wpkh).is_empty());
+        assert!(auth::mutate_delegation_state(|state| state.delegations.is_empty()));
+        assert!(restore_from_stable_memory());
+
+        let config = config::read_config(|config| config.clone());
+        assert_eq!(config.network, BitcoinNetwork::Regtest);
+        assert_eq!(config.fee_percentile, 75);
+        assert_eq!(crate::utils::cached_public_key().map(|key| key.public_key), Some(vec![2; 33]));
+        let utxos = get_all_utxo_from_wallet(&account, AddressKind::P2wpkh);
+        assert_eq!(utxos[&outpoint].value, 5_000);
+        assert_eq!(utxos[&outpoint].status, UtxoStatus::Reserved { txid: "dd".repeat(32), reserved_at: 2 });
+        assert_eq!(read_address_owner("bcrt1qowner"), Some(owner));
+        assert_eq!(take_group_state().created, 3);
+        auth::mutate_delegation_state(|state| {
+            assert!(state.is_delegate(&owner, &delegate, 4));
+            let log: Vec<_> = state.log.iter(&account).map(|(_, event)| event.action).collect();
+            assert_eq!(log, vec![DelegationAction::Granted]);
+        });
+        inbox::mutate_inbox_state(|state| {
+            let ids: Vec<_> = state.inboxes.iter(&account).map(|(_, entry)| entry.id).collect();
+            assert_eq!(ids, vec!["02", "01"]);
+            assert!(state.awaiting.contains(&"bb".repeat(32)));
+        });
+        let sent = history::read_send_history(|history| history.find(&account, &"ee".repeat(32)));
+        assert_eq!(sent.map(|sent| sent.fee), Some(200));
+    }
+}
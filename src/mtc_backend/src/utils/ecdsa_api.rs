@@ -1,35 +1,117 @@
 use crate::utils::*;
 use candid::Principal;
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
 use std::cell::RefCell;
+use std::time::Duration;
 use ic_cdk::api::management_canister::ecdsa::{EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyResponse, SignWithEcdsaArgument, SignWithEcdsaResponse};
 use ic_cdk::api::management_canister::ecdsa::{ecdsa_public_key, sign_with_ecdsa};
 use ic_cdk::api::management_canister::ecdsa::EcdsaPublicKeyArgument;
-// The fee for the `sign_with_ecdsa` endpoint using the test key.
-/// Represents an error from a management canister call, such as
-/// `sign_with_ecdsa` or `bitcoin_send_transaction`.
+
+/// The delay before the first background retry after a failed fetch. It
+/// doubles on every failure up to `MAX_KEY_FETCH_RETRY_DELAY`.
+const KEY_FETCH_RETRY_DELAY: Duration = Duration::from_secs(60);
+const MAX_KEY_FETCH_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+type KeyFetch = Shared<LocalBoxFuture<'static, Result<ECDSAPublicKey, MtcError>>>;
 
 thread_local! {
     static KEY: RefCell<Option<ECDSAPublicKey>> = RefCell::default();
+    /// The fetch in flight, shared by every caller waiting for the key.
+    static KEY_FETCH: RefCell<Option<KeyFetch>> = RefCell::default();
+}
+
+pub fn take_public_key() -> Option<ECDSAPublicKey> {
+    KEY.with(|key_state| key_state.borrow_mut().take())
+}
+
+pub fn restore_public_key(public_key: Option<ECDSAPublicKey>) {
+    KEY.with(|key_state| *key_state.borrow_mut() = public_key);
+}
+
+/// Returns the master key if it has been fetched already.
+pub fn cached_public_key() -> Option<ECDSAPublicKey> {
+    KEY.with(|key_state| key_state.borrow().clone())
+}
 
-  
+/// Whether the master key was fetched, or is being fetched, with the
+/// configured key name.
+pub fn is_public_key_requested() -> bool {
+    cached_public_key().is_some() || KEY_FETCH.with(|fetch| fetch.borrow().is_some())
 }
 
-pub async fn write_public_key(public_key: &ECDSAPublicKey) {
-    KEY.with(|key_state| *key_state.borrow_mut() = Some(public_key.clone()));
+/// Returns the master key, fetching it on first use. Concurrent callers
+/// share a single fetch, and a failed fetch is attempted again by the
+/// next caller.
+pub async fn read_public_key() -> Result<ECDSAPublicKey, MtcError> {
+    if let Some(key) = cached_public_key() {
+        return Ok(key);
+    }
+    let fetch = KEY_FETCH.with(|fetch| {
+        fetch
+            .borrow_mut()
+            .get_or_insert_with(|| fetch_public_key().boxed_local().shared())
+            .clone()
+    });
+    fetch.await
 }
 
-pub async fn read_public_key() -> ECDSAPublicKey {
-    KEY.with(|key_state| {let key = key_state.borrow().clone();
-        key.unwrap()
+/// Fetches the master key once. A failed fetch is not retried here, so
+/// that a caller isn't kept waiting: the timer started by
+/// `schedule_public_key_fetch` retries with backoff, and the next caller
+/// tries again.
+async fn fetch_public_key() -> Result<ECDSAPublicKey, MtcError> {
+    let key_name = crate::config::ecdsa_key_name();
+    let result = match get_ecdsa_public_key(key_name, vec![vec![]]).await {
+        Ok(response) => validate_public_key(ECDSAPublicKey {
+            public_key: response.public_key,
+            chain_code: response.chain_code,
+        }),
+        Err(err) => Err(err),
+    };
+    if let Ok(key) = &result {
+        KEY.with(|key_state| *key_state.borrow_mut() = Some(key.clone()));
     }
-)
+    KEY_FETCH.with(|fetch| *fetch.borrow_mut() = None);
+    result
+}
+
+fn validate_public_key(key: ECDSAPublicKey) -> Result<ECDSAPublicKey, MtcError> {
+    if key.public_key.len() != 33 {
+        return Err(MtcError::InvalidPublicKey(format!(
+            "expected a 33 bytes long SEC1 compressed key, got {} bytes",
+            key.public_key.len()
+        )));
+    }
+    if key.chain_code.len() != 32 {
+        return Err(MtcError::InvalidPublicKey(format!(
+            "expected a 32 bytes long chain code, got {} bytes",
+            key.chain_code.len()
+        )));
+    }
+    Ok(key)
+}
+
+/// Fetches the master key in the background right after install or
+/// upgrade, so that the first caller doesn't pay for it.
+pub fn schedule_public_key_fetch() {
+    schedule_public_key_fetch_in(Duration::ZERO);
+}
+
+fn schedule_public_key_fetch_in(delay: Duration) {
+    ic_cdk_timers::set_timer(delay, move || {
+        ic_cdk::spawn(async move {
+            if read_public_key().await.is_err() {
+                schedule_public_key_fetch_in((delay * 2).clamp(KEY_FETCH_RETRY_DELAY, MAX_KEY_FETCH_RETRY_DELAY));
+            }
+        })
+    });
 }
 
 /// Fetches the ECDSA public key of the canister.
 pub async fn get_ecdsa_public_key(
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
-) -> Result<EcdsaPublicKeyResponse, String> {
+) -> Result<EcdsaPublicKeyResponse, MtcError> {
     // Retrieve the public key of this canister at the given derivation path
     // from the ECDSA API.
     let arg = EcdsaPublicKeyArgument {
@@ -42,7 +124,7 @@ pub async fn get_ecdsa_public_key(
     };
     match ecdsa_public_key(arg).await {
         Ok(ecdsa_key) => Ok(ecdsa_key.0),
-        Err(err) => Err(err.1)
+        Err(err) => Err(MtcError::rejected("ecdsa_public_key", err))
     }
 }
 
@@ -50,7 +132,7 @@ pub async fn get_sign_with_ecdsa(
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
     message_hash: Vec<u8>
-) -> Result<SignWithEcdsaResponse, String> {
+) -> Result<SignWithEcdsaResponse, MtcError> {
     // Retrieve the public key of this canister at the given derivation path
     // from the ECDSA API.
     let arg = SignWithEcdsaArgument {
@@ -61,30 +143,21 @@ pub async fn get_sign_with_ecdsa(
             name: key_name,
         },
     };
-    match sign_with_ecdsa(arg).await {
-        Ok(ecdsa_key) => Ok(ecdsa_key.0),
-        Err(err) => Err(err.1)
+    let signature = match sign_with_ecdsa(arg).await {
+        Ok(signature) => signature.0,
+        Err((code, message)) => {
+            return Err(MtcError::SigningFailed(format!(
+                "sign_with_ecdsa was rejected ({:?}): {}",
+                code, message
+            )))
+        }
+    };
+    // `sec1_to_der` expects a 64 bytes long signature.
+    if signature.signature.len() != 64 {
+        return Err(MtcError::SigningFailed(format!(
+            "expected a 64 bytes long signature, got {} bytes",
+            signature.signature.len()
+        )));
     }
+    Ok(signature)
 }
-
-/// Initializes the Minter ECDSA public key. This function must be called
-/// before any endpoint runs its logic.
-pub async fn init_ecdsa_public_key() -> ECDSAPublicKey {
-    // if let Some(key) = read_state(|s| s.ecdsa_public_key.clone()) {
-    //     return key;
-    // };
-    // let key_name = read_state(|s| s.ecdsa_key_name.clone());
-    let key_name =  "test_key_1".to_string();
-    // log!(P1, "Fetching the ECDSA public key {}", &key_name);
-    let ecdsa_public_key =
-        match get_ecdsa_public_key(key_name, vec![vec![]]).await {
-            Ok(key) => key,
-            Err(_) => EcdsaPublicKeyResponse::default(),
-        };
-    let key = ECDSAPublicKey {
-        public_key: ecdsa_public_key.public_key,
-        chain_code: ecdsa_public_key.chain_code,
-    };
-    write_public_key(&key).await;
-    key
-}
\ No newline at end of file
@@ -1,7 +1,8 @@
 use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
 
-use crate::SCHNORR_CANISTER;
+use crate::config;
+use crate::utils::MtcError;
 
 
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
@@ -35,14 +36,14 @@ struct SignWithSchnorrReply {
 }
 
 
+fn schnorr_canister_id() -> Principal {
+    config::read_config(|config| config.schnorr_canister)
+}
+
 /// Returns the Schnorr public key of this canister at the given derivation path.
-pub async fn schnorr_public_key(key_name: &str, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+pub async fn schnorr_public_key(key_name: &str, derivation_path: Vec<Vec<u8>>) -> Result<Vec<u8>, MtcError> {
 
-    let canister_id = SCHNORR_CANISTER.with(|schnorr_canister| {
-        ic_cdk::println!("CANISTER_ID_SCHNORR_CANISTER: {:?}", &schnorr_canister.borrow());
-        
-        Principal::from_text(schnorr_canister.borrow().as_str()).unwrap()
-    });
+    let canister_id = schnorr_canister_id();
 
     
 
@@ -59,8 +60,8 @@ pub async fn schnorr_public_key(key_name: &str, derivation_path: Vec<Vec<u8>>) -
     )
     .await;
     match res {
-        Ok(schnnor) => schnnor.0.public_key,
-        Err(_) => vec![]
+        Ok(schnnor) => Ok(schnnor.0.public_key),
+        Err(err) => Err(MtcError::rejected("schnorr_public_key", err))
     }
 }
 
@@ -68,11 +69,9 @@ pub async fn sign_with_schnorr(
     key_name: &str,
     derivation_path: Vec<Vec<u8>>,
     message: Vec<u8>,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, MtcError> {
 
-    let canister_id = SCHNORR_CANISTER.with(|schnorr_canister| {
-        Principal::from_text(schnorr_canister.borrow().as_str()).unwrap()
-    });
+    let canister_id = schnorr_canister_id();
 
     let res: Result<(SignWithSchnorrReply,), _> = ic_cdk::call(
         canister_id,
@@ -87,7 +86,10 @@ pub async fn sign_with_schnorr(
     )
     .await;
     match res {
-        Ok(sig) => sig.0.signature,
-        Err(_) => vec![],
+        Ok(sig) => Ok(sig.0.signature),
+        Err((code, message)) => Err(MtcError::SigningFailed(format!(
+            "sign_with_schnorr was rejected ({:?}): {}",
+            code, message
+        ))),
     }
 }
\ No newline at end of file
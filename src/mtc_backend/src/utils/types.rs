@@ -1,5 +1,8 @@
 use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
+use icrc_ledger_types::icrc1::account::Subaccount;
 use serde::Serialize;
 
 #[derive(CandidType, Deserialize)]
@@ -34,13 +37,169 @@ pub struct  SignWithECDSA {
 }
 #[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
 pub struct  SendBtcRequest {
-    pub pid: String,
+    pub subaccount: Option<Subaccount>,
+    /// Spend from this principal's account instead of the caller's. The
+    /// principal must have delegated to the caller.
+    pub on_behalf_of: Option<Principal>,
     pub amount: u64,
     pub dst_address: String,
+    /// Defaults to branch and bound.
+    pub coin_selection: Option<CoinSelectionStrategy>,
+    /// An explicit fee rate, in satoshi per vbyte.
+    pub sat_per_vbyte: Option<u64>,
+    /// Pay this percentile of recent fee rates instead of the configured
+    /// one. Can't be combined with `sat_per_vbyte`.
+    pub fee_percentile: Option<u8>,
+    /// The transaction is not sent if its fee would be higher, in satoshi.
+    pub max_fee: Option<u64>,
 }
 
+/// The kind of address an account receives funds on. Both kinds are derived
+/// from the same account key, but their outputs are tracked separately.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressKind {
+    #[serde(rename="p2wpkh")]
+    P2wpkh,
+    #[serde(rename="p2pkh")]
+    P2pkh,
+}
+
+/// How the wallet outputs funding a transaction are picked. See
+/// `wallet::coin_selection`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSelectionStrategy {
+    /// Avoids a change output where possible, otherwise largest-first.
+    #[default]
+    #[serde(rename="branch_and_bound")]
+    BranchAndBound,
+    #[serde(rename="largest_first")]
+    LargestFirst,
+    #[serde(rename="oldest_first")]
+    OldestFirst,
+    #[serde(rename="single_random_draw")]
+    SingleRandomDraw,
+}
+
+/// Selects the UTXO set of one account and address kind.
 #[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
-pub struct  UpdateUtxoRequest {
-    pub address: String,
+pub struct  UtxoRequest {
+    pub subaccount: Option<Subaccount>,
+    pub on_behalf_of: Option<Principal>,
+    pub address_type: AddressKind,
 }
 
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct  DelegateRequest {
+    pub delegate: Principal,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct  SendBtcResponse {
+    pub txid: String,
+    pub transaction: Vec<u8>,
+    /// The fee the transaction pays, in satoshi.
+    pub fee: u64,
+    /// The fee rate it was built with, in millisatoshi per vbyte.
+    pub fee_rate: u64,
+}
+
+/// Replaces a pending transaction with one paying a higher fee rate.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct BumpFeeRequest {
+    pub subaccount: Option<Subaccount>,
+    pub on_behalf_of: Option<Principal>,
+    pub txid: String,
+    /// The new fee rate, in satoshi per vbyte.
+    pub sat_per_vbyte: u64,
+}
+
+/// How a message is written on chain.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageTransport {
+    /// In OP_RETURN outputs, split into fragments if needed.
+    #[default]
+    #[serde(rename="op_return")]
+    OpReturn,
+    /// In the witness of a taproot reveal transaction.
+    #[serde(rename="taproot")]
+    Taproot,
+}
+
+/// Sends an encrypted message to the owner of `recipient`.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct SendMessageRequest {
+    pub subaccount: Option<Subaccount>,
+    pub on_behalf_of: Option<Principal>,
+    /// The bitcoin address the message is sent to.
+    pub recipient: String,
+    /// An MTC envelope, already encrypted by the client. Envelopes longer
+    /// than 80 bytes are sent in at most 25 fragments; longer ones need the
+    /// taproot transport. With the taproot transport, the payload may hold
+    /// several envelopes back to back.
+    pub payload: Vec<u8>,
+    /// The amount paid to the recipient, in satoshi. Defaults to the
+    /// smallest amount that isn't dust.
+    pub amount: Option<u64>,
+    /// Defaults to OP_RETURN outputs.
+    pub transport: Option<MessageTransport>,
+    /// Defaults to branch and bound.
+    pub coin_selection: Option<CoinSelectionStrategy>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct SendMessageResponse {
+    /// The first transaction that carries the message.
+    pub txid: String,
+    /// The id of the message, or of the first one if a taproot payload
+    /// holds several.
+    pub message_id: String,
+    /// Every transaction sent for the message: one per fragment, or the
+    /// commit and the reveal transaction.
+    pub txids: Vec<String>,
+}
+
+/// The error returned by every endpoint of the canister.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum MtcError {
+    InvalidPrincipal(String),
+    InvalidAddress(String),
+    /// The address is valid, but not for the network the canister uses.
+    WrongNetwork { address: String, network: BitcoinNetwork },
+    InsufficientFunds { available: u64, required: u64 },
+    /// The canister's ECDSA master key has not been fetched yet.
+    KeyNotInitialized,
+    /// The management canister returned a malformed ECDSA master key.
+    InvalidPublicKey(String),
+    /// A call to the management canister (or another system canister) was
+    /// rejected.
+    ManagementCanisterRejected { method: String, code: i32, message: String },
+    SigningFailed(String),
+    /// An ed25519 signature supplied by the caller does not verify.
+    InvalidSignature(String),
+    /// The caller is not allowed to act for the requested account.
+    Unauthorized(String),
+    /// The selected UTXOs were taken by a concurrent transaction.
+    UtxosReserved,
+    InvalidRequest(String),
+    /// The message payload is not a well-formed MTC envelope.
+    InvalidEnvelope(String),
+    /// A signed transaction failed verification and was not broadcast.
+    InvalidTransaction { txid: String, input: u32, reason: String },
+    /// The fee would exceed the cap set by the caller.
+    FeeTooHigh { fee: u64, max_fee: u64 },
+    /// Data returned by a system canister, or kept in the canister's
+    /// state, doesn't parse.
+    MalformedData(String),
+}
+
+impl MtcError {
+    pub fn rejected(method: &str, (code, message): (RejectionCode, String)) -> Self {
+        MtcError::ManagementCanisterRejected {
+            method: method.to_string(),
+            code: code as i32,
+            message,
+        }
+    }
+}
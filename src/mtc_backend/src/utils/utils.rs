@@ -3,10 +3,10 @@ use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
 use ic_crypto_secp256k1::{DerivationIndex, DerivationPath, PublicKey};
 use ic_management_canister_types::ECDSAPublicKeyResponse;
-use crate::utils::ECDSAPublicKey;
+use crate::utils::{ECDSAPublicKey, MtcError};
 
 /// Returns a valid extended BIP-32 derivation path from an Account (Principal + subaccount)
-pub fn derive_public_key(ecdsa_public_key: &ECDSAPublicKey, account: &Account) -> ECDSAPublicKeyResponse {
+pub fn derive_public_key(ecdsa_public_key: &ECDSAPublicKey, account: &Account) -> Result<ECDSAPublicKeyResponse, MtcError> {
     let path = DerivationPath::new(
         derivation_path(account)
             .into_iter()
@@ -14,19 +14,21 @@ pub fn derive_public_key(ecdsa_public_key: &ECDSAPublicKey, account: &Account) -
             .collect(),
     );
     let pk = PublicKey::deserialize_sec1(&ecdsa_public_key.public_key)
-        .expect("Failed to parse ECDSA public key");
+        .map_err(|err| MtcError::InvalidPublicKey(format!("{:?}", err)))?;
     let chain_code: [u8; 32] = ecdsa_public_key
         .chain_code
         .clone()
         .try_into()
-        .expect("Incorrect chain code size");
+        .map_err(|chain_code: Vec<u8>| {
+            MtcError::InvalidPublicKey(format!("the chain code is {} bytes long", chain_code.len()))
+        })?;
     let (derived_public_key, derived_chain_code) =
         pk.derive_subkey_with_chain_code(&path, &chain_code);
         
-    ECDSAPublicKeyResponse {
+    Ok(ECDSAPublicKeyResponse {
         public_key: derived_public_key.serialize_sec1(true),
         chain_code: derived_chain_code.to_vec(),
-    }
+    })
 }
 
 
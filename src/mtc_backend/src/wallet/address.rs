@@ -3,7 +3,7 @@ use icrc_ledger_types::icrc1::account::Account;
 
 use crate::{
     utils::{
-        derive_public_key, ripemd160, sha256, ECDSAPublicKey
+        derive_public_key, ripemd160, sha256, ECDSAPublicKey, MtcError
     },
 };
 /// Derives a Bitcoin address for the specified account and converts it into
@@ -12,11 +12,11 @@ pub async fn account_to_p2wpkh_address(
     network: BitcoinNetwork,
     ecdsa_public_key: &ECDSAPublicKey,
     account: &Account,
-) -> String {
-    network_and_public_key_to_p2wpkh(
+) -> Result<String, MtcError> {
+    Ok(network_and_public_key_to_p2wpkh(
         network,
-        &derive_public_key(&ecdsa_public_key, account).public_key,
-    )
+        &derive_public_key(ecdsa_public_key, account)?.public_key,
+    ))
 }
 
 
@@ -26,11 +26,11 @@ pub async fn account_to_p2pkh_address(
     network: BitcoinNetwork,
     ecdsa_public_key: &ECDSAPublicKey,
     account: &Account,
-) -> String {
-    network_and_public_key_to_p2pkh(
+) -> Result<String, MtcError> {
+    Ok(network_and_public_key_to_p2pkh(
         network,
-        &derive_public_key(&ecdsa_public_key, account).public_key,
-    )
+        &derive_public_key(ecdsa_public_key, account)?.public_key,
+    ))
 }
 
 /// Calculates the p2wpkh address as described in [BIP-0173](https://github.com/bitcoin/bips/blob/master/bip-0173.mediawiki).
@@ -41,33 +41,21 @@ pub async fn account_to_p2pkh_address(
 pub fn network_and_public_key_to_p2wpkh(network: BitcoinNetwork, public_key: &[u8]) -> String {
     assert_eq!(public_key.len(), 33);
     assert!(public_key[0] == 0x02 || public_key[0] == 0x03);
-    match network {
-        BitcoinNetwork::Testnet =>     encode_bech32_tb(&ripemd160(&sha256(public_key))),
-        BitcoinNetwork::Mainnet =>     encode_bech32_bc(&ripemd160(&sha256(public_key))),
-        BitcoinNetwork::Regtest => todo!(),
-    }
-}
-
-fn encode_bech32_bc(hash: &[u8]) -> String 
-{
-    use bech32::Hrp;
-
-    // use bech32::Bech32;
-    // use bech32::encode;
-    use bech32::segwit::encode_v0;
-    let hrp = Hrp::parse_unchecked("bc");
-    encode_v0(hrp, &hash).expect("failed to encode")
+    let hrp = match network {
+        BitcoinNetwork::Mainnet => "bc",
+        BitcoinNetwork::Testnet => "tb",
+        BitcoinNetwork::Regtest => "bcrt",
+    };
+    encode_bech32(hrp, &ripemd160(&sha256(public_key)))
 }
 
-fn encode_bech32_tb(hash: &[u8]) -> String 
+fn encode_bech32(hrp: &str, hash: &[u8]) -> String 
 {
     use bech32::Hrp;
 
-    // use bech32::Bech32;
-    // use bech32::encode;
     use bech32::segwit::encode_v0;
-    let hrp = Hrp::parse_unchecked("tb");
-    encode_v0(hrp, &hash).expect("failed to encode")
+    let hrp = Hrp::parse_unchecked(hrp);
+    encode_v0(hrp, hash).expect("failed to encode")
 }
 
 
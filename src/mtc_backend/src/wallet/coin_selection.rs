@@ -0,0 +1,357 @@
+//! Picks the wallet outputs that fund a transaction.
+//!
+//! Every strategy implements `CoinSelector` and works on effective values:
+//! the value of an output minus the fee of spending it. Outputs that are
+//! worth less than that fee are never selected.
+//!
+//! * `BranchAndBound` searches for inputs that pay the outputs and the fee
+//!   without a change output, wasting at most what the change would have
+//!   cost. If there is no such set, it selects largest-first.
+//! * `LargestFirst` spends the fewest, largest outputs.
+//! * `OldestFirst` spends the outputs with the most confirmations first,
+//!   consolidating old coins.
+//! * `SingleRandomDraw` spends outputs in random order, which leaks less
+//!   about the wallet than a deterministic order.
+use ic_cdk::api::management_canister::main::raw_rand;
+use sha2::{Digest, Sha256};
+
+use crate::utils::{CoinSelectionStrategy, MtcError};
+use crate::wallet::state::JsonOutPoint;
+
+/// How many branches the branch-and-bound search visits at most.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// A wallet output that may fund a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub outpoint: JsonOutPoint,
+    pub value: u64,
+    /// The height of the block the output was created in, 0 while it is
+    /// unconfirmed.
+    pub height: u32,
+}
+
+/// What a selection has to pay for. All values are in satoshi.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectionTarget {
+    /// The value of the outputs the transaction pays.
+    pub amount: u64,
+    /// Value that has to come back as change on top of the fee. A
+    /// transaction with a reserve always has a change output.
+    pub reserve: u64,
+    /// The fee of the transaction without its inputs and change output.
+    pub base_fee: u64,
+    /// The fee every input adds.
+    pub input_fee: u64,
+    /// The fee a change output adds.
+    pub change_fee: u64,
+    /// Change below this value is not worth an output and is left to the
+    /// fee instead.
+    pub dust_limit: u64,
+}
+
+/// The inputs picked by a `CoinSelector`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub inputs: Vec<Candidate>,
+    /// The fee the transaction pays, including any change left to it.
+    pub fee: u64,
+    /// The value of the change output, 0 if there is none.
+    pub change: u64,
+}
+
+pub trait CoinSelector {
+    /// Picks outputs from `candidates` that pay for `target`.
+    fn select(&self, candidates: &[Candidate], target: &SelectionTarget) -> Result<Selection, MtcError>;
+}
+
+/// Returns the selector implementing `strategy`. Random draws are seeded
+/// from the management canister.
+pub async fn selector(strategy: CoinSelectionStrategy) -> Result<Box<dyn CoinSelector>, MtcError> {
+    Ok(match strategy {
+        CoinSelectionStrategy::BranchAndBound => Box::new(BranchAndBound),
+        CoinSelectionStrategy::LargestFirst => Box::new(LargestFirst),
+        CoinSelectionStrategy::OldestFirst => Box::new(OldestFirst),
+        CoinSelectionStrategy::SingleRandomDraw => {
+            let (randomness,) = raw_rand().await.map_err(|err| MtcError::rejected("raw_rand", err))?;
+            let seed = Sha256::digest(&randomness).into();
+            Box::new(SingleRandomDraw { seed })
+        }
+    })
+}
+
+pub struct BranchAndBound;
+
+pub struct LargestFirst;
+
+pub struct OldestFirst;
+
+pub struct SingleRandomDraw {
+    pub seed: [u8; 32],
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, candidates: &[Candidate], target: &SelectionTarget) -> Result<Selection, MtcError> {
+        // A reserve needs change, so there is nothing to search for.
+        if target.reserve == 0 {
+            let pool = by_value(spendable(candidates, target));
+            if let Some(inputs) = search_changeless(&pool, target) {
+                let total: u64 = inputs.iter().map(|candidate| candidate.value).sum();
+                return Ok(Selection { inputs, fee: total - target.amount, change: 0 });
+            }
+        }
+        LargestFirst.select(candidates, target)
+    }
+}
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, candidates: &[Candidate], target: &SelectionTarget) -> Result<Selection, MtcError> {
+        accumulate(by_value(spendable(candidates, target)), target)
+    }
+}
+
+impl CoinSelector for OldestFirst {
+    fn select(&self, candidates: &[Candidate], target: &SelectionTarget) -> Result<Selection, MtcError> {
+        let mut pool = spendable(candidates, target);
+        // Unconfirmed outputs are the youngest of all.
+        pool.sort_by(|a, b| {
+            let height = |candidate: &Candidate| if candidate.height == 0 { u32::MAX } else { candidate.height };
+            height(a)
+                .cmp(&height(b))
+                .then(b.value.cmp(&a.value))
+                .then(a.outpoint.cmp(&b.outpoint))
+        });
+        accumulate(pool, target)
+    }
+}
+
+impl CoinSelector for SingleRandomDraw {
+    fn select(&self, candidates: &[Candidate], target: &SelectionTarget) -> Result<Selection, MtcError> {
+        let mut pool = spendable(candidates, target);
+        pool.sort_by(|a, b| a.outpoint.cmp(&b.outpoint));
+        // Fisher-Yates, drawing from a hash chain over the seed.
+        for (round, index) in (1..pool.len()).rev().enumerate() {
+            let digest = Sha256::new().chain_update(self.seed).chain_update((round as u64).to_be_bytes()).finalize();
+            let draw = u64::from_be_bytes(digest[..8].try_into().unwrap());
+            pool.swap(index, (draw % (index as u64 + 1)) as usize);
+        }
+        accumulate(pool, target)
+    }
+}
+
+/// Returns the candidates that are worth more than the fee of spending
+/// them.
+fn spendable(candidates: &[Candidate], target: &SelectionTarget) -> Vec<Candidate> {
+    candidates
+        .iter()
+        .filter(|candidate| candidate.value > target.input_fee)
+        .cloned()
+        .collect()
+}
+
+/// Sorts `pool` by value, largest first.
+fn by_value(mut pool: Vec<Candidate>) -> Vec<Candidate> {
+    pool.sort_by(|a, b| b.value.cmp(&a.value).then(a.outpoint.cmp(&b.outpoint)));
+    pool
+}
+
+/// Takes outputs from `pool` in order until they pay for `target`.
+fn accumulate(pool: Vec<Candidate>, target: &SelectionTarget) -> Result<Selection, MtcError> {
+    let mut inputs = Vec::new();
+    for candidate in pool {
+        inputs.push(candidate);
+        if let Some(selection) = complete(&inputs, target) {
+            return Ok(selection);
+        }
+    }
+    Err(MtcError::InsufficientFunds {
+        available: inputs.iter().map(|candidate| candidate.value).sum(),
+        required: target.amount + target.reserve + target.base_fee + target.input_fee * inputs.len() as u64,
+    })
+}
+
+/// Returns the selection spending `inputs` if they pay for `target`. The
+/// change is dropped if it would be dust.
+fn complete(inputs: &[Candidate], target: &SelectionTarget) -> Option<Selection> {
+    let total: u64 = inputs.iter().map(|candidate| candidate.value).sum();
+    let fee = target.base_fee + target.input_fee * inputs.len() as u64;
+    let change = total.checked_sub(target.amount + fee + target.change_fee);
+    if let Some(change) = change.filter(|change| *change >= target.dust_limit.max(target.reserve)) {
+        return Some(Selection { inputs: inputs.to_vec(), fee: fee + target.change_fee, change });
+    }
+    if target.reserve == 0 && total >= target.amount + fee {
+        return Some(Selection { inputs: inputs.to_vec(), fee: total - target.amount, change: 0 });
+    }
+    None
+}
+
+/// Searches `pool`, sorted by value, for inputs whose effective value pays
+/// for `target` with less excess than a change output would cost. The
+/// depth-first search includes each output before trying without it and
+/// keeps the set with the least excess.
+fn search_changeless(pool: &[Candidate], target: &SelectionTarget) -> Option<Vec<Candidate>> {
+    let effective: Vec<u64> = pool.iter().map(|candidate| candidate.value - target.input_fee).collect();
+    let lower = target.amount + target.base_fee;
+    // Spending the change later costs another input.
+    let upper = lower + target.change_fee + target.input_fee;
+
+    let mut available: u64 = effective.iter().sum();
+    let mut value = 0;
+    // Whether each of the first `included.len()` outputs is included.
+    let mut included: Vec<bool> = Vec::new();
+    let mut best: Option<(u64, Vec<bool>)> = None;
+    for _ in 0..BNB_MAX_TRIES {
+        let backtrack = if value + available < lower || value > upper {
+            true
+        } else if value >= lower {
+            let excess = value - lower;
+            if !matches!(&best, Some((best_excess, _)) if *best_excess <= excess) {
+                best = Some((excess, included.clone()));
+            }
+            if excess == 0 {
+                break;
+            }
+            true
+        } else {
+            false
+        };
+
+        if !backtrack {
+            let next = effective[included.len()];
+            available -= next;
+            value += next;
+            included.push(true);
+            continue;
+        }
+        // Go back to the last included output and try without it. An
+        // output worth the same as one just tried without leads to the
+        // same sets, so it is skipped as well.
+        let mut resumed = false;
+        while let Some(was_included) = included.pop() {
+            let index = included.len();
+            if was_included {
+                value -= effective[index];
+                included.push(false);
+                while included.len() < pool.len() && effective[included.len()] == effective[index] {
+                    available -= effective[included.len()];
+                    included.push(false);
+                }
+                resumed = true;
+                break;
+            }
+            available += effective[index];
+        }
+        if !resumed {
+            break;
+        }
+    }
+
+    best.map(|(_, included)| {
+        pool.iter()
+            .zip(included)
+            .filter(|(_, included)| *included)
+            .map(|(candidate, _)| candidate.clone())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{hashes::Hash, OutPoint, Txid};
+
+    fn candidate(id: u8, value: u64, height: u32) -> Candidate {
+        Candidate {
+            outpoint: JsonOutPoint::from(OutPoint::new(Txid::from_byte_array([id; 32]), 0)),
+            value,
+            height,
+        }
+    }
+
+    /// Effective values 9_900, 24_900, 6_900, 2_900 and 49_900.
+    fn wallet() -> Vec<Candidate> {
+        vec![
+            candidate(1, 10_000, 100),
+            candidate(2, 25_000, 50),
+            candidate(3, 7_000, 0),
+            candidate(4, 3_000, 10),
+            candidate(5, 50_000, 200),
+        ]
+    }
+
+    fn target(amount: u64) -> SelectionTarget {
+        SelectionTarget { amount, reserve: 0, base_fee: 200, input_fee: 100, change_fee: 50, dust_limit: 546 }
+    }
+
+    fn ids(selection: &Selection) -> Vec<u8> {
+        selection.inputs.iter().map(|candidate| candidate.outpoint.txid()[0]).collect()
+    }
+
+    #[test]
+    fn branch_and_bound_finds_changeless_inputs() {
+        // 24_900 + 6_900 pays 31_600 and the 200 base fee exactly.
+        let selection = BranchAndBound.select(&wallet(), &target(31_600)).unwrap();
+        assert_eq!(ids(&selection), vec![2, 3]);
+        assert_eq!(selection.fee, 400);
+        assert_eq!(selection.change, 0);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_largest_first() {
+        let selection = BranchAndBound.select(&wallet(), &target(30_000)).unwrap();
+        assert_eq!(selection, LargestFirst.select(&wallet(), &target(30_000)).unwrap());
+        assert_eq!(ids(&selection), vec![5]);
+        assert_eq!(selection.fee, 350);
+        assert_eq!(selection.change, 19_650);
+    }
+
+    #[test]
+    fn branch_and_bound_keeps_change_for_a_reserve() {
+        let selection = BranchAndBound.select(&wallet(), &SelectionTarget { reserve: 5_000, ..target(31_600) }).unwrap();
+        assert_eq!(ids(&selection), vec![5]);
+        assert_eq!(selection.fee, 350);
+        assert_eq!(selection.change, 18_050);
+    }
+
+    #[test]
+    fn largest_first_spends_the_largest_outputs() {
+        let selection = LargestFirst.select(&wallet(), &target(55_000)).unwrap();
+        assert_eq!(ids(&selection), vec![5, 2]);
+        assert_eq!(selection.fee, 450);
+        assert_eq!(selection.change, 19_550);
+    }
+
+    #[test]
+    fn oldest_first_spends_unconfirmed_outputs_last() {
+        let selection = OldestFirst.select(&wallet(), &target(30_000)).unwrap();
+        assert_eq!(ids(&selection), vec![4, 2, 1]);
+        assert_eq!(selection.fee, 550);
+        assert_eq!(selection.change, 7_450);
+    }
+
+    #[test]
+    fn single_random_draw_is_determined_by_its_seed() {
+        let selection = SingleRandomDraw { seed: [8; 32] }.select(&wallet(), &target(30_000)).unwrap();
+        assert_eq!(ids(&selection), vec![1, 4, 2]);
+        assert_eq!(selection.fee, 550);
+        assert_eq!(selection.change, 7_450);
+        assert_eq!(selection, SingleRandomDraw { seed: [8; 32] }.select(&wallet(), &target(30_000)).unwrap());
+    }
+
+    #[test]
+    fn dust_change_is_left_to_the_fee() {
+        let selection = LargestFirst.select(&[candidate(1, 90, 1), candidate(2, 1_000, 1)], &target(500)).unwrap();
+        // The 90 satoshi output costs more to spend than it is worth.
+        assert_eq!(ids(&selection), vec![2]);
+        assert_eq!(selection.fee, 500);
+        assert_eq!(selection.change, 0);
+    }
+
+    #[test]
+    fn insufficient_funds_report_what_was_missing() {
+        assert_eq!(
+            LargestFirst.select(&wallet(), &target(100_000)),
+            Err(MtcError::InsufficientFunds { available: 95_000, required: 100_700 })
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Keeps the transactions each account broadcast.
+//!
+//! The signed transaction is stored with the values of the outputs it
+//! spends, so that a pending one can be rebuilt with a higher fee (see
+//! `send_btc::bump_fee`). A replaced transaction stays in the history,
+//! linked to the one that replaced it. The history is kept in stable
+//! memory.
+use bitcoin::{consensus::deserialize, Transaction};
+use candid::{CandidType, Deserialize};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Serialize;
+use std::cell::RefCell;
+
+use crate::storage::{StableLists, SEND_HISTORY_MEMORY};
+
+thread_local! {
+    static SEND_HISTORY: RefCell<SendHistory> = RefCell::new(SendHistory::init());
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct SentTransaction {
+    pub txid: String,
+    /// The signed transaction.
+    pub transaction: Vec<u8>,
+    /// The values of the outputs the inputs spend, in input order.
+    pub input_values: Vec<u64>,
+    /// The number of outputs paid to recipients. An output after them is
+    /// the change.
+    pub payments: u32,
+    pub fee: u64,
+    /// In millisatoshi per vbyte.
+    pub fee_rate: u64,
+    pub sent_at: u64,
+    /// The transaction this one replaced with a higher fee.
+    pub replaces: Option<String>,
+    /// The transaction that replaced this one with a higher fee.
+    pub replaced_by: Option<String>,
+}
+
+impl SentTransaction {
+    pub fn decode(&self) -> Transaction {
+        deserialize(&self.transaction).expect("sent transactions are well-formed")
+    }
+}
+
+pub struct SendHistory {
+    /// The transactions of each account.
+    pub sent: StableLists<SentTransaction>,
+}
+
+impl SendHistory {
+    pub fn init() -> Self {
+        Self { sent: StableLists::init(SEND_HISTORY_MEMORY) }
+    }
+
+    pub fn record(&mut self, account: &Account, sent: SentTransaction) {
+        self.sent.push(account, sent);
+    }
+
+    pub fn find(&self, account: &Account, txid: &str) -> Option<SentTransaction> {
+        self.sent
+            .find(account, |sent| sent.txid.eq_ignore_ascii_case(txid))
+            .map(|(_, sent)| sent)
+    }
+
+    /// Returns whether a transaction of `account` that wasn't replaced
+    /// spends an output of `txid`.
+    pub fn has_descendants(&self, account: &Account, txid: &str) -> bool {
+        self.sent
+            .find(account, |sent| {
+                sent.replaced_by.is_none()
+                    && sent
+                        .decode()
+                        .input
+                        .iter()
+                        .any(|input| input.previous_output.txid.to_string() == txid)
+            })
+            .is_some()
+    }
+
+    /// Links the transaction `old_txid` to its replacement `new_txid`.
+    pub fn replace(&mut self, account: &Account, old_txid: &str, new_txid: &str) {
+        let changed: Vec<(u64, SentTransaction)> = self
+            .sent
+            .iter(account)
+            .filter_map(|(position, mut entry)| {
+                if entry.txid == old_txid {
+                    entry.replaced_by = Some(new_txid.to_string());
+                } else if entry.txid == new_txid {
+                    entry.replaces = Some(old_txid.to_string());
+                } else {
+                    return None;
+                }
+                Some((position, entry))
+            })
+            .collect();
+        for (position, entry) in changed {
+            self.sent.set(account, position, entry);
+        }
+    }
+}
+
+pub fn read_send_history<R>(f: impl FnOnce(&SendHistory) -> R) -> R {
+    SEND_HISTORY.with(|state| f(&state.borrow()))
+}
+
+pub fn mutate_send_history<R>(f: impl FnOnce(&mut SendHistory) -> R) -> R {
+    SEND_HISTORY.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Returns the transactions `account` sent, newest first.
+pub fn read_sent_transactions(account: &Account) -> Vec<SentTransaction> {
+    read_send_history(|history| history.sent.iter(account).map(|(_, sent)| sent).collect())
+}
@@ -1,3 +1,7 @@
 pub mod address;
+pub mod coin_selection;
+pub mod history;
 pub mod state;
-pub mod send_btc;
\ No newline at end of file
+pub mod send_btc;
+pub mod verify;
+pub mod weight;
@@ -1,16 +1,19 @@
 //! A demo of a very bare-bones bitcoin "wallet".
 //!
 //! The wallet here showcases how bitcoin addresses can be be computed
-//! and how bitcoin transactions can be signed. It is missing several
-//! pieces that any production-grade wallet would have, including:
-//!
-//! * Support for address types that aren't P2PKH.
-//! * Caching spent UTXOs so that they are not reused in future transactions.
-//! * Option to set the fee.
-use std::{collections::HashMap, str::FromStr};
+//! and how bitcoin transactions can be signed.
+use std::str::FromStr;
 
 use crate::{
-    wallet::state::{JsonOutPoint, get_all_utxo_from_wallet}, 
+    config::{self, to_bitcoin_network},
+    wallet::coin_selection::{selector, Candidate, CoinSelector, Selection, SelectionTarget},
+    wallet::history::{mutate_send_history, read_send_history, SentTransaction},
+    wallet::state::{
+        JsonOutPoint, get_available_candidates_from_wallet, get_reserved_utxo_from_wallet, release_wallet_utxo,
+        reserve_wallet_utxo, transfer_wallet_utxo,
+    },
+    wallet::verify::verify_transaction,
+    wallet::weight::{dust_limit, fee_for_weight, output_weight, transaction_weight, P2WPKH_INPUT_WEIGHT, P2WPKH_OUTPUT_WEIGHT},
     utils::{read_public_key, get_sign_with_ecdsa, ECDSAPublicKey}, 
 };
 use bitcoin::{
@@ -26,7 +29,6 @@ use bitcoin::{
     AddressType, 
     Amount, 
     CompressedPublicKey, 
-    Network, 
     OutPoint,
     Script,  
     Transaction, 
@@ -43,12 +45,18 @@ use ic_cdk::
         MillisatoshiPerByte, 
         Satoshi, 
         SendTransactionRequest, 
-}, ecdsa::{sign_with_ecdsa, SignWithEcdsaResponse}};
+}};
 use icrc_ledger_types::icrc1::account::Account;
 
 use crate::utils::*;
 // use crate::ecdsa_api::{DerivationPath};
 const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
+/// Nodes running the default policy don't relay a transaction with more
+/// than 24 unconfirmed ancestors, so a chain is at most this long.
+pub const MAX_CHAIN_LENGTH: usize = 25;
+/// The default incremental relay fee of 1 satoshi per vbyte, which a
+/// replacement pays for its own size on top of the fee of the original.
+const INCREMENTAL_RELAY_FEE_RATE: MillisatoshiPerByte = 1_000;
 
 
 
@@ -58,6 +66,17 @@ const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
 
 
 
+/// How the fee rate of a send is chosen and how much it may pay. By
+/// default, the configured percentile of recent fee rates is paid.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FeePolicy {
+    /// An explicit rate, in satoshi per vbyte.
+    pub sat_per_vbyte: Option<u64>,
+    /// The percentile of recent fee rates to pay.
+    pub percentile: Option<u8>,
+    /// The most the transactions of a send may pay together, in satoshi.
+    pub max_fee: Option<Satoshi>,
+}
 
 /// Sends a transaction to the network that transfers the given amount to the
 /// given destination, where the source of the funds is the canister itself
@@ -67,193 +86,580 @@ pub async fn send(
     key_name: String,
     dst_address: String,
     amount: Satoshi,
+    strategy: CoinSelectionStrategy,
+    fees: FeePolicy,
     account: &Account
-) -> (Vec<u8>, String) {
-    // Get fee percentiles from previous transactions to estimate our own fee.
-    let fee_percentiles = match  bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest{network}).await {
-        Ok(fee) => fee.0,
-        Err(_) => vec![],
-    };
-    let own_public_key = read_public_key().await;
-    let fee_per_byte = if fee_percentiles.is_empty() {
-        // There are no fee percentiles. This case can only happen on a regtest
-        // network where there are no non-coinbase transactions. In this case,
-        // we use a default of 2000 millisatoshis/byte (i.e. 2 satoshi/byte)
-        2000
-    } else {
-        // Choose the 50th percentile for sending fees.
-        fee_percentiles[50]
-    };
+) -> Result<SendBtcResponse, MtcError> {
+    let dst_address = parse_address(&dst_address, network)?;
+    let outputs = vec![TxOut {
+        script_pubkey: dst_address.script_pubkey(),
+        value: Amount::from_sat(amount),
+    }];
+    send_outputs(network, key_name, outputs, strategy, fees, account).await
+}
+
+/// Funds, signs and broadcasts a transaction paying `outputs` from the
+/// P2WPKH address of `account`. Change goes back to the same address.
+pub async fn send_outputs(
+    network: BitcoinNetwork,
+    key_name: String,
+    outputs: Vec<TxOut>,
+    strategy: CoinSelectionStrategy,
+    fees: FeePolicy,
+    account: &Account
+) -> Result<SendBtcResponse, MtcError> {
+    let mut responses = send_chain(network, key_name, vec![outputs], strategy, fees, account).await?;
+    Ok(responses.remove(0))
+}
+
+/// Funds, signs and broadcasts one transaction per entry of `chain`. The
+/// first transaction is funded from the wallet of `account` and keeps
+/// enough change to pay for the rest; every later one spends the change
+/// of the one before it, so the transactions confirm in order.
+///
+/// A chain of more than one transaction is only funded by confirmed
+/// outputs: the ancestors of an unconfirmed one are unknown, and would
+/// count towards the `MAX_CHAIN_LENGTH` of the last transaction.
+///
+/// If a broadcast fails, the transactions before it stay broadcast and the
+/// error is returned.
+pub async fn send_chain(
+    network: BitcoinNetwork,
+    key_name: String,
+    chain: Vec<Vec<TxOut>>,
+    strategy: CoinSelectionStrategy,
+    fees: FeePolicy,
+    account: &Account
+) -> Result<Vec<SendBtcResponse>, MtcError> {
+    let fee_per_byte = get_fee_per_byte(network, &fees).await?;
+    let signed_chain = sign_chain(network, key_name, chain, fee_per_byte, strategy, fees.max_fee, account).await?;
+    broadcast_chain(network, signed_chain, account).await
+}
 
-    // Fetch our public key, P2wPKH address, and UTXOs.
-    let own_utxos = get_all_utxo_from_wallet();
+/// Transactions signed by `sign_chain`. The wallet outputs they spend stay
+/// reserved under `reserved_txid` until they are broadcast or released.
+pub struct SignedChain {
+    pub transactions: Vec<Transaction>,
+    /// The outputs spent by each transaction, in input order.
+    pub prevouts: Vec<Vec<TxOut>>,
+    /// The fee of each transaction.
+    pub fees: Vec<Satoshi>,
+    /// The number of outputs of each transaction paid to recipients.
+    pub payments: Vec<u32>,
+    pub fee_rate: MillisatoshiPerByte,
+    pub reserved_txid: String,
+}
+
+impl SignedChain {
+    /// Makes the wallet outputs of a chain that won't be broadcast
+    /// spendable again.
+    pub fn release(&self, account: &Account) {
+        release_wallet_utxo(account, AddressKind::P2wpkh, &self.reserved_txid);
+    }
+}
+
+/// Builds and signs the transactions of `send_chain` without broadcasting
+/// them. The first transaction is funded by the outputs `strategy` picks.
+/// Nothing is signed if the transactions would pay more than `max_fee`
+/// together.
+pub async fn sign_chain(
+    network: BitcoinNetwork,
+    key_name: String,
+    chain: Vec<Vec<TxOut>>,
+    fee_per_byte: MillisatoshiPerByte,
+    strategy: CoinSelectionStrategy,
+    max_fee: Option<Satoshi>,
+    account: &Account
+) -> Result<SignedChain, MtcError> {
+    if chain.is_empty() {
+        return Err(MtcError::InvalidRequest("nothing to send".to_string()));
+    }
+    if chain.len() > MAX_CHAIN_LENGTH {
+        return Err(MtcError::InvalidRequest(format!(
+            "a chain of {} transactions is longer than the {} nodes relay",
+            chain.len(),
+            MAX_CHAIN_LENGTH
+        )));
+    }
+    // Nodes don't relay transactions with dust outputs.
+    for output in chain.iter().flatten() {
+        let limit = dust_limit(&output.script_pubkey);
+        if output.value.to_sat() < limit {
+            return Err(MtcError::InvalidRequest(format!(
+                "an output of {} satoshi is below the dust limit of {} satoshi",
+                output.value.to_sat(),
+                limit
+            )));
+        }
+    }
+    let own_public_key = read_public_key().await?;
+    let selector = selector(strategy).await?;
+
+    // Fetch our public key, P2wPKH address, and UTXOs. Only the outputs of
+    // the sending account can be signed for, so no other set is considered.
+    let mut own_utxos = get_available_candidates_from_wallet(account, AddressKind::P2wpkh);
+    if chain.len() > 1 {
+        own_utxos.retain(|candidate| candidate.height > 0);
+    }
     // ic_cdk::println!("own_utxo: {:?}", &own_utxos);
-    let ecdsa_key = read_public_key().await;
-    let derive_pubkey = derive_public_key(&ecdsa_key, &account).public_key;
-    let compress_key = CompressedPublicKey::from_slice(&derive_pubkey).unwrap();
-    let own_address = Address::p2wpkh(&compress_key, Network::Testnet);
-    let dst_address = Address::from_str(&dst_address).unwrap().require_network(Network::Testnet).unwrap();
-    // Build the transaction that sends `amount` to the destination address.
-    let transaction = build_transaction(
-        &own_public_key,
-        &own_address,
-        &own_utxos,
-        &dst_address,
-        amount,
-        fee_per_byte,
-        account
-    )
-    .await;
+    let own_address = account_p2wpkh_address(network, &own_public_key, account)?;
+
+    // Build the transactions that pay the outputs.
+    let mut transactions = Vec::with_capacity(chain.len());
+    let mut fees = Vec::with_capacity(chain.len());
+    let mut payments = Vec::with_capacity(chain.len());
+    for (index, outputs) in chain.iter().enumerate() {
+        let reserve = chain[index + 1..]
+            .iter()
+            .map(|outputs| chained_cost(outputs, fee_per_byte))
+            .sum();
+        let (transaction, selection) = build_transaction(
+            &own_address,
+            &own_utxos,
+            outputs,
+            reserve,
+            fee_per_byte,
+            selector.as_ref(),
+        )?;
+        let prevouts = spent_outputs(&selection, &own_address);
+        if reserve > 0 {
+            // The change output comes right after the requested outputs.
+            let change = transaction
+                .output
+                .get(outputs.len())
+                .ok_or(MtcError::InsufficientFunds { available: 0, required: reserve })?;
+            let outpoint = OutPoint { txid: transaction.compute_txid(), vout: outputs.len() as u32 };
+            own_utxos = vec![Candidate { outpoint: JsonOutPoint::from(outpoint), value: change.value.to_sat(), height: 0 }];
+        }
+        transactions.push((transaction, prevouts));
+        fees.push(selection.fee);
+        payments.push(outputs.len() as u32);
+    }
+    if let Some(max_fee) = max_fee {
+        let fee: Satoshi = fees.iter().sum();
+        if fee > max_fee {
+            return Err(MtcError::FeeTooHigh { fee, max_fee });
+        }
+    }
+
+    // The txid of a segwit transaction does not cover the witness, so the
+    // inputs can be reserved under their final txid before signing. This
+    // keeps a concurrent send from picking them while we wait for
+    // signatures. Only the first transaction spends wallet outputs.
+    let first_txid = transactions[0].0.compute_txid().to_string();
+    let spent: Vec<JsonOutPoint> = transactions[0]
+        .0
+        .input
+        .iter()
+        .map(|input| JsonOutPoint::from(input.previous_output))
+        .collect();
+    if !reserve_wallet_utxo(account, AddressKind::P2wpkh, &spent, &first_txid) {
+        return Err(MtcError::UtxosReserved);
+    }
 
     // let tx_bytes = serialize(&transaction);
     // print(&format!("Transaction to sign: {}", hex::encode(tx_bytes)));
 
-    // Sign the transaction.
-    let signed_transaction = sign_transaction(
-        &own_public_key,
-        &own_address,
-        transaction,
-        key_name,
-        // path,
-        amount,
-        account,
-    )
-    .await;
-
-    let signed_transaction_bytes = serialize(&signed_transaction);
-    // eprintln!("{}", &format!(
-    //     "Signed transaction: {}",
-    //     hex::encode(&signed_transaction_bytes)
-    // ));
-    let res_vec = vec![0u8];
-    match bitcoin_send_transaction(SendTransactionRequest{network, transaction: signed_transaction_bytes.clone() }).await {
-    // match bitcoin_api::send_transaction(network, signed_transaction_bytes.clone()).await {
-        Ok(()) => return (signed_transaction_bytes, signed_transaction.compute_txid().to_string()),
-        Err(err) => return (res_vec,err.1)
+    // Sign the transactions.
+    let mut signed_transactions = Vec::with_capacity(transactions.len());
+    let mut spent_prevouts = Vec::with_capacity(transactions.len());
+    for (transaction, prevouts) in transactions {
+        match sign_transaction(
+            &own_public_key,
+            &own_address,
+            transaction,
+            key_name.clone(),
+            &prevouts,
+            account,
+        )
+        .await {
+            Ok(signed_transaction) => {
+                signed_transactions.push(signed_transaction);
+                spent_prevouts.push(prevouts);
+            }
+            Err(err) => {
+                release_wallet_utxo(account, AddressKind::P2wpkh, &first_txid);
+                return Err(err);
+            }
+        }
     }
+    Ok(SignedChain {
+        transactions: signed_transactions,
+        prevouts: spent_prevouts,
+        fees,
+        payments,
+        fee_rate: fee_per_byte,
+        reserved_txid: first_txid,
+    })
+}
 
-    
+/// Verifies the transactions of `signed_chain` and broadcasts them in
+/// order. Nothing is broadcast if any of them doesn't verify. Broadcast
+/// transactions are recorded in the send history of `account`.
+pub async fn broadcast_chain(
+    network: BitcoinNetwork,
+    signed_chain: SignedChain,
+    account: &Account
+) -> Result<Vec<SendBtcResponse>, MtcError> {
+    for (transaction, prevouts) in signed_chain.transactions.iter().zip(&signed_chain.prevouts) {
+        if let Err(err) = verify_transaction(transaction, prevouts) {
+            signed_chain.release(account);
+            return Err(err);
+        }
+    }
+    let mut responses = Vec::with_capacity(signed_chain.transactions.len());
+    for (index, signed_transaction) in signed_chain.transactions.iter().enumerate() {
+        let txid = signed_transaction.compute_txid().to_string();
+        let fee = signed_chain.fees[index];
+        let signed_transaction_bytes = serialize(signed_transaction);
+        // eprintln!("{}", &format!(
+        //     "Signed transaction: {}",
+        //     hex::encode(&signed_transaction_bytes)
+        // ));
+        match bitcoin_send_transaction(SendTransactionRequest{network, transaction: signed_transaction_bytes.clone() }).await {
+        // match bitcoin_api::send_transaction(network, signed_transaction_bytes.clone()).await {
+            Ok(()) => {
+                let sent = SentTransaction {
+                    txid: txid.clone(),
+                    transaction: signed_transaction_bytes.clone(),
+                    input_values: signed_chain.prevouts[index].iter().map(|prevout| prevout.value.to_sat()).collect(),
+                    payments: signed_chain.payments[index],
+                    fee,
+                    fee_rate: signed_chain.fee_rate,
+                    sent_at: ic_cdk::api::time(),
+                    replaces: None,
+                    replaced_by: None,
+                };
+                mutate_send_history(|history| history.record(account, sent));
+                responses.push(SendBtcResponse {
+                    txid,
+                    transaction: signed_transaction_bytes,
+                    fee,
+                    fee_rate: signed_chain.fee_rate,
+                });
+            }
+            Err(err) => {
+                if responses.is_empty() {
+                    signed_chain.release(account);
+                }
+                return Err(MtcError::rejected("bitcoin_send_transaction", err));
+            }
+        }
+    }
+    Ok(responses)
 }
 
-// Builds a transaction to send the given `amount` of satoshis to the
-// destination address.
-async fn build_transaction(
-    own_public_key: &ECDSAPublicKey,
-    own_address: &Address,
-    own_utxos: &HashMap<JsonOutPoint, u64>,
-    dst_address: &Address,
-    amount: Satoshi,
+/// Spends `inputs`, outputs of the P2WPKH address of `account`, to
+/// `destination` in a single output, less the fee, and broadcasts the
+/// transaction.
+pub async fn sweep(
+    network: BitcoinNetwork,
+    key_name: String,
+    inputs: Vec<Candidate>,
+    destination: &Address,
     fee_per_byte: MillisatoshiPerByte,
     account: &Account
-) -> Transaction {
-    // We have a chicken-and-egg problem where we need to know the length
-    // of the transaction in order to compute its proper fee, but we need
-    // to know the proper fee in order to figure out the inputs needed for
-    // the transaction.
-    //
-    // We solve this problem iteratively. We start with a fee of zero, build
-    // and sign a transaction, see what its size is, and then update the fee,
-    // rebuild the transaction, until the fee is set to the correct amount.
-    // print("Building transaction...");
-    let mut total_fee = 0;
+) -> Result<SendBtcResponse, MtcError> {
+    let own_public_key = read_public_key().await?;
+    let own_address = account_p2wpkh_address(network, &own_public_key, account)?;
+    let mut output = TxOut { script_pubkey: destination.script_pubkey(), value: Amount::ZERO };
+    let total: u64 = inputs.iter().map(|input| input.value).sum();
+    let fee = fee_for_weight(transaction_weight(inputs.len(), &[output.clone()]), fee_per_byte);
+    let required = fee + dust_limit(&output.script_pubkey);
+    if inputs.is_empty() || total < required {
+        return Err(MtcError::InsufficientFunds { available: total, required });
+    }
+    output.value = Amount::from_sat(total - fee);
+    let selection = Selection { inputs, fee, change: 0 };
+    let transaction = build_transaction_with_fee(&selection, &own_address, &[output])?;
+    let txid = transaction.compute_txid().to_string();
+    let spent: Vec<JsonOutPoint> = selection.inputs.iter().map(|input| input.outpoint.clone()).collect();
+    if !reserve_wallet_utxo(account, AddressKind::P2wpkh, &spent, &txid) {
+        return Err(MtcError::UtxosReserved);
+    }
+    let prevouts = spent_outputs(&selection, &own_address);
+    let signed_transaction =
+        match sign_transaction(&own_public_key, &own_address, transaction, key_name, &prevouts, account).await {
+            Ok(signed_transaction) => signed_transaction,
+            Err(err) => {
+                release_wallet_utxo(account, AddressKind::P2wpkh, &txid);
+                return Err(err);
+            }
+        };
+    let signed_chain = SignedChain {
+        transactions: vec![signed_transaction],
+        prevouts: vec![prevouts],
+        fees: vec![fee],
+        payments: vec![1],
+        fee_rate: fee_per_byte,
+        reserved_txid: txid,
+    };
+    let mut responses = broadcast_chain(network, signed_chain, account).await?;
+    Ok(responses.remove(0))
+}
+
+/// Replaces the pending transaction `txid` of `account` with one paying
+/// `fee_per_byte`, following BIP-125, and broadcasts it.
+///
+/// The replacement spends the same inputs and pays the same recipients.
+/// The extra fee comes out of the change, or from confirmed wallet outputs
+/// added as inputs. It pays at least the fee of the original plus the
+/// incremental relay fee for its own size. Only transactions funded by the
+/// wallet and not spent by a later one of the account can be replaced.
+pub async fn bump_fee(
+    network: BitcoinNetwork,
+    key_name: String,
+    txid: &str,
+    fee_per_byte: MillisatoshiPerByte,
+    account: &Account
+) -> Result<SendBtcResponse, MtcError> {
+    let txid = Txid::from_str(txid)
+        .map_err(|err| MtcError::InvalidRequest(format!("invalid txid {}: {}", txid, err)))?
+        .to_string();
+    let original = read_send_history(|history| history.find(account, &txid))
+        .ok_or_else(|| MtcError::InvalidRequest(format!("{} was not sent by this account", txid)))?;
+    let transaction = original.decode();
+    // The inputs of a pending transaction stay reserved until it is seen
+    // in a block.
+    let reserved = get_reserved_utxo_from_wallet(account, AddressKind::P2wpkh, &txid);
+    let descendants = read_send_history(|history| history.has_descendants(account, &txid));
+    let own_public_key = read_public_key().await?;
+    let own_address = account_p2wpkh_address(network, &own_public_key, account)?;
+    let change_output = TxOut { script_pubkey: own_address.script_pubkey(), value: Amount::ZERO };
+    let candidates = get_available_candidates_from_wallet(account, AddressKind::P2wpkh);
+    let selection = replacement_selection(&original, &reserved, descendants, &candidates, &change_output, fee_per_byte)?;
+
+    let outputs = &transaction.output[..original.payments as usize];
+    let replacement = build_transaction_with_fee(&selection, &own_address, outputs)?;
+    let new_txid = replacement.compute_txid().to_string();
+    let added: Vec<JsonOutPoint> = selection.inputs[transaction.input.len()..]
+        .iter()
+        .map(|input| input.outpoint.clone())
+        .collect();
+    if !reserve_wallet_utxo(account, AddressKind::P2wpkh, &added, &new_txid) {
+        return Err(MtcError::UtxosReserved);
+    }
+    let prevouts = spent_outputs(&selection, &own_address);
+    let signed_transaction =
+        match sign_transaction(&own_public_key, &own_address, replacement, key_name, &prevouts, account).await {
+            Ok(signed_transaction) => signed_transaction,
+            Err(err) => {
+                release_wallet_utxo(account, AddressKind::P2wpkh, &new_txid);
+                return Err(err);
+            }
+        };
+    let signed_chain = SignedChain {
+        transactions: vec![signed_transaction],
+        prevouts: vec![prevouts],
+        fees: vec![selection.fee],
+        payments: vec![original.payments],
+        fee_rate: fee_per_byte,
+        reserved_txid: new_txid.clone(),
+    };
+    let mut responses = broadcast_chain(network, signed_chain, account).await?;
+    // The inputs of the original now belong to its replacement.
+    transfer_wallet_utxo(account, AddressKind::P2wpkh, &txid, &new_txid);
+    mutate_send_history(|history| history.replace(account, &txid, &new_txid));
+    Ok(responses.remove(0))
+}
+
+/// Checks that `original` can be replaced at `fee_per_byte` and picks the
+/// inputs of its replacement: those of the original, then confirmed
+/// `candidates`, largest first, until they pay the recipients and the fee.
+/// `reserved` are the outputs reserved for the original, and `descendants`
+/// whether a later transaction spends it.
+fn replacement_selection(
+    original: &SentTransaction,
+    reserved: &[JsonOutPoint],
+    descendants: bool,
+    candidates: &[Candidate],
+    change_output: &TxOut,
+    fee_per_byte: MillisatoshiPerByte,
+) -> Result<Selection, MtcError> {
+    if let Some(replaced_by) = &original.replaced_by {
+        return Err(MtcError::InvalidRequest(format!("{} was already replaced by {}", original.txid, replaced_by)));
+    }
+    if fee_per_byte <= original.fee_rate {
+        return Err(MtcError::InvalidRequest(format!(
+            "the new fee rate must be above {} millisatoshi per vbyte",
+            original.fee_rate
+        )));
+    }
+    let transaction = original.decode();
+    if transaction.input.is_empty()
+        || !transaction.input.iter().all(|input| reserved.contains(&JsonOutPoint::from(input.previous_output)))
+    {
+        return Err(MtcError::InvalidRequest(format!(
+            "{} is not a pending transaction funded by the wallet",
+            original.txid
+        )));
+    }
+    if descendants {
+        return Err(MtcError::InvalidRequest(format!("{} is spent by a later transaction", original.txid)));
+    }
+
+    let outputs = &transaction.output[..original.payments as usize];
+    let amount: u64 = outputs.iter().map(|output| output.value.to_sat()).sum();
+    let mut outputs_with_change = outputs.to_vec();
+    outputs_with_change.push(change_output.clone());
+    let min_fee = |weight| {
+        fee_for_weight(weight, fee_per_byte).max(original.fee + fee_for_weight(weight, INCREMENTAL_RELAY_FEE_RATE))
+    };
+
+    let mut inputs: Vec<Candidate> = transaction
+        .input
+        .iter()
+        .zip(&original.input_values)
+        .map(|(input, value)| Candidate { outpoint: JsonOutPoint::from(input.previous_output), value: *value, height: 0 })
+        .collect();
+    // New inputs of a replacement must be confirmed, largest first.
+    let mut extra_inputs: Vec<Candidate> =
+        candidates.iter().filter(|candidate| candidate.height > 0).cloned().collect();
+    extra_inputs.sort_by(|a, b| b.value.cmp(&a.value).then(a.outpoint.cmp(&b.outpoint)));
+    let mut extra_inputs = extra_inputs.into_iter();
     loop {
-        let transaction =
-            build_transaction_with_fee(own_utxos, own_address, dst_address, amount, total_fee)
-                .expect("Error building transaction.");
-
-        // Sign the transaction. In this case, we only care about the size
-        // of the signed transaction, so we use a mock signer here for efficiency.
-        let signed_transaction = sign_transaction(
-            own_public_key,
-            own_address,
-            transaction.clone(),
-            "test_key_1".to_string(), // mock key name
-            amount,       // mock derivation path
-            account,
-        )
-        .await;
+        let total: u64 = inputs.iter().map(|input| input.value).sum();
+        let fee = min_fee(transaction_weight(inputs.len(), &outputs_with_change));
+        match total.checked_sub(amount + fee) {
+            Some(change) if change >= dust_limit(&change_output.script_pubkey) => {
+                return Ok(Selection { inputs, fee, change });
+            }
+            _ => {}
+        }
+        let fee = min_fee(transaction_weight(inputs.len(), outputs));
+        if total >= amount + fee {
+            return Ok(Selection { inputs, fee: total - amount, change: 0 });
+        }
+        match extra_inputs.next() {
+            Some(input) => inputs.push(input),
+            None => return Err(MtcError::InsufficientFunds { available: total, required: amount + fee }),
+        }
+    }
+}
+
+/// Returns the outputs spent by the inputs of `selection`, which are all
+/// paid to `own_address`.
+fn spent_outputs(selection: &Selection, own_address: &Address) -> Vec<TxOut> {
+    selection
+        .inputs
+        .iter()
+        .map(|input| TxOut {
+            script_pubkey: own_address.script_pubkey(),
+            value: Amount::from_sat(input.value),
+        })
+        .collect()
+}
 
-        let signed_tx_bytes_len = signed_transaction.total_size() as u64;
+/// Returns what a transaction paying `outputs` from a single P2WPKH input
+/// costs, fee included. It may have a change output.
+fn chained_cost(outputs: &[TxOut], fee_per_byte: MillisatoshiPerByte) -> u64 {
+    let weight = transaction_weight(1, outputs) + P2WPKH_OUTPUT_WEIGHT;
+    let amount: u64 = outputs.iter().map(|output| output.value.to_sat()).sum();
+    amount + fee_for_weight(weight, fee_per_byte)
+}
 
-        if (signed_tx_bytes_len * fee_per_byte) / 1000 == total_fee {
-            // print(&format!("Transaction built with fee {}.", total_fee));
-            return transaction;
-        } else {
-            total_fee = (signed_tx_bytes_len * fee_per_byte) / 1000;
+/// Returns the fee rate `fees` asks for, in millisatoshi per vbyte: the
+/// explicit rate, or a percentile of recent transactions.
+pub async fn get_fee_per_byte(network: BitcoinNetwork, fees: &FeePolicy) -> Result<MillisatoshiPerByte, MtcError> {
+    match (fees.sat_per_vbyte, fees.percentile) {
+        (Some(_), Some(_)) => {
+            return Err(MtcError::InvalidRequest(
+                "set either a fee rate or a fee percentile, not both".to_string(),
+            ))
+        }
+        (Some(sat_per_vbyte), None) => {
+            return match sat_per_vbyte.checked_mul(1000) {
+                Some(fee_rate) if fee_rate > 0 => Ok(fee_rate),
+                _ => Err(MtcError::InvalidRequest(format!("invalid fee rate: {} satoshi per vbyte", sat_per_vbyte))),
+            };
+        }
+        (None, Some(percentile)) if percentile > 99 => {
+            return Err(MtcError::InvalidRequest(format!(
+                "fee percentile must be below 100, got {}",
+                percentile
+            )));
         }
+        (None, _) => {}
     }
+
+    // Get fee percentiles from previous transactions to estimate our own fee.
+    let fee_percentiles = bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest{network})
+        .await
+        .map_err(|err| MtcError::rejected("bitcoin_get_current_fee_percentiles", err))?
+        .0;
+    let (fee_percentile, fallback_fee_rate) =
+        config::read_config(|config| (fees.percentile.unwrap_or(config.fee_percentile), config.fallback_fee_rate));
+    Ok(match fee_percentiles.get(fee_percentile as usize) {
+        Some(fee_rate) => *fee_rate,
+        // There are no fee percentiles. This case can only happen on a regtest
+        // network where there are no non-coinbase transactions.
+        None => fallback_fee_rate,
+    })
 }
 
+/// Returns the P2WPKH address the canister controls for `account`.
+fn account_p2wpkh_address(network: BitcoinNetwork, own_public_key: &ECDSAPublicKey, account: &Account) -> Result<Address, MtcError> {
+    let derive_pubkey = derive_public_key(own_public_key, account)?.public_key;
+    let compress_key = CompressedPublicKey::from_slice(&derive_pubkey)
+        .map_err(|err| MtcError::InvalidPublicKey(err.to_string()))?;
+    Ok(Address::p2wpkh(&compress_key, to_bitcoin_network(network)))
+}
 
+/// Parses a destination address and checks that it belongs to `network`.
+pub fn parse_address(address: &str, network: BitcoinNetwork) -> Result<Address, MtcError> {
+    Address::from_str(address)
+        .map_err(|err| MtcError::InvalidAddress(format!("{}: {}", address, err)))?
+        .require_network(to_bitcoin_network(network))
+        .map_err(|_| MtcError::WrongNetwork { address: address.to_string(), network })
+}
 
-fn p2wpkh_script_code(pkhash: &[u8; 20]) -> bitcoin::ScriptBuf {
-    use bitcoin::blockdata::opcodes;
-    let push_bytes = PushBytesBuf::try_from(pkhash.to_vec()).unwrap();
-    Builder::new()
-        .push_opcode(opcodes::all::OP_DUP)
-        .push_opcode(opcodes::all::OP_HASH160)
-        .push_slice(push_bytes)
-        .push_opcode(opcodes::all::OP_EQUALVERIFY)
-        .push_opcode(opcodes::all::OP_CHECKSIG)
-        .into_script()
+/// Builds a transaction that pays the given outputs from the inputs
+/// `selector` picks, and returns it with the selection. The fee is
+/// estimated from the weight of the inputs and outputs (see `weight`).
+fn build_transaction(
+    own_address: &Address,
+    own_utxos: &[Candidate],
+    outputs: &[TxOut],
+    reserve: u64,
+    fee_per_byte: MillisatoshiPerByte,
+    selector: &dyn CoinSelector,
+) -> Result<(Transaction, Selection), MtcError> {
+    let change_output = TxOut { script_pubkey: own_address.script_pubkey(), value: Amount::ZERO };
+    let target = SelectionTarget {
+        amount: outputs.iter().map(|output| output.value.to_sat()).sum(),
+        reserve,
+        base_fee: fee_for_weight(transaction_weight(0, outputs), fee_per_byte),
+        input_fee: fee_for_weight(P2WPKH_INPUT_WEIGHT, fee_per_byte),
+        change_fee: fee_for_weight(output_weight(&change_output), fee_per_byte),
+        dust_limit: dust_limit(&change_output.script_pubkey),
+    };
+    let selection = selector.select(own_utxos, &target)?;
+    Ok((build_transaction_with_fee(&selection, own_address, outputs)?, selection))
 }
 
+/// Builds a transaction spending the inputs of `selection` and paying
+/// `outputs`, followed by the change if there is any.
 fn build_transaction_with_fee(
-    own_utxos: &HashMap<JsonOutPoint, u64>,
+    selection: &Selection,
     own_address: &Address,
-    dst_address: &Address,
-    amount: u64,
-    fee: u64,
-) -> Result<Transaction, String> {
-    // Assume that any amount below this threshold is dust.
-    const DUST_THRESHOLD: u64 = 1_000;
-
-    // Select which UTXOs to spend. We naively spend the oldest available UTXOs,
-    // even if they were previously spent in a transaction. This isn't a
-    // problem as long as at most one transaction is created per block and
-    // we're using min_confirmations of 1.
-    let mut utxos_to_spend = vec![];
-    let mut total_spent = 0;
-    for (outpoint, value) in own_utxos.iter() {
-        total_spent += value;
-        utxos_to_spend.push((outpoint, value));
-        if total_spent >= amount + fee {
-            // We have enough inputs to cover the amount we want to spend.
-            break;
-        }
-    }
-
-    if total_spent < amount + fee {
-        return Err(format!(
-            "Insufficient balance: {}, trying to transfer {} satoshi with fee {}",
-            total_spent, amount, fee
-        ));
-    }
-   
-    let inputs: Vec<TxIn> = utxos_to_spend
-        .into_iter()
-        .map(|(utxo, _)| TxIn {
-            previous_output: OutPoint {
-                txid: Txid::from_raw_hash(Hash::from_slice(&utxo.txid()).unwrap()),
-                vout: utxo.vout(),
-            },
+    outputs: &[TxOut],
+) -> Result<Transaction, MtcError> {
+    let mut inputs: Vec<TxIn> = Vec::with_capacity(selection.inputs.len());
+    for utxo in &selection.inputs {
+        inputs.push(TxIn {
+            previous_output: utxo.outpoint.to_outpoint()?,
             sequence: bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
             witness: Witness::default(),
             script_sig: Script::builder().into_script(),
-        })
-        .collect();
-
-    let mut outputs = vec![TxOut {
-        script_pubkey: dst_address.script_pubkey(),
-        value: Amount::from_sat(amount),
-    }];
+        });
+    }
 
-    let remaining_amount = total_spent - amount - fee;
+    let mut outputs = outputs.to_vec();
 
-    if remaining_amount >= DUST_THRESHOLD {
+    if selection.change > 0 {
         outputs.push(TxOut {
             script_pubkey: own_address.script_pubkey(),
-            value: Amount::from_sat(remaining_amount),
+            value: Amount::from_sat(selection.change),
         });
     }
 
@@ -265,39 +671,40 @@ fn build_transaction_with_fee(
     })
 }
 
-// Sign a p2wpkh bitcoin transaction.
-
+/// Signs a transaction spending P2WPKH outputs of `account`. `prevouts`
+/// are the outputs spent by the inputs, in order: BIP-143 commits to the
+/// value of each of them.
 async fn sign_transaction
 (
     own_public_key: &ECDSAPublicKey,
     own_address: &Address,
     mut transaction: Transaction,
     key_name: String,
-    amount: Satoshi,
+    prevouts: &[TxOut],
     account: &Account,
-) -> Transaction
+) -> Result<Transaction, MtcError>
 {
     // Verify that our own address is P2wPKH.
-    assert_eq!(
-        own_address.address_type(),
-        Some(AddressType::P2wpkh),
-        "This example supports signing p2wpkh addresses only."
-    );
+    if own_address.address_type() != Some(AddressType::P2wpkh) {
+        return Err(MtcError::SigningFailed(format!("{} is not a P2WPKH address", own_address)));
+    }
     let mut sighashcache = SighashCache::new(transaction.clone());
     
     let path = derivation_path(account).iter().map(|path| path.to_vec()).collect::<Vec<_>>();
-    let pubkey = derive_public_key(own_public_key, account).public_key;
+    let pubkey = derive_public_key(own_public_key, account)?.public_key;
+    let witness_pubkey = bitcoin::secp256k1::PublicKey::from_slice(&pubkey)
+        .map_err(|err| MtcError::InvalidPublicKey(err.to_string()))?;
 
     for (index, input) in transaction.input.iter_mut().enumerate() {
 
-        let sighash = sighashcache.p2wpkh_signature_hash(index, &own_address.script_pubkey(), Amount::from_sat(amount), EcdsaSighashType::All).expect("build sighash failed");
+        let prevout = &prevouts[index];
+        let sighash = sighashcache
+            .p2wpkh_signature_hash(index, &prevout.script_pubkey, prevout.value, SIG_HASH_TYPE)
+            .map_err(|err| MtcError::SigningFailed(err.to_string()))?;
         
         let signature =
-        match get_sign_with_ecdsa(key_name.clone(), path.clone(), sighash.to_byte_array().to_vec())
-            .await {
-                Ok(sig) => sig,
-                Err(_) => SignWithEcdsaResponse::default(),
-        };
+            get_sign_with_ecdsa(key_name.clone(), path.clone(), sighash.to_byte_array().to_vec())
+                .await?;
 
         // Convert signature to DER.
         let der_signature = sec1_to_der(signature.signature);
@@ -305,16 +712,13 @@ async fn sign_transaction
         sig_with_hashtype.push(SIG_HASH_TYPE.to_u32() as u8);
         // let sig_data = PushBytesBuf::try_from(sig_with_hashtype.as_slice().to_vec()).unwrap();
         // let pubkey_data = PushBytesBuf::try_from(pubkey.to_vec()).unwrap();
-        let witness_sig = Signature::from_slice(&sig_with_hashtype).unwrap();
-        let witness_pubkey = bitcoin::secp256k1::PublicKey::from_slice(&pubkey).unwrap();
-         input.witness = Witness::p2wpkh(&witness_sig, &witness_pubkey);
-        // *sighashcache.witness_mut(index).unwrap() = Witness::p2wpkh(&witness_sig, &witness_pubkey);
-        input.witness.push(&sig_with_hashtype);
-        input.witness.push(&pubkey);
+        let witness_sig = Signature::from_slice(&sig_with_hashtype)
+            .map_err(|err| MtcError::SigningFailed(err.to_string()))?;
+        input.witness = Witness::p2wpkh(&witness_sig, &witness_pubkey);
     }
     // sighashcache.into_transaction()
 
-    transaction
+    Ok(transaction)
 }
 
 
@@ -327,30 +731,25 @@ pub async fn sign_transaction_p2pkh(
     mut transaction: Transaction,
     key_name: &str,
     derivation_path: Vec<Vec<u8>>,
-) -> Transaction
+) -> Result<Transaction, MtcError>
 {
     // Verify that our own address is P2PKH.
-    assert_eq!(
-        own_address.address_type(),
-        Some(AddressType::P2pkh),
-        "Not a correct p2pkh address."
-    );
+    if own_address.address_type() != Some(AddressType::P2pkh) {
+        return Err(MtcError::SigningFailed(format!("{} is not a P2PKH address", own_address)));
+    }
 
     let txclone = transaction.clone();
     for (index, input) in transaction.input.iter_mut().enumerate() {
         let sighash = SighashCache::new(&txclone)
             .legacy_signature_hash(index, &own_address.script_pubkey(), SIG_HASH_TYPE.to_u32())
-            .unwrap();
+            .map_err(|err| MtcError::SigningFailed(err.to_string()))?;
 
-        let signature = match get_sign_with_ecdsa(
+        let signature = get_sign_with_ecdsa(
             key_name.to_string(),
             derivation_path.clone(),
             sighash.as_byte_array().to_vec(),
         )
-        .await {
-            Ok(sig) => sig,
-            Err(_) => SignWithEcdsaResponse::default(),
-        };
+        .await?;
 
         // Convert signature to DER.
         let der_signature = sec1_to_der(signature.signature);
@@ -358,8 +757,10 @@ pub async fn sign_transaction_p2pkh(
         let mut sig_with_hashtype = der_signature;
         sig_with_hashtype.push(SIG_HASH_TYPE.to_u32() as u8);
 
-        let sig_with_hashtype_push_bytes = PushBytesBuf::try_from(sig_with_hashtype).unwrap();
-        let own_public_key_push_bytes = PushBytesBuf::try_from(own_public_key.to_vec()).unwrap();
+        let sig_with_hashtype_push_bytes = PushBytesBuf::try_from(sig_with_hashtype)
+            .map_err(|err| MtcError::SigningFailed(err.to_string()))?;
+        let own_public_key_push_bytes = PushBytesBuf::try_from(own_public_key.to_vec())
+            .map_err(|err| MtcError::InvalidPublicKey(err.to_string()))?;
         input.script_sig = Builder::new()
             .push_slice(sig_with_hashtype_push_bytes)
             .push_slice(own_public_key_push_bytes)
@@ -367,5 +768,127 @@ pub async fn sign_transaction_p2pkh(
         input.witness.clear();
     }
 
-    transaction
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{ScriptBuf, WPubkeyHash};
+
+    fn script(byte: u8) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([byte; 20]))
+    }
+
+    fn change_output() -> TxOut {
+        TxOut { script_pubkey: script(9), value: Amount::ZERO }
+    }
+
+    /// Returns a pending send spending outputs of `input_values`, paying
+    /// `payments` and `change` back to the wallet, sent at `fee_rate`.
+    fn original(input_values: &[u64], payments: &[u64], change: u64, fee_rate: u64) -> SentTransaction {
+        let mut output: Vec<TxOut> = payments
+            .iter()
+            .map(|value| TxOut { script_pubkey: script(8), value: Amount::from_sat(*value) })
+            .collect();
+        if change > 0 {
+            output.push(TxOut { script_pubkey: script(9), value: Amount::from_sat(change) });
+        }
+        let transaction = Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: (1..=input_values.len() as u8)
+                .map(|id| TxIn {
+                    previous_output: OutPoint::new(Txid::from_byte_array([id; 32]), 0),
+                    sequence: bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    ..TxIn::default()
+                })
+                .collect(),
+            output,
+        };
+        SentTransaction {
+            txid: transaction.compute_txid().to_string(),
+            transaction: serialize(&transaction),
+            input_values: input_values.to_vec(),
+            payments: payments.len() as u32,
+            fee: input_values.iter().sum::<u64>() - payments.iter().sum::<u64>() - change,
+            fee_rate,
+            sent_at: 0,
+            replaces: None,
+            replaced_by: None,
+        }
+    }
+
+    fn reserved(original: &SentTransaction) -> Vec<JsonOutPoint> {
+        original.decode().input.iter().map(|input| JsonOutPoint::from(input.previous_output)).collect()
+    }
+
+    fn candidate(id: u8, value: u64, height: u32) -> Candidate {
+        Candidate { outpoint: JsonOutPoint::from(OutPoint::new(Txid::from_byte_array([id; 32]), 0)), value, height }
+    }
+
+    fn ids(selection: &Selection) -> Vec<u8> {
+        selection.inputs.iter().map(|candidate| candidate.outpoint.txid()[0]).collect()
+    }
+
+    fn select(original: &SentTransaction, candidates: &[Candidate], fee_per_byte: u64) -> Result<Selection, MtcError> {
+        replacement_selection(original, &reserved(original), false, candidates, &change_output(), fee_per_byte)
+    }
+
+    #[test]
+    fn replacements_pay_the_new_rate_and_the_incremental_relay_fee() {
+        // 141 vbytes at 10 satoshi per vbyte.
+        let sent = original(&[100_000], &[50_000], 48_590, 10_000);
+        let weight = transaction_weight(1, &sent.decode().output);
+        let selection = select(&sent, &[], 20_000).unwrap();
+        assert_eq!(selection.fee, fee_for_weight(weight, 20_000));
+        assert_eq!(selection.fee, 2_820);
+        // Half a satoshi per vbyte more doesn't cover the relay fee of the
+        // replacement's own size.
+        let selection = select(&sent, &[], 10_500).unwrap();
+        assert_eq!(selection.fee, sent.fee + fee_for_weight(weight, INCREMENTAL_RELAY_FEE_RATE));
+        assert_eq!(selection.fee, 1_551);
+        assert!(selection.fee > fee_for_weight(weight, 10_500));
+    }
+
+    #[test]
+    fn the_fee_comes_out_of_the_change_first() {
+        let sent = original(&[100_000], &[50_000], 48_590, 10_000);
+        let selection = select(&sent, &[candidate(10, 80_000, 5)], 20_000).unwrap();
+        assert_eq!(ids(&selection), vec![1]);
+        assert_eq!(selection.change, 100_000 - 50_000 - selection.fee);
+    }
+
+    #[test]
+    fn change_below_the_dust_limit_is_left_to_the_fee() {
+        let sent = original(&[51_000], &[50_000], 400, 4_000);
+        let selection = select(&sent, &[candidate(10, 80_000, 5)], 5_000).unwrap();
+        assert_eq!(ids(&selection), vec![1]);
+        assert_eq!((selection.fee, selection.change), (1_000, 0));
+    }
+
+    #[test]
+    fn confirmed_outputs_are_added_largest_first() {
+        let sent = original(&[50_500], &[50_000], 0, 4_000);
+        let candidates = [candidate(10, 100_000, 0), candidate(11, 30_000, 5), candidate(12, 60_000, 7)];
+        let selection = select(&sent, &candidates, 20_000).unwrap();
+        assert_eq!(ids(&selection), vec![1, 12]);
+        assert_eq!(selection.change, 110_500 - 50_000 - selection.fee);
+
+        let result = select(&sent, &candidates[..1], 20_000);
+        assert!(matches!(result, Err(MtcError::InsufficientFunds { available: 50_500, .. })));
+    }
+
+    #[test]
+    fn only_pending_wallet_sends_without_descendants_are_replaced() {
+        let sent = original(&[100_000], &[50_000], 48_590, 10_000);
+        let rejected = |result: Result<Selection, MtcError>| matches!(result, Err(MtcError::InvalidRequest(_)));
+        assert!(rejected(select(&sent, &[], 10_000)));
+        assert!(rejected(replacement_selection(&sent, &reserved(&sent), true, &[], &change_output(), 20_000)));
+        assert!(rejected(replacement_selection(&sent, &[], false, &[], &change_output(), 20_000)));
+        let mut replaced = sent.clone();
+        replaced.replaced_by = Some("00".repeat(32));
+        assert!(rejected(select(&replaced, &[], 20_000)));
+        assert!(select(&sent, &[], 20_000).is_ok());
+    }
 }
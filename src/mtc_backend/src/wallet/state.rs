@@ -1,5 +1,3 @@
-
-
 // use std::fmt;
 // use std::io::{Read, Write};
 
@@ -10,67 +8,260 @@ use candid::{CandidType, Principal, Deserialize};
 use ic_cdk::api::call::call_with_payment;
 use ic_cdk::api::management_canister::bitcoin::{
  BitcoinNetwork,
-    GetUtxosRequest, GetUtxosResponse,
+    GetUtxosRequest, GetUtxosResponse, UtxoFilter,
 };
+use icrc_ledger_types::icrc1::account::Account;
 use std::cell::RefCell;
 use serde::Serialize;
 use std::collections::HashMap;
+
+use crate::utils::{read_public_key, AddressKind, MtcError};
+use crate::wallet::address::{account_to_p2pkh_address, account_to_p2wpkh_address};
+use crate::wallet::coin_selection::Candidate;
 // The fees for the various bitcoin endpoints.
 const GET_UTXOS_COST_CYCLES: u64 = 10_000_000_000;
+/// How long the inputs of a broadcast transaction stay reserved while the
+/// transaction is not seen in a block. After that they are released, on
+/// the assumption that the transaction was dropped from the mempool.
+const RESERVATION_TIMEOUT_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// How long spent outputs are remembered after the network stopped
+/// reporting them, so that a lagging response cannot make them spendable
+/// again.
+const SPENT_RETENTION_NANOS: u64 = 6 * 60 * 60 * 1_000_000_000;
 
 thread_local! {
     static WALLET_STATE: RefCell<WalletState> = RefCell::new(WalletState::init());
 
 }
 
+/// The lifecycle of an output tracked by the wallet:
+/// `Available` -> `Reserved` -> `Spent` -> dropped.
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum UtxoStatus {
+    /// The output can be selected as a transaction input.
+    Available,
+    /// The output is an input of the broadcast transaction `txid`, which
+    /// has not been seen in a block yet.
+    Reserved { txid: String, reserved_at: u64 },
+    /// The transaction `txid` spending the output was confirmed.
+    Spent { txid: String, spent_at: u64 },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
+pub struct WalletUtxo {
+    pub value: u64,
+    pub height: u32,
+    pub status: UtxoStatus,
+}
+
+/// The outputs of a single account and address kind.
+pub type UtxoSet = HashMap<JsonOutPoint, WalletUtxo>;
+
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
 pub struct WalletState {
-    pub unspend_utxo: HashMap<JsonOutPoint, u64>
+    /// UTXOs keyed by the account that owns them and the address kind they
+    /// were received on, so that a transaction only ever spends outputs
+    /// locked to the key that signs it.
+    pub utxos: HashMap<(Account, AddressKind), UtxoSet>,
 }
 
 
 impl WalletState {
     pub fn init() -> Self {
-        Self { unspend_utxo: HashMap::new() }
+        Self { utxos: HashMap::new() }
     }
 
-    pub fn push_utxo(&mut self, outpoint: &JsonOutPoint, amount: u64) {
-        self.unspend_utxo.insert(outpoint.to_owned(), amount);
-    
+    pub fn get_utxo(&self, account: &Account, kind: AddressKind) -> UtxoSet {
+        self.utxos.get(&(*account, kind)).cloned().unwrap_or_default()
+    }
+
+    /// Returns the outputs of the account that can be spent right now.
+    pub fn get_available_utxo(&self, account: &Account, kind: AddressKind) -> HashMap<JsonOutPoint, u64> {
+        self.utxos
+            .get(&(*account, kind))
+            .map(|utxos| {
+                utxos
+                    .iter()
+                    .filter(|(_, utxo)| utxo.status == UtxoStatus::Available)
+                    .map(|(outpoint, utxo)| (outpoint.clone(), utxo.value))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Marks the given outputs as inputs of the transaction `txid`. Nothing
+    /// is reserved and `false` is returned if any of them is not available
+    /// anymore, e.g. because a concurrent send picked it first.
+    pub fn reserve_utxo(&mut self, account: &Account, kind: AddressKind, outpoints: &[JsonOutPoint], txid: &str, now: u64) -> bool {
+        let Some(utxos) = self.utxos.get_mut(&(*account, kind)) else {
+            return outpoints.is_empty();
+        };
+        let all_available = outpoints.iter().all(|outpoint| {
+            utxos
+                .get(outpoint)
+                .map_or(false, |utxo| utxo.status == UtxoStatus::Available)
+        });
+        if !all_available {
+            return false;
+        }
+        for outpoint in outpoints {
+            if let Some(utxo) = utxos.get_mut(outpoint) {
+                utxo.status = UtxoStatus::Reserved { txid: txid.to_string(), reserved_at: now };
+            }
+        }
+        true
+    }
+
+    /// Returns the outputs reserved by the transaction `txid`.
+    pub fn get_reserved_utxo(&self, account: &Account, kind: AddressKind, txid: &str) -> Vec<JsonOutPoint> {
+        self.utxos
+            .get(&(*account, kind))
+            .map(|utxos| {
+                utxos
+                    .iter()
+                    .filter(|(_, utxo)| matches!(&utxo.status, UtxoStatus::Reserved { txid: reserved_by, .. } if reserved_by == txid))
+                    .map(|(outpoint, _)| outpoint.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Moves the outputs reserved by `old_txid` to `new_txid`, which
+    /// replaced it. The reservation starts over.
+    pub fn transfer_utxo(&mut self, account: &Account, kind: AddressKind, old_txid: &str, new_txid: &str, now: u64) {
+        if let Some(utxos) = self.utxos.get_mut(&(*account, kind)) {
+            for utxo in utxos.values_mut() {
+                if matches!(&utxo.status, UtxoStatus::Reserved { txid: reserved_by, .. } if reserved_by == old_txid) {
+                    utxo.status = UtxoStatus::Reserved { txid: new_txid.to_string(), reserved_at: now };
+                }
+            }
+        }
     }
 
-    pub fn get_utxo(&self) -> HashMap<JsonOutPoint, u64> {
-        self.unspend_utxo.clone()
+    /// Makes the outputs reserved by `txid` available again.
+    pub fn release_utxo(&mut self, account: &Account, kind: AddressKind, txid: &str) {
+        if let Some(utxos) = self.utxos.get_mut(&(*account, kind)) {
+            for utxo in utxos.values_mut() {
+                if matches!(&utxo.status, UtxoStatus::Reserved { txid: reserved_by, .. } if reserved_by == txid) {
+                    utxo.status = UtxoStatus::Available;
+                }
+            }
+        }
+    }
+
+    /// Brings the account's set in line with the outputs the network
+    /// currently reports as unspent.
+    ///
+    /// * New outputs are added as available.
+    /// * Reserved outputs that are no longer reported were spent by their
+    ///   transaction and become spent.
+    /// * Reserved outputs still reported after the reservation timeout are
+    ///   released.
+    /// * Available outputs that are no longer reported were spent elsewhere
+    ///   and are dropped, as are spent outputs past their retention period.
+    pub fn reconcile_utxo(&mut self, account: &Account, kind: AddressKind, reported: HashMap<JsonOutPoint, (u64, u32)>, now: u64) {
+        let utxos = self.utxos.entry((*account, kind)).or_default();
+
+        utxos.retain(|outpoint, utxo| {
+            if reported.contains_key(outpoint) {
+                return true;
+            }
+            match &utxo.status {
+                UtxoStatus::Available => false,
+                UtxoStatus::Reserved { txid, .. } => {
+                    utxo.status = UtxoStatus::Spent { txid: txid.clone(), spent_at: now };
+                    true
+                }
+                UtxoStatus::Spent { spent_at, .. } => now.saturating_sub(*spent_at) < SPENT_RETENTION_NANOS,
+            }
+        });
+
+        for (outpoint, (value, height)) in reported {
+            let utxo = utxos.entry(outpoint).or_insert(WalletUtxo {
+                value,
+                height,
+                status: UtxoStatus::Available,
+            });
+            utxo.value = value;
+            utxo.height = height;
+            if let UtxoStatus::Reserved { reserved_at, .. } = utxo.status {
+                if now.saturating_sub(reserved_at) >= RESERVATION_TIMEOUT_NANOS {
+                    utxo.status = UtxoStatus::Available;
+                }
+            }
+        }
     }
 
 }
 
-pub fn write_wallet_utxo(outpoint: JsonOutPoint, amount: u64) {
-    WALLET_STATE.with(|wallet_state| wallet_state.borrow_mut().push_utxo(&outpoint, amount));
+pub fn take_wallet_state() -> WalletState {
+    WALLET_STATE.with(|wallet_state| wallet_state.replace(WalletState::init()))
 }
 
-pub fn get_all_utxo_from_wallet() -> HashMap<JsonOutPoint, u64> {
-    WALLET_STATE.with(|wallet_state| wallet_state.borrow().get_utxo())
+pub fn restore_wallet_state(state: WalletState) {
+    WALLET_STATE.with(|wallet_state| *wallet_state.borrow_mut() = state);
+}
+
+pub fn get_all_utxo_from_wallet(account: &Account, kind: AddressKind) -> UtxoSet {
+    WALLET_STATE.with(|wallet_state| wallet_state.borrow().get_utxo(account, kind))
 
 }
 
-pub fn read_wallet_utxo() -> Vec<(String, u64)> {
-    let mut utxo_set = Vec::new();
-    WALLET_STATE.with(|wallet_state| {wallet_state
-        .borrow()
-        .get_utxo()
-        .iter()
-        .for_each(|(outpoint, amount)| {
-            let outpoint_str = Txid::from_raw_hash(Hash::from_slice(outpoint.txid()).unwrap()).to_string();
-            let vout = outpoint.vout();
-            let utxo_str = format!("{:?}:{}", outpoint_str, vout);
-            utxo_set.push((utxo_str, *amount));
-        });
-    });
-    utxo_set
+pub fn get_available_utxo_from_wallet(account: &Account, kind: AddressKind) -> HashMap<JsonOutPoint, u64> {
+    WALLET_STATE.with(|wallet_state| wallet_state.borrow().get_available_utxo(account, kind))
+}
+
+/// Returns the outputs of the account that can be spent right now, as coin
+/// selection candidates.
+pub fn get_available_candidates_from_wallet(account: &Account, kind: AddressKind) -> Vec<Candidate> {
+    WALLET_STATE.with(|wallet_state| {
+        wallet_state
+            .borrow()
+            .utxos
+            .get(&(*account, kind))
+            .map(|utxos| {
+                utxos
+                    .iter()
+                    .filter(|(_, utxo)| utxo.status == UtxoStatus::Available)
+                    .map(|(outpoint, utxo)| Candidate { outpoint: outpoint.clone(), value: utxo.value, height: utxo.height })
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+pub fn reserve_wallet_utxo(account: &Account, kind: AddressKind, outpoints: &[JsonOutPoint], txid: &str) -> bool {
+    let now = ic_cdk::api::time();
+    WALLET_STATE.with(|wallet_state| wallet_state.borrow_mut().reserve_utxo(account, kind, outpoints, txid, now))
+}
+
+pub fn release_wallet_utxo(account: &Account, kind: AddressKind, txid: &str) {
+    WALLET_STATE.with(|wallet_state| wallet_state.borrow_mut().release_utxo(account, kind, txid));
+}
+
+pub fn get_reserved_utxo_from_wallet(account: &Account, kind: AddressKind, txid: &str) -> Vec<JsonOutPoint> {
+    WALLET_STATE.with(|wallet_state| wallet_state.borrow().get_reserved_utxo(account, kind, txid))
+}
+
+pub fn transfer_wallet_utxo(account: &Account, kind: AddressKind, old_txid: &str, new_txid: &str) {
+    let now = ic_cdk::api::time();
+    WALLET_STATE.with(|wallet_state| wallet_state.borrow_mut().transfer_utxo(account, kind, old_txid, new_txid, now));
 }
 
-#[derive(Serialize, Deserialize, Debug, CandidType, Clone, PartialEq, PartialOrd, Eq, Hash)]
+/// Returns the spendable outputs of the account.
+pub fn read_wallet_utxo(account: &Account, kind: AddressKind) -> Result<Vec<(String, u64)>, MtcError> {
+    let available = WALLET_STATE.with(|wallet_state| wallet_state.borrow().get_available_utxo(account, kind));
+    let mut utxo_set = Vec::with_capacity(available.len());
+    for (outpoint, amount) in available {
+        let outpoint_str = outpoint.to_outpoint()?.txid.to_string();
+        let vout = outpoint.vout();
+        let utxo_str = format!("{:?}:{}", outpoint_str, vout);
+        utxo_set.push((utxo_str, amount));
+    }
+    Ok(utxo_set)
+}
+
+#[derive(Serialize, Deserialize, Debug, CandidType, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub struct JsonOutPoint {
   txid: Vec<u8>,
   vout: u32,
@@ -84,6 +275,12 @@ impl JsonOutPoint {
     pub fn vout(&self) -> u32 {
         self.vout
     }
+
+    pub fn to_outpoint(&self) -> Result<OutPoint, MtcError> {
+        let txid = Txid::from_slice(&self.txid)
+            .map_err(|err| MtcError::MalformedData(format!("outpoint txid: {}", err)))?;
+        Ok(OutPoint::new(txid, self.vout))
+    }
 }
 
 impl From<OutPoint> for JsonOutPoint {
@@ -96,24 +293,161 @@ impl From<OutPoint> for JsonOutPoint {
 }
 // tb1qnh2pq8ltrnk5qcqssu5wxhqwgg53s48fw7glv2
 
-pub async fn update_utxo(network: BitcoinNetwork, address: String) -> Vec<(String, u64)> {
-    let utxo_res: Result<(GetUtxosResponse, ), _> = call_with_payment(
-        Principal::management_canister(), 
-        "bitcoin_get_utxos", 
-        (GetUtxosRequest {
-            address,
-            network: network.into(),
-            filter: None,
-        }, ), GET_UTXOS_COST_CYCLES).await;
-    let unspent_utxo = utxo_res.unwrap().0.utxos;
-    unspent_utxo.into_iter()
-        .for_each(|output| {
-            let outpoint = OutPoint::new(Txid::from_slice(&output.outpoint.txid).expect("get txid failed"), output.outpoint.vout);
-            let json_outpoint = JsonOutPoint::from(outpoint);
-            write_wallet_utxo(json_outpoint, output.value);
-        });
-    // unspent
-    read_wallet_utxo()
+/// Fetches the UTXOs of the account's address of the given kind and
+/// reconciles that account's set with them.
+pub async fn update_utxo(network: BitcoinNetwork, account: &Account, kind: AddressKind) -> Result<Vec<(String, u64)>, MtcError> {
+    let ecdsa_key = read_public_key().await?;
+    let address = match kind {
+        AddressKind::P2wpkh => account_to_p2wpkh_address(network, &ecdsa_key, account).await?,
+        AddressKind::P2pkh => account_to_p2pkh_address(network, &ecdsa_key, account).await?,
+    };
+    // Outputs missing from the response are treated as spent, so every page
+    // has to be fetched before reconciling.
+    let reported = get_utxos(network, &address).await?;
+    let now = ic_cdk::api::time();
+    WALLET_STATE.with(|wallet_state| wallet_state.borrow_mut().reconcile_utxo(account, kind, reported, now));
+    read_wallet_utxo(account, kind)
 
 }
 
+/// Returns every output the network reports as unspent for `address`, with
+/// its value and the height of its block.
+pub async fn get_utxos(network: BitcoinNetwork, address: &str) -> Result<HashMap<JsonOutPoint, (u64, u32)>, MtcError> {
+    let mut reported = HashMap::new();
+    let mut filter = None;
+    loop {
+        let utxo_res: Result<(GetUtxosResponse, ), _> = call_with_payment(
+            Principal::management_canister(), 
+            "bitcoin_get_utxos", 
+            (GetUtxosRequest {
+                address: address.to_string(),
+                network: network.into(),
+                filter,
+            }, ), GET_UTXOS_COST_CYCLES).await;
+        let response = utxo_res.map_err(|err| MtcError::rejected("bitcoin_get_utxos", err))?.0;
+        for output in response.utxos {
+            let txid = Txid::from_slice(&output.outpoint.txid)
+                .map_err(|err| MtcError::MalformedData(format!("bitcoin_get_utxos txid: {}", err)))?;
+            let outpoint = OutPoint::new(txid, output.outpoint.vout);
+            reported.insert(JsonOutPoint::from(outpoint), (output.value, output.height));
+        }
+        match response.next_page {
+            Some(page) => filter = Some(UtxoFilter::Page(page)),
+            None => break,
+        }
+    }
+    Ok(reported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    const HOUR_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+    fn account() -> Account {
+        Account::from(Principal::from_slice(&[1; 29]))
+    }
+
+    fn outpoint(byte: u8) -> JsonOutPoint {
+        JsonOutPoint::from(OutPoint::new(Txid::from_byte_array([byte; 32]), 0))
+    }
+
+    fn status(state: &WalletState, byte: u8) -> Option<UtxoStatus> {
+        state.get_utxo(&account(), AddressKind::P2wpkh).get(&outpoint(byte)).map(|utxo| utxo.status.clone())
+    }
+
+    /// A wallet whose P2WPKH address received outputs 1, 2 and 3.
+    fn wallet() -> WalletState {
+        let mut state = WalletState::init();
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[1, 2, 3]), 0);
+        state
+    }
+
+    fn reported(bytes: &[u8]) -> HashMap<JsonOutPoint, (u64, u32)> {
+        bytes.iter().map(|byte| (outpoint(*byte), (*byte as u64 * 1_000, 10))).collect()
+    }
+
+    fn reserved(txid: &str, reserved_at: u64) -> Option<UtxoStatus> {
+        Some(UtxoStatus::Reserved { txid: txid.to_string(), reserved_at })
+    }
+
+    #[test]
+    fn reported_outputs_are_available() {
+        let state = wallet();
+        assert_eq!(state.get_available_utxo(&account(), AddressKind::P2wpkh).len(), 3);
+        assert_eq!(status(&state, 1), Some(UtxoStatus::Available));
+        // Other address kinds of the account have outputs of their own.
+        assert!(state.get_available_utxo(&account(), AddressKind::P2pkh).is_empty());
+    }
+
+    #[test]
+    fn reserved_outputs_cant_be_reserved_again() {
+        let mut state = wallet();
+        assert!(state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(1), outpoint(2)], "a", 5));
+        // A second send that picked one of the same outputs reserves
+        // nothing.
+        assert!(!state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(2), outpoint(3)], "b", 6));
+        assert_eq!(status(&state, 2), reserved("a", 5));
+        assert_eq!(status(&state, 3), Some(UtxoStatus::Available));
+        assert!(!state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(4)], "b", 6));
+        assert_eq!(state.get_available_utxo(&account(), AddressKind::P2wpkh).keys().collect::<Vec<_>>(), vec![&outpoint(3)]);
+
+        let mut reserved_by_a = state.get_reserved_utxo(&account(), AddressKind::P2wpkh, "a");
+        reserved_by_a.sort();
+        assert_eq!(reserved_by_a, vec![outpoint(1), outpoint(2)]);
+        state.release_utxo(&account(), AddressKind::P2wpkh, "a");
+        assert_eq!(state.get_available_utxo(&account(), AddressKind::P2wpkh).len(), 3);
+    }
+
+    #[test]
+    fn transferred_reservations_start_over() {
+        let mut state = wallet();
+        assert!(state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(1)], "a", 5));
+        assert!(state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(2)], "b", 5));
+        state.transfer_utxo(&account(), AddressKind::P2wpkh, "a", "c", 7);
+        assert_eq!(status(&state, 1), reserved("c", 7));
+        assert_eq!(status(&state, 2), reserved("b", 5));
+        assert!(state.get_reserved_utxo(&account(), AddressKind::P2wpkh, "a").is_empty());
+    }
+
+    #[test]
+    fn reconciling_spends_reserved_and_drops_available_outputs() {
+        let mut state = wallet();
+        assert!(state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(1)], "a", 5));
+        // Outputs 1 and 2 aren't reported anymore, output 4 is new.
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[3, 4]), 6);
+        assert_eq!(status(&state, 1), Some(UtxoStatus::Spent { txid: "a".to_string(), spent_at: 6 }));
+        assert_eq!(status(&state, 2), None);
+        assert_eq!(status(&state, 4), Some(UtxoStatus::Available));
+        assert_eq!(state.get_utxo(&account(), AddressKind::P2wpkh)[&outpoint(4)].value, 4_000);
+    }
+
+    #[test]
+    fn reservations_are_released_after_the_timeout() {
+        let mut state = wallet();
+        assert!(state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(1)], "a", 5));
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[1, 2, 3]), 5 + RESERVATION_TIMEOUT_NANOS - 1);
+        assert_eq!(status(&state, 1), reserved("a", 5));
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[1, 2, 3]), 5 + RESERVATION_TIMEOUT_NANOS);
+        assert_eq!(status(&state, 1), Some(UtxoStatus::Available));
+    }
+
+    #[test]
+    fn spent_outputs_expire_after_the_retention_period() {
+        let mut state = wallet();
+        assert!(state.reserve_utxo(&account(), AddressKind::P2wpkh, &[outpoint(1)], "a", 5));
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[2, 3]), HOUR_NANOS);
+        // A lagging response that still reports the spent output doesn't
+        // make it spendable again.
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[1, 2, 3]), 2 * HOUR_NANOS);
+        assert_eq!(status(&state, 1), Some(UtxoStatus::Spent { txid: "a".to_string(), spent_at: HOUR_NANOS }));
+        assert!(!state.get_available_utxo(&account(), AddressKind::P2wpkh).contains_key(&outpoint(1)));
+
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[2, 3]), HOUR_NANOS + SPENT_RETENTION_NANOS - 1);
+        assert!(status(&state, 1).is_some());
+        state.reconcile_utxo(&account(), AddressKind::P2wpkh, reported(&[2, 3]), HOUR_NANOS + SPENT_RETENTION_NANOS);
+        assert_eq!(status(&state, 1), None);
+    }
+}
@@ -0,0 +1,398 @@
+//! Checks signed transactions before they are broadcast.
+//!
+//! A transaction that doesn't verify would be dropped by the network
+//! anyway, but only after its inputs were reserved and the caller was told
+//! it was sent. Every input is therefore checked against the output it
+//! spends, the way a node would:
+//!
+//! * P2WPKH inputs: the witness holds a strict-DER, low-S signature and a
+//!   compressed key hashing to the spent script, and the signature
+//!   verifies against the BIP-143 sighash of the real prevout value.
+//! * P2TR inputs: key-path spends verify against the BIP-341 sighash; for
+//!   script-path spends the control block must commit to the leaf, and the
+//!   leaf must be a single-key `<key> OP_CHECKSIG ...` script like the
+//!   reveal scripts of `message::inscription`.
+//!
+//! libbitcoinconsensus would cover every script type, but it is C++ that
+//! doesn't build for `wasm32-unknown-unknown`, so the checks use the
+//! secp256k1 library rust-bitcoin already links. Other script types are
+//! rejected, since the canister never signs for them.
+use bitcoin::{
+    ecdsa,
+    hashes::Hash,
+    key::{CompressedPublicKey, Secp256k1, XOnlyPublicKey},
+    script::Instruction,
+    secp256k1::{Message, Verification},
+    sighash::{Prevouts, SighashCache},
+    taproot::{self, ControlBlock, LeafVersion, TapLeafHash},
+    Amount, Script, ScriptBuf, Transaction, TxOut,
+};
+
+use crate::utils::MtcError;
+
+/// The first byte of a taproot annex.
+const ANNEX_TAG: u8 = 0x50;
+
+/// Checks every input of `transaction` against `prevouts`, the outputs it
+/// spends in input order, and that it doesn't spend more than it has.
+pub fn verify_transaction(transaction: &Transaction, prevouts: &[TxOut]) -> Result<(), MtcError> {
+    let txid = transaction.compute_txid().to_string();
+    let invalid = |input: usize, reason: String| MtcError::InvalidTransaction {
+        txid: txid.clone(),
+        input: input as u32,
+        reason,
+    };
+    if prevouts.len() != transaction.input.len() {
+        return Err(invalid(0, format!(
+            "{} inputs but {} spent outputs",
+            transaction.input.len(),
+            prevouts.len()
+        )));
+    }
+    let spent: Amount = prevouts.iter().map(|prevout| prevout.value).sum();
+    let paid: Amount = transaction.output.iter().map(|output| output.value).sum();
+    if paid > spent {
+        return Err(invalid(0, format!("pays {} but spends only {}", paid, spent)));
+    }
+
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(transaction);
+    for (index, prevout) in prevouts.iter().enumerate() {
+        let script_pubkey = &prevout.script_pubkey;
+        let result = if script_pubkey.is_p2wpkh() {
+            verify_p2wpkh(&secp, transaction, &mut cache, index, prevout)
+        } else if script_pubkey.is_p2tr() {
+            verify_p2tr(&secp, transaction, &mut cache, index, prevouts)
+        } else {
+            Err(format!("cannot verify a spend of {}", script_pubkey))
+        };
+        result.map_err(|reason| invalid(index, reason))?;
+    }
+    Ok(())
+}
+
+fn verify_p2wpkh<C: Verification>(
+    secp: &Secp256k1<C>,
+    transaction: &Transaction,
+    cache: &mut SighashCache<&Transaction>,
+    index: usize,
+    prevout: &TxOut,
+) -> Result<(), String> {
+    let witness = &transaction.input[index].witness;
+    if witness.len() != 2 {
+        return Err(format!("expected a signature and a key in the witness, got {} items", witness.len()));
+    }
+    let signature = ecdsa::Signature::from_slice(&witness[0]).map_err(|err| format!("invalid signature: {}", err))?;
+    let public_key = CompressedPublicKey::from_slice(&witness[1]).map_err(|err| format!("invalid key: {}", err))?;
+    if ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash()) != prevout.script_pubkey {
+        return Err("the key doesn't match the spent script".to_string());
+    }
+    let mut normalized = signature.signature;
+    normalized.normalize_s();
+    if normalized != signature.signature {
+        return Err("the signature has a high S value".to_string());
+    }
+    let sighash = cache
+        .p2wpkh_signature_hash(index, &prevout.script_pubkey, prevout.value, signature.sighash_type)
+        .map_err(|err| err.to_string())?;
+    secp.verify_ecdsa(&Message::from_digest(sighash.to_byte_array()), &signature.signature, &public_key.0)
+        .map_err(|err| format!("the signature doesn't verify: {}", err))
+}
+
+fn verify_p2tr<C: Verification>(
+    secp: &Secp256k1<C>,
+    transaction: &Transaction,
+    cache: &mut SighashCache<&Transaction>,
+    index: usize,
+    prevouts: &[TxOut],
+) -> Result<(), String> {
+    let output_key = XOnlyPublicKey::from_slice(&prevouts[index].script_pubkey.as_bytes()[2..])
+        .map_err(|err| format!("invalid output key: {}", err))?;
+    let mut items: Vec<&[u8]> = transaction.input[index].witness.iter().collect();
+    let annex = match items.last() {
+        Some(last) if items.len() >= 2 && last.first() == Some(&ANNEX_TAG) => items.pop(),
+        _ => None,
+    };
+    if annex.is_some() {
+        return Err("annexes are not standard".to_string());
+    }
+    let prevouts = Prevouts::All(prevouts);
+
+    if let [signature] = items[..] {
+        let signature = taproot::Signature::from_slice(signature).map_err(|err| format!("invalid signature: {}", err))?;
+        let sighash = cache
+            .taproot_key_spend_signature_hash(index, &prevouts, signature.sighash_type)
+            .map_err(|err| err.to_string())?;
+        return secp
+            .verify_schnorr(&signature.signature, &Message::from_digest(sighash.to_byte_array()), &output_key)
+            .map_err(|err| format!("the signature doesn't verify: {}", err));
+    }
+
+    let [signature, script, control_block] = items[..] else {
+        return Err(format!("expected a signature, a script and a control block, got {} items", items.len()));
+    };
+    let script = Script::from_bytes(script);
+    let control_block = ControlBlock::decode(control_block).map_err(|err| format!("invalid control block: {}", err))?;
+    if control_block.leaf_version != LeafVersion::TapScript {
+        return Err("unknown leaf version".to_string());
+    }
+    if !control_block.verify_taproot_commitment(secp, output_key, script) {
+        return Err("the control block doesn't commit to the script".to_string());
+    }
+    let mut instructions = script.instructions();
+    let key = match (instructions.next(), instructions.next()) {
+        (Some(Ok(Instruction::PushBytes(key))), Some(Ok(Instruction::Op(bitcoin::opcodes::all::OP_CHECKSIG)))) => {
+            XOnlyPublicKey::from_slice(key.as_bytes()).map_err(|err| format!("invalid script key: {}", err))?
+        }
+        _ => return Err("only single-key scripts can be verified".to_string()),
+    };
+    if !is_inert(instructions) {
+        return Err("only single-key scripts can be verified".to_string());
+    }
+    let signature = taproot::Signature::from_slice(signature).map_err(|err| format!("invalid signature: {}", err))?;
+    let leaf_hash = TapLeafHash::from_script(script, LeafVersion::TapScript);
+    let sighash = cache
+        .taproot_script_spend_signature_hash(index, &prevouts, leaf_hash, signature.sighash_type)
+        .map_err(|err| err.to_string())?;
+    secp.verify_schnorr(&signature.signature, &Message::from_digest(sighash.to_byte_array()), &key)
+        .map_err(|err| format!("the signature doesn't verify: {}", err))
+}
+
+/// Whether the rest of a script after `<key> OP_CHECKSIG` leaves the
+/// result alone: nothing, or an `OP_FALSE OP_IF <pushes> OP_ENDIF` data
+/// envelope.
+fn is_inert(mut instructions: bitcoin::script::Instructions) -> bool {
+    match instructions.next() {
+        None => return true,
+        Some(Ok(Instruction::PushBytes(bytes))) if bytes.is_empty() => {}
+        _ => return false,
+    }
+    if !matches!(instructions.next(), Some(Ok(Instruction::Op(bitcoin::opcodes::all::OP_IF)))) {
+        return false;
+    }
+    loop {
+        match instructions.next() {
+            Some(Ok(Instruction::PushBytes(_))) => {}
+            Some(Ok(Instruction::Op(bitcoin::opcodes::all::OP_ENDIF))) => return instructions.next().is_none(),
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        absolute::LockTime,
+        key::{Keypair, TapTweak},
+        opcodes::{all::*, OP_FALSE},
+        script::Builder,
+        secp256k1::{constants::CURVE_ORDER, All, SecretKey},
+        sighash::{EcdsaSighashType, TapSighashType},
+        taproot::TaprootBuilder,
+        transaction::Version,
+        OutPoint, Sequence, TxIn, Txid, Witness,
+    };
+
+    fn secret(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    fn compressed(secp: &Secp256k1<All>, secret: &SecretKey) -> CompressedPublicKey {
+        CompressedPublicKey(secret.public_key(secp))
+    }
+
+    /// A transaction spending one output and paying 90,000 satoshi.
+    fn unsigned() -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut { value: Amount::from_sat(90_000), script_pubkey: ScriptBuf::new_op_return([1, 2, 3]) }],
+        }
+    }
+
+    fn p2wpkh_prevout(secp: &Secp256k1<All>, value: u64) -> TxOut {
+        TxOut { value: Amount::from_sat(value), script_pubkey: ScriptBuf::new_p2wpkh(&compressed(secp, &secret(1)).wpubkey_hash()) }
+    }
+
+    /// Signs the only input of `transaction` with `secret(1)` against
+    /// `prevout`, and puts `key` in the witness.
+    fn sign_p2wpkh(secp: &Secp256k1<All>, mut transaction: Transaction, prevout: &TxOut, key: &CompressedPublicKey) -> Transaction {
+        let sighash = SighashCache::new(&transaction)
+            .p2wpkh_signature_hash(0, &prevout.script_pubkey, prevout.value, EcdsaSighashType::All)
+            .unwrap();
+        let signature = secp.sign_ecdsa(&Message::from_digest(sighash.to_byte_array()), &secret(1));
+        let signature = ecdsa::Signature { signature, sighash_type: EcdsaSighashType::All };
+        transaction.input[0].witness = Witness::p2wpkh(&signature, &key.0);
+        transaction
+    }
+
+    fn reason(result: Result<(), MtcError>) -> String {
+        match result {
+            Err(MtcError::InvalidTransaction { input: 0, reason, .. }) => reason,
+            other => panic!("expected an invalid input, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn p2wpkh_spends_verify() {
+        let secp = Secp256k1::new();
+        let prevout = p2wpkh_prevout(&secp, 100_000);
+        let transaction = sign_p2wpkh(&secp, unsigned(), &prevout, &compressed(&secp, &secret(1)));
+        assert_eq!(verify_transaction(&transaction, &[prevout]), Ok(()));
+    }
+
+    #[test]
+    fn p2wpkh_spends_of_another_value_are_rejected() {
+        let secp = Secp256k1::new();
+        let transaction = sign_p2wpkh(&secp, unsigned(), &p2wpkh_prevout(&secp, 100_000), &compressed(&secp, &secret(1)));
+        let reason = reason(verify_transaction(&transaction, &[p2wpkh_prevout(&secp, 100_001)]));
+        assert!(reason.starts_with("the signature doesn't verify"), "{}", reason);
+    }
+
+    #[test]
+    fn p2wpkh_spends_with_another_key_are_rejected() {
+        let secp = Secp256k1::new();
+        let prevout = p2wpkh_prevout(&secp, 100_000);
+        let transaction = sign_p2wpkh(&secp, unsigned(), &prevout, &compressed(&secp, &secret(2)));
+        assert_eq!(reason(verify_transaction(&transaction, &[prevout])), "the key doesn't match the spent script");
+    }
+
+    #[test]
+    fn high_s_signatures_are_rejected() {
+        let secp = Secp256k1::new();
+        let prevout = p2wpkh_prevout(&secp, 100_000);
+        let mut transaction = sign_p2wpkh(&secp, unsigned(), &prevout, &compressed(&secp, &secret(1)));
+        let signature = ecdsa::Signature::from_slice(&transaction.input[0].witness[0]).unwrap();
+        // Replace S with n - S, which verifies just as well.
+        let mut compact = signature.signature.serialize_compact();
+        let mut borrow = 0;
+        for index in (32..64).rev() {
+            let difference = CURVE_ORDER[index - 32] as i16 - compact[index] as i16 - borrow;
+            compact[index] = difference.rem_euclid(256) as u8;
+            borrow = (difference < 0) as i16;
+        }
+        let high_s = bitcoin::secp256k1::ecdsa::Signature::from_compact(&compact).unwrap();
+        let signature = ecdsa::Signature { signature: high_s, sighash_type: EcdsaSighashType::All };
+        transaction.input[0].witness = Witness::p2wpkh(&signature, &compressed(&secp, &secret(1)).0);
+        assert_eq!(reason(verify_transaction(&transaction, &[prevout])), "the signature has a high S value");
+    }
+
+    #[test]
+    fn spending_more_than_the_prevouts_is_rejected() {
+        let secp = Secp256k1::new();
+        let prevout = p2wpkh_prevout(&secp, 80_000);
+        let transaction = sign_p2wpkh(&secp, unsigned(), &prevout, &compressed(&secp, &secret(1)));
+        assert!(reason(verify_transaction(&transaction, &[prevout])).starts_with("pays"));
+    }
+
+    /// A reveal script of `key` carrying an envelope, as
+    /// `message::inscription` writes it.
+    fn envelope_script(key: &XOnlyPublicKey) -> ScriptBuf {
+        Builder::new()
+            .push_x_only_key(key)
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"mtc")
+            .push_slice([7; 75])
+            .push_opcode(OP_ENDIF)
+            .into_script()
+    }
+
+    /// Returns the prevout of a taproot output whose only leaf is
+    /// `script`, and the witness spending it with the signature of
+    /// `signer`.
+    fn sign_tapscript(
+        secp: &Secp256k1<All>,
+        transaction: &mut Transaction,
+        script: ScriptBuf,
+        signer: &Keypair,
+        signed_value: u64,
+    ) -> TxOut {
+        let internal_key = Keypair::from_secret_key(secp, &secret(9)).x_only_public_key().0;
+        let spend_info = TaprootBuilder::new().add_leaf(0, script.clone()).unwrap().finalize(secp, internal_key).unwrap();
+        let script_pubkey = ScriptBuf::new_p2tr_tweaked(spend_info.output_key());
+        let signed_prevout = TxOut { value: Amount::from_sat(signed_value), script_pubkey: script_pubkey.clone() };
+        let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let sighash = SighashCache::new(&*transaction)
+            .taproot_script_spend_signature_hash(0, &Prevouts::All(&[signed_prevout]), leaf_hash, TapSighashType::Default)
+            .unwrap();
+        let signature = taproot::Signature {
+            signature: secp.sign_schnorr_no_aux_rand(&Message::from_digest(sighash.to_byte_array()), signer),
+            sighash_type: TapSighashType::Default,
+        };
+        let control_block = spend_info.control_block(&(script.clone(), LeafVersion::TapScript)).unwrap();
+        let mut witness = Witness::new();
+        witness.push(signature.to_vec());
+        witness.push(script.as_bytes());
+        witness.push(control_block.serialize());
+        transaction.input[0].witness = witness;
+        TxOut { value: Amount::from_sat(100_000), script_pubkey }
+    }
+
+    #[test]
+    fn envelope_tapscript_spends_verify() {
+        let secp = Secp256k1::new();
+        let signer = Keypair::from_secret_key(&secp, &secret(3));
+        let mut transaction = unsigned();
+        let prevout = sign_tapscript(&secp, &mut transaction, envelope_script(&signer.x_only_public_key().0), &signer, 100_000);
+        assert_eq!(verify_transaction(&transaction, &[prevout]), Ok(()));
+    }
+
+    #[test]
+    fn envelope_tapscript_spends_of_another_value_are_rejected() {
+        let secp = Secp256k1::new();
+        let signer = Keypair::from_secret_key(&secp, &secret(3));
+        let mut transaction = unsigned();
+        let prevout = sign_tapscript(&secp, &mut transaction, envelope_script(&signer.x_only_public_key().0), &signer, 100_001);
+        let reason = reason(verify_transaction(&transaction, &[prevout]));
+        assert!(reason.starts_with("the signature doesn't verify"), "{}", reason);
+    }
+
+    #[test]
+    fn envelope_tapscript_spends_by_another_key_are_rejected() {
+        let secp = Secp256k1::new();
+        let signer = Keypair::from_secret_key(&secp, &secret(3));
+        let other = Keypair::from_secret_key(&secp, &secret(4));
+        let mut transaction = unsigned();
+        let prevout = sign_tapscript(&secp, &mut transaction, envelope_script(&signer.x_only_public_key().0), &other, 100_000);
+        let reason = reason(verify_transaction(&transaction, &[prevout]));
+        assert!(reason.starts_with("the signature doesn't verify"), "{}", reason);
+    }
+
+    #[test]
+    fn tapscripts_the_output_doesnt_commit_to_are_rejected() {
+        let secp = Secp256k1::new();
+        let signer = Keypair::from_secret_key(&secp, &secret(3));
+        let mut transaction = unsigned();
+        let mut prevout = sign_tapscript(&secp, &mut transaction, envelope_script(&signer.x_only_public_key().0), &signer, 100_000);
+        let (other_key, _) = signer.x_only_public_key().0.tap_tweak(&secp, None);
+        prevout.script_pubkey = ScriptBuf::new_p2tr_tweaked(other_key);
+        assert_eq!(
+            reason(verify_transaction(&transaction, &[prevout])),
+            "the control block doesn't commit to the script"
+        );
+    }
+
+    #[test]
+    fn tapscripts_that_arent_inert_after_the_checksig_are_rejected() {
+        let secp = Secp256k1::new();
+        let signer = Keypair::from_secret_key(&secp, &secret(3));
+        let script = Builder::new()
+            .push_x_only_key(&signer.x_only_public_key().0)
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_DROP)
+            .push_opcode(OP_PUSHNUM_1)
+            .into_script();
+        let mut transaction = unsigned();
+        let prevout = sign_tapscript(&secp, &mut transaction, script, &signer, 100_000);
+        assert_eq!(reason(verify_transaction(&transaction, &[prevout])), "only single-key scripts can be verified");
+    }
+}
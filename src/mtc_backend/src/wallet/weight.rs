@@ -0,0 +1,148 @@
+//! Estimates the size of the transactions the wallet builds before they
+//! are signed, and the dust limits of their outputs.
+//!
+//! Sizes follow BIP-141: non-witness bytes weigh four units, witness bytes
+//! one, and the virtual size is the weight divided by four, rounded up.
+//! Fee rates apply to the virtual size. Signatures are assumed to take
+//! their longest low-S encoding, so an estimate is never below the size of
+//! the signed transaction.
+use bitcoin::{Amount, Script, TxOut};
+use ic_cdk::api::management_canister::bitcoin::MillisatoshiPerByte;
+
+/// Version and lock time.
+const TRANSACTION_FIXED_SIZE: u64 = 4 + 4;
+/// The segwit marker and flag, which count as witness data.
+const SEGWIT_MARKER_WEIGHT: u64 = 2;
+/// Outpoint, empty script sig and sequence.
+const INPUT_BASE_SIZE: u64 = 32 + 4 + 1 + 4;
+/// The item count, a DER signature of at most 71 bytes followed by the
+/// sighash type, and a compressed public key, each pushed with its length.
+const P2WPKH_WITNESS_SIZE: u64 = 1 + (1 + 72) + (1 + 33);
+/// The weight of an input spending a P2WPKH output: 68 vbytes.
+pub const P2WPKH_INPUT_WEIGHT: u64 = INPUT_BASE_SIZE * 4 + P2WPKH_WITNESS_SIZE;
+/// The weight of an output paying a P2WPKH address: 31 vbytes.
+pub const P2WPKH_OUTPUT_WEIGHT: u64 = (8 + 1 + 22) * 4;
+/// The fee rate relay policy prices spending an output at to decide whether
+/// it is dust, in millisatoshi per vbyte.
+const DUST_RELAY_FEE_RATE: MillisatoshiPerByte = 3_000;
+
+/// Returns the weight of `output`.
+pub fn output_weight(output: &TxOut) -> u64 {
+    output.size() as u64 * 4
+}
+
+/// Returns the weight of a transaction spending `inputs` P2WPKH outputs and
+/// paying `outputs`.
+pub fn transaction_weight(inputs: usize, outputs: &[TxOut]) -> u64 {
+    let size = TRANSACTION_FIXED_SIZE + compact_size_len(inputs) + compact_size_len(outputs.len());
+    size * 4
+        + SEGWIT_MARKER_WEIGHT
+        + inputs as u64 * P2WPKH_INPUT_WEIGHT
+        + outputs.iter().map(output_weight).sum::<u64>()
+}
+
+/// Returns the fee of `weight` units at `fee_rate`, rounded up so that the
+/// rate is never undercut.
+pub fn fee_for_weight(weight: u64, fee_rate: MillisatoshiPerByte) -> u64 {
+    (weight.div_ceil(4) * fee_rate).div_ceil(1000)
+}
+
+/// Returns the smallest value an output locked to `script_pubkey` can have
+/// without being dust under the default relay policy: what it costs to
+/// create and spend it at 3 satoshi per vbyte. That is 546 satoshi for
+/// P2PKH, 540 for P2SH, 294 for P2WPKH and 330 for P2WSH and P2TR.
+/// OP_RETURN outputs can't be spent and have no limit.
+pub fn dust_limit(script_pubkey: &Script) -> u64 {
+    if script_pubkey.is_op_return() {
+        return 0;
+    }
+    let output = TxOut { value: Amount::ZERO, script_pubkey: script_pubkey.to_owned() };
+    // A spending input is assumed to carry a 107 bytes long signature and
+    // public key, in its witness if the output is a witness program.
+    let input_size = if script_pubkey.is_witness_program() {
+        INPUT_BASE_SIZE + 107 / 4
+    } else {
+        INPUT_BASE_SIZE + 107
+    };
+    (output.size() as u64 + input_size) * DUST_RELAY_FEE_RATE / 1000
+}
+
+/// Returns the length of the compact size encoding of `n`.
+fn compact_size_len(n: usize) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{absolute::LockTime, transaction::Version, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, Witness};
+
+    fn p2wpkh() -> ScriptBuf {
+        ScriptBuf::from_bytes([vec![0x00, 0x14], vec![0; 20]].concat())
+    }
+
+    fn p2pkh() -> ScriptBuf {
+        ScriptBuf::from_bytes([vec![0x76, 0xa9, 0x14], vec![0; 20], vec![0x88, 0xac]].concat())
+    }
+
+    fn p2tr() -> ScriptBuf {
+        ScriptBuf::from_bytes([vec![0x51, 0x20], vec![0; 32]].concat())
+    }
+
+    fn output(script_pubkey: ScriptBuf) -> TxOut {
+        TxOut { value: Amount::from_sat(10_000), script_pubkey }
+    }
+
+    #[test]
+    fn p2wpkh_input_weighs_272_units() {
+        assert_eq!(P2WPKH_INPUT_WEIGHT, 272);
+        assert_eq!(P2WPKH_OUTPUT_WEIGHT, output_weight(&output(p2wpkh())));
+    }
+
+    #[test]
+    fn one_input_two_outputs_is_141_vbytes() {
+        let outputs = vec![output(p2wpkh()), output(p2wpkh())];
+        let weight = transaction_weight(1, &outputs);
+        assert_eq!(weight, 562);
+        assert_eq!(weight.div_ceil(4), 141);
+
+        // The same transaction signed with the longest low-S signature:
+        // 71 bytes of DER and the sighash type.
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::from_slice(&[vec![0; 72], vec![2; 33]]),
+            }],
+            output: outputs,
+        };
+        assert_eq!(transaction.weight().to_wu(), weight);
+        assert_eq!(transaction.vsize(), 141);
+    }
+
+    #[test]
+    fn dust_limits_follow_the_relay_policy() {
+        assert_eq!(dust_limit(&p2pkh()), 546);
+        assert_eq!(dust_limit(&p2wpkh()), 294);
+        assert_eq!(dust_limit(&p2tr()), 330);
+        assert_eq!(dust_limit(&ScriptBuf::new_op_return([1, 2, 3])), 0);
+    }
+
+    #[test]
+    fn fees_are_rounded_up() {
+        assert_eq!(fee_for_weight(562, 1_000), 141);
+        // 140.25 vbytes are charged as 141.
+        assert_eq!(fee_for_weight(561, 1_000), 141);
+        // 141 vbytes at 1.5 satoshi per vbyte are 211.5 satoshi.
+        assert_eq!(fee_for_weight(562, 1_500), 212);
+        assert_eq!(fee_for_weight(0, 1_000), 0);
+    }
+}